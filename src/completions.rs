@@ -0,0 +1,185 @@
+//! Shell completion scripts (`rustdn completions <shell>`).
+//!
+//! The scripts are hand written (`rustdn` doesn't use an argument parsing library which could
+//! generate them). Toolchains are completed dynamically, by calling
+//! `rustdn completions --toolchains`, so that the cached and linked toolchains are always
+//! up to date.
+//!
+//! Scripts are meant to be used like `source <(rustdn completions zsh)`.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    error::Error,
+    toolchain::{cached_toolchains, linked_toolchains, Channel, Extras, ToolchainOverride},
+};
+
+/// Shells completions can be generated for.
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+/// Placeholder for the space separated list of commands in the scripts.
+const COMMANDS_PLACEHOLDER: &str = "@COMMANDS@";
+
+const BASH: &str = r#"_rustdn() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+
+    if [[ "$cur" == +* ]]; then
+        COMPREPLY=($(compgen -P + -W "$(rustdn completions --toolchains 2>/dev/null)" -- "${cur#+}"))
+        return
+    fi
+
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "@COMMANDS@ --offline --quiet --no-toolchain-file --version --help" -- "$cur"))
+        return
+    fi
+
+    case "$prev" in
+        default|run|shell|remove|set|update)
+            COMPREPLY=($(compgen -W "$(rustdn completions --toolchains 2>/dev/null)" -- "$cur")) ;;
+        toolchain) COMPREPLY=($(compgen -W "list add remove link export import" -- "$cur")) ;;
+        override) COMPREPLY=($(compgen -W "set unset list" -- "$cur")) ;;
+        self) COMPREPLY=($(compgen -W "install uninstall" -- "$cur")) ;;
+        completions) COMPREPLY=($(compgen -W "bash zsh fish" -- "$cur")) ;;
+        help) COMPREPLY=($(compgen -W "@COMMANDS@" -- "$cur")) ;;
+    esac
+}
+
+complete -o default -F _rustdn rustdn
+"#;
+
+const ZSH: &str = r#"#compdef rustdn
+
+_rustdn() {
+    local -a commands toolchains
+    commands=(@COMMANDS@)
+    toolchains=(${(f)"$(rustdn completions --toolchains 2>/dev/null)"})
+
+    if [[ $PREFIX == +* ]]; then
+        compset -P '+'
+        compadd -a toolchains
+        return
+    fi
+
+    if (( CURRENT == 2 )); then
+        compadd -a commands
+        compadd -- --offline --quiet --no-toolchain-file --version --help
+        return
+    fi
+
+    case ${words[CURRENT-1]} in
+        default|run|shell|remove|set|update) compadd -a toolchains ;;
+        toolchain) compadd list add remove link export import ;;
+        override) compadd set unset list ;;
+        self) compadd install uninstall ;;
+        completions) compadd bash zsh fish ;;
+        help) compadd -a commands ;;
+        *) _files ;;
+    esac
+}
+
+compdef _rustdn rustdn
+"#;
+
+const FISH: &str = r#"function __rustdn_toolchains
+    rustdn completions --toolchains 2>/dev/null
+end
+
+function __rustdn_plus
+    string match -q -- '+*' (commandline -ct)
+end
+
+complete -c rustdn -n __fish_use_subcommand -f -a "@COMMANDS@"
+complete -c rustdn -n __fish_use_subcommand -l offline -d 'Only use cached toolchains'
+complete -c rustdn -n __fish_use_subcommand -s q -l quiet -d 'Only print errors'
+complete -c rustdn -n __fish_use_subcommand -l no-toolchain-file -d 'Ignore toolchain files'
+complete -c rustdn -n __fish_use_subcommand -s V -l version -d 'Print the version of rustdn'
+complete -c rustdn -n __fish_use_subcommand -s h -l help -d 'Print help'
+
+complete -c rustdn -n __rustdn_plus -f -a "(__rustdn_toolchains | string replace -r '^' +)"
+
+complete -c rustdn -n "__fish_seen_subcommand_from toolchain; and not __fish_seen_subcommand_from list add remove link export import" -f -a "list add remove link export import"
+complete -c rustdn -n "__fish_seen_subcommand_from override; and not __fish_seen_subcommand_from set unset list" -f -a "set unset list"
+complete -c rustdn -n "__fish_seen_subcommand_from self; and not __fish_seen_subcommand_from install uninstall" -f -a "install uninstall"
+complete -c rustdn -n "__fish_seen_subcommand_from default run shell remove set update" -f -a "(__rustdn_toolchains)"
+complete -c rustdn -n "__fish_seen_subcommand_from completions" -f -a "bash zsh fish"
+complete -c rustdn -n "__fish_seen_subcommand_from help" -f -a "@COMMANDS@"
+"#;
+
+/// Returns the completion script for `shell` (one of [`SHELLS`]).
+///
+/// `commands` are the names of all `rustdn` commands.
+pub fn script(shell: &str, commands: &[&str]) -> Option<String> {
+    let template = match shell {
+        "bash" => BASH,
+        "zsh" => ZSH,
+        "fish" => FISH,
+        _ => return None,
+    };
+
+    Some(template.replace(COMMANDS_PLACEHOLDER, &commands.join(" ")))
+}
+
+/// Returns the toolchains which should be suggested after `+`, one per line.
+///
+/// These are the channels, the cached toolchains and the linked ones.
+pub fn toolchains() -> Result<String, Error> {
+    let mut toolchains: BTreeSet<String> = Channel::iter()
+        .map(|channel| channel.as_str().to_owned())
+        .collect();
+
+    for (toolchain, _) in cached_toolchains()? {
+        match toolchain {
+            // Components and targets can't be specified after `+`
+            ToolchainOverride::Version {
+                channel, version, ..
+            } => {
+                let toolchain = ToolchainOverride::Version {
+                    channel,
+                    version,
+                    extras: Extras::default(),
+                };
+                toolchains.insert(toolchain.to_string());
+            }
+            // Paths are better completed by the shell itself,
+            // and `default` is not valid after `+`
+            ToolchainOverride::File(_) | ToolchainOverride::None => {}
+            ToolchainOverride::LocalName(name) => {
+                toolchains.insert(name);
+            }
+        }
+    }
+
+    for (name, _) in linked_toolchains()? {
+        toolchains.insert(name);
+    }
+
+    let mut out = String::new();
+    for toolchain in toolchains {
+        out.push_str(&toolchain);
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        for shell in SHELLS {
+            let script = script(shell, &["show", "run"]).unwrap();
+            assert!(!script.contains(COMMANDS_PLACEHOLDER));
+            assert!(script.contains("show run"));
+            // Same order as `rustdn toolchain` dispatches them
+            assert!(
+                script.contains("list add remove link export import"),
+                "{shell}"
+            );
+        }
+
+        assert_eq!(script("powershell", &[]), None);
+    }
+}
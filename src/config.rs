@@ -0,0 +1,560 @@
+//! User configuration (`~/.rustdn/config.toml`).
+//!
+//! ```toml
+//! # Pin rust-overlay to a specific revision, instead of using the latest master
+//! rust_overlay_rev = "f7c8a2d0e3b9..."
+//! rust_overlay_sha256 = "sha256-..."
+//!
+//! # Use a specific nixpkgs, instead of `<nixpkgs>` from `NIX_PATH`
+//! nixpkgs_rev = "2b9c1f4e..."
+//! nixpkgs_sha256 = "sha256-..."
+//!
+//! # nixpkgs used if `<nixpkgs>` is not in `NIX_PATH` (and none of the above are set),
+//! # a nixpkgs release by default
+//! nixpkgs_fallback_url = "https://github.com/NixOS/nixpkgs/archive/2b9c1f4e....tar.gz"
+//! nixpkgs_fallback_sha256 = "sha256-..."
+//!
+//! # Re-check for updates of `stable`/`beta`/`nightly` (without a version) and the default
+//! # toolchain at most once per this many seconds (24 hours by default, 0 to always re-check)
+//! cache_ttl_secs = 3600
+//!
+//! # Look for toolchain files at most this many directories above the current one
+//! # (0 to only use the one in the current directory), instead of up to `/`
+//! max_ancestor_depth = 3
+//!
+//! # Don't look for toolchain files above the root of the current git repository, or in
+//! # directories on other filesystems (by default all the parent directories are searched)
+//! stop_at_vcs_root = true
+//! stop_at_filesystem_boundary = true
+//!
+//! # Toolchain used when nothing else chooses one (the latest stable by default),
+//! # `rustdn default <toolchain>` takes priority over this
+//! default_channel = "nightly"
+//!
+//! # Build toolchains with the new nix CLI (`nix build --impure`), instead of `nix-build`.
+//! # By default `nix-build` is used, unless only `nix` is installed
+//! nix_command = "nix build"
+//!
+//! # Extra arguments for `nix-build`/`nix-instantiate` (or `nix build`/`nix eval`), appended to
+//! # the ones set by `RUSTDN_NIX_ARGS` (which is split like a shell would)
+//! extra_nix_args = ["--option", "substituters", "https://cache.example.org", "--max-jobs", "4"]
+//!
+//! # Retry builds which failed because of (what looks like) a network problem this many times
+//! # (2 by default, 0 to never retry, at most 10), and also treat errors containing these as such problems
+//! build_retries = 5
+//! transient_build_errors = ["error: unable to download"]
+//!
+//! # Tools `rustdn self install` creates proxies for, in addition to the usual ones
+//! extra_proxies = ["cargo-nextest", "wasm-bindgen"]
+//! ```
+//!
+//! All options are optional, with missing ones keeping the default behavior.
+//!
+//! `~/.rustdn` (where all `rustdn` state is kept) can be moved with `RUSTDN_HOME`,
+//! see [`rustdn_home`]. The toolchain cache alone can be moved with `RUSTDN_SHARED_CACHE`,
+//! see [`cache_dir`].
+
+use std::{env, fs, io, path::PathBuf, sync::OnceLock, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    error::{Error, IoResultExt as _},
+    toolchain::{nixpkgs_in_search_path, parse_toolchain_spec, Channel, Extras, ToolchainOverride},
+    unstd::{nix_string, shell_split},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Revision (commit hash, tag, ...) of rust-overlay to use.
+    pub rust_overlay_rev: Option<String>,
+    /// Hash of the rust-overlay tarball, makes the fetch fully reproducible.
+    pub rust_overlay_sha256: Option<String>,
+
+    /// URL of a nixpkgs tarball to use. Takes priority over [`Config::nixpkgs_rev`].
+    pub nixpkgs_url: Option<String>,
+    /// Revision of nixpkgs (in the `NixOS/nixpkgs` GitHub repository) to use.
+    pub nixpkgs_rev: Option<String>,
+    /// Hash of the nixpkgs tarball.
+    pub nixpkgs_sha256: Option<String>,
+    /// URL of a nixpkgs tarball to use if there is no `<nixpkgs>`, see [`Config::nixpkgs_expr`].
+    pub nixpkgs_fallback_url: Option<String>,
+    /// Hash of the fallback nixpkgs tarball.
+    pub nixpkgs_fallback_sha256: Option<String>,
+
+    /// For how long toolchains which update in place are trusted, see [`Config::cache_ttl`].
+    pub cache_ttl_secs: Option<u64>,
+
+    /// How many parent directories are searched for toolchain files (all of them by default).
+    pub max_ancestor_depth: Option<usize>,
+    /// Don't search for toolchain files above the root of the git repository (a directory with
+    /// `.git`).
+    pub stop_at_vcs_root: bool,
+    /// Don't search for toolchain files on other filesystems than the current directory's.
+    pub stop_at_filesystem_boundary: bool,
+
+    /// Channel (optionally with a version) of the default toolchain,
+    /// see [`Config::default_toolchain`].
+    pub default_channel: Option<String>,
+
+    /// Which nix CLI to use, see [`Config::nix_command`].
+    pub nix_command: Option<NixCommand>,
+
+    /// Extra arguments for nix, see [`Config::extra_nix_args`].
+    pub extra_nix_args: Vec<String>,
+
+    /// How many times transient build failures are retried, see [`Config::build_retries`].
+    pub build_retries: Option<u32>,
+    /// Errors which are transient, in addition to [`TRANSIENT_BUILD_ERRORS`], see
+    /// [`Config::is_transient_error`].
+    pub transient_build_errors: Vec<String>,
+
+    /// Tools to create proxies for, in addition to [`PROXIES`](crate::proxy::PROXIES).
+    pub extra_proxies: Vec<String>,
+}
+
+/// Environment variable with extra arguments for nix, see [`Config::extra_nix_args`].
+pub const NIX_ARGS_VAR: &str = "RUSTDN_NIX_ARGS";
+
+/// Arguments which `rustdn` sets itself, changing them would break the cache.
+const RESERVED_NIX_ARGS: &[&str] = &["-o", "--out-link", "--no-out-link", "-E", "--expr"];
+
+/// The nix CLI used to build (and evaluate) toolchains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NixCommand {
+    /// `nix-build` and `nix-instantiate`.
+    #[serde(rename = "nix-build")]
+    Legacy,
+    /// `nix build --impure` and `nix eval --impure` (`--impure` is required, since toolchain
+    /// expressions use `<nixpkgs>` and `fetchTarball`).
+    #[serde(rename = "nix build")]
+    New,
+}
+
+/// Default of [`Config::nixpkgs_fallback_url`] (the 24.05 release).
+const DEFAULT_NIXPKGS_FALLBACK_URL: &str = "https://github.com/NixOS/nixpkgs/archive/24.05.tar.gz";
+
+/// Default of [`Config::build_retries`].
+const DEFAULT_BUILD_RETRIES: u32 = 2;
+
+/// Maximal value of `build_retries`, more retries are unlikely to help.
+const MAX_BUILD_RETRIES: u32 = 10;
+
+/// Parts of nix errors which mean that the build failed because of a (most likely transient)
+/// network problem, rather than a problem with the toolchain.
+///
+/// N.B.: these are specific on purpose, e.g. 404s are not here, since retrying them is pointless.
+const TRANSIENT_BUILD_ERRORS: &[&str] = &[
+    "HTTP error 429",
+    "HTTP error 500",
+    "HTTP error 502",
+    "HTTP error 503",
+    "HTTP error 504",
+    "Timeout was reached",
+    "Connection timed out",
+    "Connection reset by peer",
+    "SSL connect error",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "Failure when receiving data from the peer",
+];
+
+/// Default of [`Config::cache_ttl_secs`].
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Environment variable which overrides the directory where `rustdn` keeps its state.
+pub const HOME_VAR: &str = "RUSTDN_HOME";
+
+/// Environment variable which moves the toolchain cache out of the rustdn home, see [`cache_dir`].
+pub const SHARED_CACHE_VAR: &str = "RUSTDN_SHARED_CACHE";
+
+/// Environment variable which makes `rustdn` (and proxies) quiet, see [`quiet`].
+pub const QUIET_VAR: &str = "RUSTDN_QUIET";
+
+/// Returns `true` if only errors (and the output of the commands themselves) should be printed,
+/// i.e. `--quiet` was used, or [`QUIET_VAR`] is set.
+///
+/// This disables logs below the error level (unless `RUSTDN_LOG` is set), the throbber, the
+/// progress of nix builds (unless they fail) and messages like "removed `nightly`".
+/// The output of the tools run by proxies is not affected.
+pub fn quiet() -> bool {
+    env::var_os(QUIET_VAR).is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Returns `true` if output should be colored, `is_terminal` is whether it goes to a terminal.
+///
+/// This is controlled by `RUSTDN_COLOR=always|never|auto` (`auto` by default).
+/// With `auto` colors are used if the output is a terminal and `NO_COLOR` is not set
+/// (see <https://no-color.org>).
+pub fn use_color(is_terminal: bool) -> bool {
+    match env::var("RUSTDN_COLOR").as_deref() {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()) && is_terminal,
+    }
+}
+
+/// Returns the directory where `rustdn` keeps all its state (toolchains, links, configuration).
+///
+/// This is the first of:
+/// - `$RUSTDN_HOME`
+/// - `~/.rustdn`
+/// - `$XDG_DATA_HOME/rustdn` (only used if there is no home directory)
+///
+/// The directory is checked with [`check_state_dir`], so that a misconfigured environment (e.g.
+/// a relative `$HOME`) is an error, instead of state being silently kept in the current
+/// directory.
+pub fn rustdn_home() -> Result<PathBuf, Error> {
+    let var = |name| {
+        env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+
+    if let Some(home) = var(HOME_VAR) {
+        return check_state_dir(home, &format!("set by `{HOME_VAR}`"));
+    }
+
+    if let Some(home) = dirs::home_dir().filter(|h| !h.as_os_str().is_empty()) {
+        let origin = format!("in the home directory, set `{HOME_VAR}` to use another directory");
+        return check_state_dir(home.join(".rustdn"), &origin);
+    }
+
+    match var("XDG_DATA_HOME") {
+        Some(data) => {
+            let origin = format!("in `XDG_DATA_HOME`, set `{HOME_VAR}` to use another directory");
+            check_state_dir(data.join("rustdn"), &origin)
+        }
+        None => Err(Error::NoHome),
+    }
+}
+
+/// Checks that `dir` (`origin` says where it comes from, for errors) can be used to keep state in, i.e. that it's an
+/// absolute path, and that it's either a directory or can be created (its closest existing
+/// ancestor is a directory). Nothing is created here.
+///
+/// N.B.: other errors (e.g. missing permissions) are left to whoever uses the directory, they
+///       report them with the exact path which failed.
+fn check_state_dir(dir: PathBuf, origin: &str) -> Result<PathBuf, Error> {
+    let invalid = |reason: String| Error::InvalidStateDir {
+        path: dir.clone(),
+        origin: origin.to_owned(),
+        reason,
+    };
+
+    if !dir.is_absolute() {
+        return Err(invalid("it's not an absolute path".to_owned()));
+    }
+
+    for ancestor in dir.ancestors() {
+        match fs::metadata(ancestor) {
+            Ok(m) if m.is_dir() => break,
+            Ok(_) if ancestor == dir => return Err(invalid("it's not a directory".to_owned())),
+            Ok(_) => {
+                return Err(invalid(format!(
+                    "it can't be created, `{}` is not a directory",
+                    ancestor.display()
+                )))
+            }
+            // N.B.: `ENOTDIR` means that some ancestor is a file, which is found later
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || rustix::io::Errno::from_io_error(&e) == Some(rustix::io::Errno::NOTDIR) => {}
+            Err(_) => break,
+        }
+    }
+
+    Ok(dir)
+}
+
+/// Returns the directory with the toolchain cache (`toolchains/` and their `locks/`).
+///
+/// This is `$RUSTDN_SHARED_CACHE`, or the [rustdn home](rustdn_home). The rest of the state
+/// (configuration, links, overrides, the default toolchain) always stays in the home.
+///
+/// The cache can be moved (e.g. saved and restored by CI) and can be read-only: cache keys don't
+/// depend on where the cache is, and the metadata in the cache directories (copies of toolchain
+/// files, update times, `meta.toml`) is only ever used relative to them. What's *not* portable
+/// is managed by nix:
+/// - The out-links point to absolute store paths, which only work if the store has them (a
+///   toolchain whose store path is missing is rebuilt)
+/// - nix registers the out-links as gc roots by their path, so after moving the cache the
+///   toolchains are not protected from `nix-store --gc` until they are rebuilt
+/// - Keys of toolchain files contain the absolute path of the file
+///
+/// To restore just the set of toolchains (and let nix get them), see `rustdn toolchain export`.
+pub fn cache_dir() -> Result<PathBuf, Error> {
+    match env::var_os(SHARED_CACHE_VAR).filter(|v| !v.is_empty()) {
+        Some(dir) => check_state_dir(PathBuf::from(dir), &format!("set by `{SHARED_CACHE_VAR}`")),
+        None => rustdn_home(),
+    }
+}
+
+/// Returns path to the configuration file.
+fn config_file() -> Result<PathBuf, Error> {
+    Ok(rustdn_home()?.join("config.toml"))
+}
+
+/// Returns the configuration, loading it on the first call.
+///
+/// A missing configuration file is the same as an empty one.
+pub fn get() -> Result<&'static Config, Error> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+
+    // Unit tests must not depend on the configuration of whoever runs them
+    if cfg!(test) {
+        return Ok(CONFIG.get_or_init(Config::default));
+    }
+
+    let config = load()?;
+
+    // If someone else managed to set it in the meantime, theirs is just as good
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+fn load() -> Result<Config, Error> {
+    let path = config_file()?;
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).with_path(path),
+    };
+
+    let config: Config = toml::from_str(&contents).map_err(|e| Error::Config {
+        path: path.clone(),
+        reason: e.to_string(),
+    })?;
+
+    if let Some(channel) = &config.default_channel {
+        if parse_default_channel(channel).is_none() {
+            return Err(Error::Config {
+                path,
+                reason: format!(
+                    "invalid `default_channel` `{channel}`, \
+                     expected a channel like `stable`, `nightly-2024-05-01` or `1.80`"
+                ),
+            });
+        }
+    }
+
+    if let Some(retries) = config.build_retries.filter(|&r| r > MAX_BUILD_RETRIES) {
+        return Err(Error::Config {
+            path,
+            reason: format!(
+                "invalid `build_retries` `{retries}`, expected at most {MAX_BUILD_RETRIES}"
+            ),
+        });
+    }
+
+    for (field, sha256) in [
+        ("rust_overlay_sha256", &config.rust_overlay_sha256),
+        ("nixpkgs_sha256", &config.nixpkgs_sha256),
+        ("nixpkgs_fallback_sha256", &config.nixpkgs_fallback_sha256),
+    ] {
+        if let Some(sha256) = sha256.as_deref().filter(|h| !is_sha256(h)) {
+            return Err(Error::Config {
+                path,
+                reason: format!(
+                    "invalid `{field}` `{sha256}`, expected a sha256 hash, \
+                     like `sha256-<base64>` or 52 characters of nix's base32"
+                ),
+            });
+        }
+    }
+
+    Ok(config)
+}
+
+/// Returns `true` if `s` is a sha256 hash in one of the formats nix accepts: SRI
+/// (`sha256-<base64>`), nix's base32 or hex.
+fn is_sha256(s: &str) -> bool {
+    const BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+    match s.strip_prefix("sha256-") {
+        Some(base64) => {
+            base64.len() == 44
+                && base64.ends_with('=')
+                && base64[..43]
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+        }
+        None if s.len() == 52 => s.bytes().all(|b| BASE32.contains(&b)),
+        None => s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()),
+    }
+}
+
+/// Parses [`Config::default_channel`], only channel based toolchains are allowed.
+fn parse_default_channel(s: &str) -> Option<ToolchainOverride> {
+    parse_toolchain_spec(s)
+        .ok()
+        .filter(|t| matches!(t, ToolchainOverride::Version { .. }))
+}
+
+impl Config {
+    /// Returns a nix expression which evaluates to nixpkgs source.
+    ///
+    /// If nixpkgs is not configured, this is `<nixpkgs>` from the nix search path, or, if it's
+    /// not there (see [`nixpkgs_in_search_path`]), the fallback nixpkgs.
+    pub fn nixpkgs_expr(&self) -> String {
+        let url = match (&self.nixpkgs_url, &self.nixpkgs_rev) {
+            (Some(url), _) => url.clone(),
+            (None, Some(rev)) => format!("https://github.com/NixOS/nixpkgs/archive/{rev}.tar.gz"),
+            // Ambient nixpkgs
+            (None, None) if nixpkgs_in_search_path(self) => return "<nixpkgs>".to_owned(),
+            (None, None) => {
+                let url = self
+                    .nixpkgs_fallback_url
+                    .as_deref()
+                    .unwrap_or(DEFAULT_NIXPKGS_FALLBACK_URL);
+
+                return fetch_tarball(url, self.nixpkgs_fallback_sha256.as_deref());
+            }
+        };
+
+        fetch_tarball(&url, self.nixpkgs_sha256.as_deref())
+    }
+
+    /// Returns for how long a cached toolchain which updates in place (like `nightly`) is used,
+    /// before checking for updates again.
+    pub fn cache_ttl(&self) -> Duration {
+        self.cache_ttl_secs
+            .map_or(DEFAULT_CACHE_TTL, Duration::from_secs)
+    }
+
+    /// Returns how many times a build is retried, if it failed because of a transient problem
+    /// (see [`Config::is_transient_error`]).
+    pub fn build_retries(&self) -> u32 {
+        self.build_retries.unwrap_or(DEFAULT_BUILD_RETRIES)
+    }
+
+    /// Returns `true` if `line` (of the output of nix) is an error which is likely to go away on
+    /// its own, i.e. contains one of [`TRANSIENT_BUILD_ERRORS`] or `transient_build_errors` from
+    /// the config.
+    ///
+    /// Builds which fail with such errors are retried, all others (e.g. evaluation errors) fail
+    /// right away.
+    pub fn is_transient_error(&self, line: &str) -> bool {
+        let configured = self
+            .transient_build_errors
+            .iter()
+            .map(String::as_str)
+            .filter(|error| !error.is_empty());
+
+        TRANSIENT_BUILD_ERRORS
+            .iter()
+            .copied()
+            .chain(configured)
+            .any(|error| line.contains(error))
+    }
+
+    /// Returns the toolchain which [`ToolchainOverride::None`] stands for, i.e. the one used when
+    /// nothing else (including `rustdn default`) chooses a toolchain.
+    ///
+    /// This is `default_channel` from the config, or the latest stable.
+    pub fn default_toolchain(&self) -> ToolchainOverride {
+        // N.B.: `default_channel` is validated when the config is loaded
+        self.default_channel
+            .as_deref()
+            .and_then(parse_default_channel)
+            .unwrap_or(ToolchainOverride::Version {
+                channel: Channel::Stable,
+                version: None,
+                extras: Extras::default(),
+            })
+    }
+
+    /// Returns the nix CLI to use.
+    ///
+    /// If it's not configured, this is [`NixCommand::Legacy`], unless `nix-build` is not in
+    /// `PATH`, but `nix` is.
+    pub fn nix_command(&self) -> NixCommand {
+        if let Some(command) = self.nix_command {
+            return command;
+        }
+
+        if !in_path("nix-build") && in_path("nix") {
+            return NixCommand::New;
+        }
+
+        NixCommand::Legacy
+    }
+
+    /// Returns the extra arguments which should be passed to nix, when building or evaluating
+    /// toolchains.
+    ///
+    /// These are the arguments from [`NIX_ARGS_VAR`] (split like a shell would), followed by
+    /// `extra_nix_args` from the config. Arguments which `rustdn` sets itself (like `--out-link`
+    /// or `--expr`) are rejected, since changing them would break the cache.
+    pub fn extra_nix_args(&self) -> Result<Vec<String>, Error> {
+        let invalid = |origin: &str, reason: String| Error::InvalidNixArgs {
+            origin: origin.to_owned(),
+            reason,
+        };
+
+        let mut args = match env::var(NIX_ARGS_VAR) {
+            Ok(var) => shell_split(&var)
+                .ok_or_else(|| invalid(NIX_ARGS_VAR, "unterminated quote".to_owned()))?,
+            Err(_) => Vec::new(),
+        };
+        let from_env = args.len();
+        args.extend(self.extra_nix_args.iter().cloned());
+
+        for (i, arg) in args.iter().enumerate() {
+            // `--out-link=...` is the same as `--out-link ...`
+            let flag = arg.split('=').next().unwrap_or(arg);
+            if RESERVED_NIX_ARGS.contains(&flag) {
+                let origin = if i < from_env {
+                    NIX_ARGS_VAR
+                } else {
+                    "extra_nix_args"
+                };
+                return Err(invalid(origin, format!("`{arg}` is set by rustdn itself")));
+            }
+        }
+
+        Ok(args)
+    }
+
+    /// Returns a nix expression which evaluates to rust-overlay source.
+    pub fn rust_overlay_expr(&self) -> String {
+        let rev = self.rust_overlay_rev.as_deref().unwrap_or("master");
+        let url = format!("https://github.com/oxalica/rust-overlay/archive/{rev}.tar.gz");
+
+        fetch_tarball(&url, self.rust_overlay_sha256.as_deref())
+    }
+}
+
+/// Returns `true` if there is a `program` in `PATH`.
+fn in_path(program: &str) -> bool {
+    find_in_path(program).is_some()
+}
+
+/// Returns the path to the first `program` in `PATH` (the one a shell would run).
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|program| program.is_file())
+}
+
+/// Returns a nix expression which fetches the tarball at `url` (checking its hash, if given).
+///
+/// N.B.: both come from the config, so they are quoted.
+fn fetch_tarball(url: &str, sha256: Option<&str>) -> String {
+    let url = nix_string(url);
+
+    match sha256 {
+        Some(sha256) => format!(
+            "(builtins.fetchTarball {{ url = {url}; sha256 = {}; }})",
+            nix_string(sha256)
+        ),
+        None => format!("(builtins.fetchTarball {url})"),
+    }
+}
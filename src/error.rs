@@ -0,0 +1,318 @@
+//! Error handling.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+
+/// An error which stops `rustdn` from doing what it was asked to do.
+///
+/// These are reported to the user as `error: {error}`.
+#[derive(Debug)]
+pub enum Error {
+    /// `nix-build` (or `nix build`) exited unsuccessfully.
+    ///
+    /// nix reports its own errors to stderr, so there is not much to add.
+    NixBuild { status: ExitStatus },
+
+    /// The toolchain was built, but its store path doesn't have the hash specified with
+    /// `+<toolchain>@<hash>`.
+    StoreHashMismatch { toolchain: String, actual: PathBuf },
+
+    /// nix built the toolchain "successfully", but the result doesn't have `bin/rustc`, i.e.
+    /// the nix expression is wrong (e.g. selects an unexpected attribute).
+    BuildWithoutRustc { expr: String },
+
+    /// `nix-instantiate` (or `nix eval`) exited unsuccessfully (or printed something unexpected).
+    NixEval { status: ExitStatus },
+
+    /// A nix program (like `nix-build`) is not installed (or not in `PATH`).
+    NixNotFound { program: String },
+
+    /// Locking (or upgrading a lock on) a lock file failed.
+    Lock {
+        path: PathBuf,
+        source: rustix::io::Errno,
+    },
+
+    /// Waiting for a lock took too long.
+    LockTimeout { path: PathBuf, timeout: Duration },
+
+    /// An IO operation on `path` failed.
+    Io { path: PathBuf, source: io::Error },
+
+    /// A program couldn't be started.
+    Spawn { program: PathBuf, source: io::Error },
+
+    /// A `+<toolchain>` override couldn't be parsed.
+    InvalidToolchain { spec: String },
+
+    /// A toolchain file couldn't be parsed.
+    InvalidToolchainFile { path: PathBuf, reason: String },
+
+    /// A `rustdn` configuration file couldn't be parsed.
+    Config { path: PathBuf, reason: String },
+
+    /// Extra arguments for nix (from `origin`, the config or an environment variable) are invalid.
+    InvalidNixArgs { origin: String, reason: String },
+
+    /// A linked toolchain doesn't look like a toolchain.
+    InvalidLink { name: String, path: PathBuf },
+
+    /// The toolchain is not in the cache.
+    ToolchainNotInstalled { toolchain: String },
+
+    /// The toolchain is not in the cache, and can't be built in offline mode.
+    Offline { toolchain: String },
+
+    /// The toolchain needs to be built (or updated), but the cache (`path`) is read-only.
+    ReadOnlyCache { toolchain: String, path: PathBuf },
+
+    /// The selected toolchain doesn't have a requested tool.
+    ToolNotFound {
+        tool: String,
+        toolchain: String,
+        /// The component which (probably) provides the tool.
+        component: Option<&'static str>,
+    },
+
+    /// The selected toolchain doesn't have the requested documentation.
+    DocsNotFound {
+        toolchain: String,
+        /// The crate whose docs were requested (`None` for the documentation index).
+        krate: Option<String>,
+    },
+
+    /// The binary a proxy would run is `rustdn` itself (e.g. a toolchain's `bin` contains
+    /// symlinks to `rustdn`), running it would loop forever.
+    ProxyLoop { tool: String, path: PathBuf },
+
+    /// `rustdn self install` would replace a file which is not a symlink.
+    ProxyConflict { path: PathBuf },
+
+    /// Some of the toolchains requested by `rustdn toolchain add` couldn't be installed
+    /// (the errors are reported separately).
+    InstallFailed { failed: usize, total: usize },
+
+    /// Some of the toolchains couldn't be updated by `rustdn update` (the errors are reported
+    /// separately).
+    UpdateFailed { failed: usize, total: usize },
+
+    /// Some of the checks of `rustdn doctor` failed (the problems are reported separately).
+    ChecksFailed { failed: usize },
+
+    /// The user didn't confirm `rustdn self uninstall` (`terminal` is `false` if stdin is not a
+    /// terminal, so they couldn't have).
+    Aborted { terminal: bool },
+
+    /// There is no home directory (and no `RUSTDN_HOME`), so there is nowhere to keep the state.
+    NoHome,
+
+    /// The directory chosen to keep the state in can't be used, `origin` explains where it
+    /// came from (e.g. `RUSTDN_HOME`).
+    InvalidStateDir {
+        path: PathBuf,
+        origin: String,
+        reason: String,
+    },
+
+    /// The current directory couldn't be determined (e.g. it was deleted), so neither could the
+    /// toolchain.
+    NoCurrentDir { source: io::Error },
+
+    /// Command line arguments don't make sense.
+    Usage(String),
+}
+
+impl Error {
+    /// Exit code which `rustdn` should exit with, after reporting this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // Forward the exit code of `nix-build`.
+            Error::NixBuild { status } | Error::NixEval { status } => status.code().unwrap_or(1),
+            Error::Usage(_) => 2,
+            // Same as shells do for commands which can't be found.
+            Error::ToolNotFound { .. } => 127,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NixBuild { status } => {
+                write!(f, "building the toolchain with nix failed ({status})")
+            }
+            Error::BuildWithoutRustc { expr } => write!(
+                f,
+                "nix built the toolchain, but the result doesn't contain `bin/rustc` \
+                 (the expression was `{expr}`)"
+            ),
+            Error::StoreHashMismatch { toolchain, actual } => write!(
+                f,
+                "toolchain `{toolchain}` was built as `{}`, which doesn't have the expected hash \
+                 (the toolchain has changed since the hash was recorded, \
+                 pin the version of the toolchain too or update the hash)",
+                actual.display()
+            ),
+            Error::NixEval { status } => {
+                write!(f, "evaluating the toolchain with nix failed ({status})")
+            }
+            Error::NixNotFound { program } => write!(
+                f,
+                "couldn't find `{program}`; rustdn uses Nix to install toolchains, \
+                 see <https://nixos.org/download> for how to install it"
+            ),
+            Error::Lock { path, source } => {
+                write!(f, "couldn't lock `{}`: {source}", path.display())
+            }
+            Error::LockTimeout { path, timeout } => write!(
+                f,
+                "timed out after {}s while waiting for a lock on `{}` \
+                 (another `rustdn` process might be stuck updating the toolchain)",
+                timeout.as_secs(),
+                path.display()
+            ),
+            Error::Io { path, source } => write!(f, "`{}`: {source}", path.display()),
+            Error::Spawn { program, source } => {
+                write!(f, "couldn't run `{}`: {source}", program.display())
+            }
+            Error::InvalidToolchain { spec } => {
+                write!(f, "invalid toolchain override `{spec}`")?;
+
+                // rust-overlay has stable releases by version, betas and nightlies by date
+                let hint = match spec.trim_start_matches('+').split_once('-') {
+                    Some(("stable", _)) => "stable versions are releases, like `stable-1.79.0`",
+                    Some(("beta", _)) => "beta versions are dates, like `beta-2024-05-01`",
+                    Some(("nightly", _)) => "nightly versions are dates, like `nightly-2024-05-01`",
+                    _ => return Ok(()),
+                };
+                write!(f, " ({hint})")?;
+
+                Ok(())
+            }
+            Error::InvalidToolchainFile { path, reason } => {
+                write!(f, "invalid toolchain file `{}`: {reason}", path.display())
+            }
+            Error::Config { path, reason } => write!(f, "`{}`: {reason}", path.display()),
+            Error::InvalidNixArgs { origin, reason } => {
+                write!(f, "invalid extra nix arguments in `{origin}`: {reason}")
+            }
+            Error::InvalidLink { name, path } => write!(
+                f,
+                "toolchain `{name}` links to `{}`, which doesn't contain `bin/rustc`",
+                path.display()
+            ),
+            Error::ToolchainNotInstalled { toolchain } => {
+                write!(f, "toolchain `{toolchain}` is not installed")
+            }
+            Error::Offline { toolchain } => write!(
+                f,
+                "toolchain `{toolchain}` is not installed (and can't be installed in offline mode)"
+            ),
+            Error::ReadOnlyCache { toolchain, path } => write!(
+                f,
+                "toolchain `{toolchain}` needs to be installed or updated, \
+                 but the cache (`{}`) is read-only",
+                path.display()
+            ),
+            Error::ToolNotFound {
+                tool,
+                toolchain,
+                component,
+            } => {
+                write!(f, "`{tool}` is not available in toolchain `{toolchain}`")?;
+                if let Some(component) = component {
+                    write!(f, "; it may require the `{component}` component")?;
+                }
+                Ok(())
+            }
+            Error::DocsNotFound {
+                toolchain,
+                krate: Some(krate),
+            } => write!(
+                f,
+                "toolchain `{toolchain}` doesn't have documentation for the `{krate}` crate"
+            ),
+            Error::DocsNotFound {
+                toolchain,
+                krate: None,
+            } => write!(
+                f,
+                "toolchain `{toolchain}` doesn't have documentation; \
+                 it may require the `rust-docs` component"
+            ),
+            Error::ProxyLoop { tool, path } => write!(
+                f,
+                "`{}` is a `rustdn` proxy, refusing to run `{tool}` from it (it would run itself)",
+                path.display()
+            ),
+            Error::ProxyConflict { path } => write!(
+                f,
+                "`{}` already exists and is not a symlink, use `--force` to replace it",
+                path.display()
+            ),
+            Error::InstallFailed { failed, total } => {
+                write!(f, "couldn't install {failed} of {total} toolchains")
+            }
+            Error::UpdateFailed { failed, total } => {
+                write!(f, "couldn't update {failed} of {total} toolchains")
+            }
+            Error::ChecksFailed { failed: 1 } => f.write_str("1 check failed"),
+            Error::ChecksFailed { failed } => write!(f, "{failed} checks failed"),
+            Error::Aborted { terminal: true } => f.write_str("aborted, nothing was removed"),
+            Error::Aborted { terminal: false } => f.write_str(
+                "aborted, nothing was removed \
+                 (stdin is not a terminal, use `--yes` to remove without confirmation)",
+            ),
+            Error::NoHome => f.write_str(
+                "couldn't find the home directory, \
+                 set `RUSTDN_HOME` to choose where rustdn keeps its state",
+            ),
+            Error::InvalidStateDir {
+                path,
+                origin,
+                reason,
+            } => write!(
+                f,
+                "can't keep rustdn state in `{}`: {reason} ({origin})",
+                path.display()
+            ),
+            Error::NoCurrentDir { source } => write!(
+                f,
+                "couldn't get the current directory ({source}), it might have been deleted; \
+                 `cd` to another directory and try again"
+            ),
+            Error::Usage(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Lock { source, .. } => Some(source),
+            Error::Io { source, .. }
+            | Error::Spawn { source, .. }
+            | Error::NoCurrentDir { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub trait IoResultExt<T> {
+    /// Converts an IO error into an [`Error::Io`], attaching the `path` the IO was performed on.
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, Error>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            path: path.as_ref().to_owned(),
+            source,
+        })
+    }
+}
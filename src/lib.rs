@@ -0,0 +1,113 @@
+//! `rustdn` as a library.
+//!
+//! The `rustdn` binary (and its proxies) is a thin wrapper around this crate. The library exposes
+//! the toolchain resolution, so that other tools (test harnesses, editor plugins, ...) can choose
+//! and install toolchains exactly like `rustdn` does, without running it.
+//!
+//! ```no_run
+//! let cwd = std::env::current_dir().unwrap();
+//! let resolved = rustdn::resolve_toolchain(&["build", "+nightly"], &cwd).unwrap();
+//! let toolchain = rustdn::ensure_toolchain(&resolved.toolchain).unwrap();
+//!
+//! println!("rustc is at {}", toolchain.join("bin/rustc").display());
+//! ```
+
+mod completions;
+mod config;
+mod error;
+mod lock;
+mod overrides;
+mod proxy;
+mod rustdn;
+mod throbber;
+mod toolchain;
+mod toolchain_file;
+mod unstd;
+
+use std::{
+    convert::Infallible,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+pub use crate::{
+    error::Error,
+    toolchain::{Channel, Extras, Profile, ToolchainOverride, ToolchainSource},
+};
+
+/// A toolchain chosen by [`resolve_toolchain`].
+#[derive(Debug, Clone)]
+pub struct ResolvedToolchain {
+    pub toolchain: ToolchainOverride,
+    /// Why this toolchain was chosen.
+    pub source: ToolchainSource,
+}
+
+/// Chooses the toolchain a proxy would use, if it was run with `args` in `cwd`.
+///
+/// The first `+<toolchain>` in `args` (before `--`) overrides the toolchain, relative toolchain
+/// file paths in it are relative to `cwd`. Otherwise `RUSTUP_TOOLCHAIN`, directory overrides,
+/// `RUSTDN_TOOLCHAIN_FILE`, toolchain files in `cwd` (and its parents) and the default toolchain
+/// are considered, in the same order as for proxies.
+///
+/// This never builds or downloads anything, see [`ensure_toolchain`] for that.
+pub fn resolve_toolchain(args: &[impl AsRef<str>], cwd: &Path) -> Result<ResolvedToolchain, Error> {
+    let override_arg = args
+        .iter()
+        .map(AsRef::as_ref)
+        .take_while(|&arg| arg != "--")
+        .find_map(|arg| {
+            let arg = match arg.strip_prefix("+.") {
+                Some(path) => format!("+{}", cwd.join(format!(".{path}")).display()),
+                None => arg.to_owned(),
+            };
+
+            matches!(toolchain::parse_toolchain_override(Some(&arg)), Ok(Some(_))).then_some(arg)
+        });
+
+    let (toolchain, source) = toolchain::resolve_toolchain_in(override_arg.as_deref(), cwd)?;
+
+    Ok(ResolvedToolchain { toolchain, source })
+}
+
+/// Returns the path to `toolchain`, building (or updating) it first if necessary.
+///
+/// The path is the toolchain in the nix store, i.e. `<path>/bin/rustc` is the compiler. Each
+/// toolchain is only resolved once per process, so calling this again is cheap, and returns the
+/// same path. This respects offline mode (`RUSTDN_OFFLINE`).
+pub fn ensure_toolchain(toolchain: &ToolchainOverride) -> Result<PathBuf, Error> {
+    toolchain::get_or_update_toolchain(toolchain.clone())
+}
+
+#[doc(hidden)]
+pub use crate::config::{quiet, use_color, QUIET_VAR};
+
+/// Entry point of the `rustdn` command (`args` are the arguments after the binary name).
+#[doc(hidden)]
+pub fn rustdn_main(args: impl Iterator<Item = OsString>) -> Result<(), Error> {
+    rustdn::main(args)
+}
+
+/// Entry point of the proxy for `tool` (`args` are the arguments after the binary name).
+#[doc(hidden)]
+pub fn proxy_main(tool: &str, args: impl Iterator<Item = OsString>) -> Result<Infallible, Error> {
+    proxy::main(tool, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_override() {
+        let cwd = Path::new("/");
+
+        let resolved = resolve_toolchain(&["build", "+nightly", "+beta"], cwd).unwrap();
+        assert_eq!(resolved.source, ToolchainSource::CommandLine);
+        assert_eq!(resolved.toolchain.to_string(), "nightly");
+
+        // Not a toolchain, so it's skipped
+        let resolved = resolve_toolchain(&["+not-a-toolchain", "+1.80"], cwd).unwrap();
+        assert_eq!(resolved.toolchain.to_string(), "stable-1.80");
+    }
+}
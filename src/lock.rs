@@ -47,26 +47,90 @@
 //! [^2]: i have not checked the state of file locking on windows, since there is no need for that, as per the note above.
 //! [^3]: because while you *can* open a directory for reading, you can't open it for writing and exclusive `fcntl` locks require write permissions
 
-use std::{fs::File, ops::Deref, os::fd::AsFd};
+use std::{
+    fs::File,
+    ops::Deref,
+    os::fd::AsFd,
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::destructure;
 
-// FIXME: this should provide a `cfg(test)` implementation that works only in-process, rather than between processes.
-//        (i.e. use `static STATE: Mutex<...>` to manage locks, instead of `fcntl`, so that we can run unit tests)
-// FIXME: add `cfg(debug_assertions)` code, which would check that we are not locking the same file multiple times in-process
+// N.B.: under `cfg(test)` locks are implemented in-process (see `imp`), rather than with `fcntl`,
+//       so that we can test them with threads.
+
+/// How often [`lock_shared_timeout`] re-tries to acquire the lock.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Acquires a shared lock on `file`.
 ///
 /// **N.B.** `file` must be opened for reading.
 ///
 /// This blocks until a shared lock can be acquired.
+#[allow(dead_code)] // only used in tests for now
 pub fn lock_shared<F>(file: F) -> rustix::io::Result<Lock<F, Shared>>
 where
     F: Deref<Target = File>,
 {
-    rustix::fs::fcntl_lock(file.as_fd(), rustix::fs::FlockOperation::LockShared)?;
+    lock_shared_timeout(file, Duration::MAX)
+}
+
+/// Acquires a shared lock on `file`, giving up after `timeout`.
+///
+/// **N.B.** `file` must be opened for reading.
+///
+/// This blocks until a shared lock can be acquired or until `timeout` passes,
+/// in which case [`TIMEDOUT`] error is returned.
+/// If `timeout` is too large to be represented as a deadline (e.g. [`Duration::MAX`]),
+/// this blocks indefinitely.
+///
+/// [`TIMEDOUT`]: rustix::io::Errno::TIMEDOUT
+pub fn lock_shared_timeout<F>(file: F, timeout: Duration) -> rustix::io::Result<Lock<F, Shared>>
+where
+    F: Deref<Target = File>,
+{
+    let Some(deadline) = Instant::now().checked_add(timeout) else {
+        // No deadline, so we can just block.
+        imp::lock_shared(file.as_fd())?;
+        held::insert(file.as_fd());
+
+        return Ok(Lock { file, mode: Shared });
+    };
+
+    // `fcntl` doesn't support timeouts, so we have to poll.
+    loop {
+        if imp::try_lock_shared(file.as_fd())? {
+            held::insert(file.as_fd());
+
+            return Ok(Lock { file, mode: Shared });
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(rustix::io::Errno::TIMEDOUT);
+        }
+
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Tries to acquire a shared lock on `file`, without blocking.
+///
+/// **N.B.** `file` must be opened for reading.
+///
+/// Returns `Ok(None)` if the lock can't be acquired right now,
+/// because someone holds an exclusive lock.
+pub fn try_lock_shared<F>(file: F) -> rustix::io::Result<Option<Lock<F, Shared>>>
+where
+    F: Deref<Target = File>,
+{
+    if !imp::try_lock_shared(file.as_fd())? {
+        return Ok(None);
+    }
+    held::insert(file.as_fd());
 
-    Ok(Lock { file, mode: Shared })
+    Ok(Some(Lock { file, mode: Shared }))
 }
 
 pub struct Shared;
@@ -111,7 +175,7 @@ where
         // to give an opportunity for someone to actually acquire exclusive lock.
         //
         // on the error-path this drops `self`, which unlocks the lock.
-        rustix::fs::fcntl_lock(self.file.as_fd(), rustix::fs::FlockOperation::LockExclusive)?;
+        imp::upgrade(self.file.as_fd())?;
 
         // `destructure` does not run the destructor, so this **doesn't** unlock the lock.
         destructure!(Lock { file, mode: _ } = self);
@@ -119,6 +183,51 @@ where
 
         Ok(Lock { file, mode })
     }
+
+    /// Given a shared lock, try upgrading it to an exclusive one, without blocking.
+    ///
+    /// **N.B.**: the underlying `file` must be opened for writing.
+    ///
+    /// Returns `Ok(Err(self))` if the lock can't be upgraded right now,
+    /// because someone else holds a shared lock. In this case the shared lock is kept.
+    ///
+    /// On error, the shared lock is released.
+    pub fn try_upgrade(self) -> rustix::io::Result<Result<Lock<F, Exclusive>, Self>> {
+        // on the error-path this drops `self`, which unlocks the lock.
+        if !imp::try_upgrade(self.file.as_fd())? {
+            return Ok(Err(self));
+        }
+
+        // `destructure` does not run the destructor, so this **doesn't** unlock the lock.
+        destructure!(Lock { file, mode: _ } = self);
+        let mode = Exclusive;
+
+        Ok(Ok(Lock { file, mode }))
+    }
+}
+
+impl<F> Lock<F, Exclusive>
+where
+    F: Deref<Target = File>,
+{
+    /// Given an exclusive lock, downgrade it to a shared one.
+    ///
+    /// Unlike unlocking and then locking again, this doesn't give a chance to anyone else to
+    /// acquire an exclusive lock in-between.
+    ///
+    /// On error, the exclusive lock is released.
+    ///
+    /// Under the hood this re-locks the file using `fcntl`.
+    pub fn downgrade(self) -> rustix::io::Result<Lock<F, Shared>> {
+        // on the error-path this drops `self`, which unlocks the lock.
+        imp::downgrade(self.file.as_fd())?;
+
+        // `destructure` does not run the destructor, so this **doesn't** unlock the lock.
+        destructure!(Lock { file, mode: _ } = self);
+        let mode = Shared;
+
+        Ok(Lock { file, mode })
+    }
 }
 
 // we could have a `impl<F, M> Deref for Lock<F, M>`, but we don't need it,
@@ -131,6 +240,422 @@ where
     F: Deref<Target = File>,
 {
     fn drop(&mut self) {
-        _ = rustix::fs::fcntl_lock(self.file.as_fd(), rustix::fs::FlockOperation::Unlock);
+        held::remove(self.file.as_fd());
+        imp::unlock(self.file.as_fd());
+    }
+}
+
+/// Returns an identity of the file which is the same for all `File`s referring to it.
+#[cfg(any(test, debug_assertions))]
+fn file_id(fd: std::os::fd::BorrowedFd<'_>) -> rustix::io::Result<(u64, u64)> {
+    let stat = rustix::fs::fstat(fd)?;
+    Ok((stat.st_dev, stat.st_ino))
+}
+
+/// Tracking of the files locked by this thread, to catch locking the same file twice.
+///
+/// `fcntl` locks are per-process, so locking an already locked file just succeeds (replacing
+/// the old lock) and then unlocking either of the locks unlocks both... which is not at all what
+/// we want. So this asserts that it doesn't happen.
+///
+/// Tracking is thread-local, rather than process-global, so that the in-process `cfg(test)`
+/// implementation can treat threads as processes.
+///
+/// This is only done in debug builds.
+#[cfg(debug_assertions)]
+mod held {
+    use std::{cell::RefCell, collections::BTreeSet, os::fd::BorrowedFd};
+
+    thread_local! {
+        static HELD: RefCell<BTreeSet<(u64, u64)>> = const { RefCell::new(BTreeSet::new()) };
+    }
+
+    pub(super) fn insert(fd: BorrowedFd<'_>) {
+        let Ok(id) = super::file_id(fd) else { return };
+
+        let new = HELD.with_borrow_mut(|held| held.insert(id));
+        debug_assert!(new, "the same file was locked twice (file id: {id:?})");
+    }
+
+    pub(super) fn remove(fd: BorrowedFd<'_>) {
+        let Ok(id) = super::file_id(fd) else { return };
+
+        HELD.with_borrow_mut(|held| held.remove(&id));
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod held {
+    use std::os::fd::BorrowedFd;
+
+    pub(super) fn insert(_: BorrowedFd<'_>) {}
+    pub(super) fn remove(_: BorrowedFd<'_>) {}
+}
+
+#[cfg(not(test))]
+mod imp {
+    use std::os::fd::BorrowedFd;
+
+    use rustix::{
+        fs::{fcntl_lock, FlockOperation},
+        io::Errno,
+    };
+
+    pub(super) fn lock_shared(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        fcntl_lock(fd, FlockOperation::LockShared)
+    }
+
+    pub(super) fn upgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        fcntl_lock(fd, FlockOperation::LockExclusive)
+    }
+
+    pub(super) fn downgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        // Replacing a write lock with a read lock never blocks.
+        fcntl_lock(fd, FlockOperation::LockShared)
+    }
+
+    pub(super) fn try_lock_shared(fd: BorrowedFd<'_>) -> rustix::io::Result<bool> {
+        would_block_to_false(fcntl_lock(fd, FlockOperation::NonBlockingLockShared))
+    }
+
+    pub(super) fn try_upgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<bool> {
+        would_block_to_false(fcntl_lock(fd, FlockOperation::NonBlockingLockExclusive))
+    }
+
+    /// `F_SETLK` reports a conflicting lock with either `EAGAIN` or `EACCES`,
+    /// depending on the os.
+    fn would_block_to_false(res: rustix::io::Result<()>) -> rustix::io::Result<bool> {
+        match res {
+            Ok(()) => Ok(true),
+            Err(Errno::AGAIN | Errno::ACCESS) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(super) fn unlock(fd: BorrowedFd<'_>) {
+        _ = fcntl_lock(fd, FlockOperation::Unlock);
+    }
+}
+
+/// In-process implementation of the locks, which emulates the semantics of `fcntl` locks
+/// (as far as we rely on them), but synchronizes threads rather than processes.
+///
+/// Locks are identified by the file identity (device + inode), so different `File`s referring to
+/// the same file on disk are the same lock (which is also how `fcntl` works).
+#[cfg(test)]
+mod imp {
+    use std::{
+        collections::BTreeMap,
+        os::fd::BorrowedFd,
+        sync::{Condvar, Mutex},
+    };
+
+    use rustix::io::Errno;
+
+    #[derive(Default)]
+    struct State {
+        /// Number of shared locks held.
+        shared: usize,
+        /// `true` if there is an exclusive lock held.
+        /// Exclusive lock can only be acquired by upgrading, so this implies `shared == 0`.
+        exclusive: bool,
+        /// `true` if someone is waiting for an upgrade.
+        upgrading: bool,
+    }
+
+    static LOCKS: Mutex<BTreeMap<(u64, u64), State>> = Mutex::new(BTreeMap::new());
+    static CHANGED: Condvar = Condvar::new();
+
+    pub(super) fn lock_shared(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        let id = super::file_id(fd)?;
+
+        let mut locks = LOCKS.lock().unwrap();
+        while locks.get(&id).is_some_and(|s| s.exclusive) {
+            locks = CHANGED.wait(locks).unwrap();
+        }
+
+        locks.entry(id).or_default().shared += 1;
+
+        Ok(())
+    }
+
+    pub(super) fn upgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        let id = super::file_id(fd)?;
+
+        let mut locks = LOCKS.lock().unwrap();
+        let state = locks.get_mut(&id).unwrap();
+
+        // Someone else holds a shared lock and waits for us to release ours,
+        // if we waited for them to release theirs, we'd deadlock.
+        if state.upgrading {
+            return Err(Errno::DEADLK);
+        }
+        state.upgrading = true;
+
+        // Wait until our shared lock is the only one.
+        while locks[&id].shared > 1 {
+            locks = CHANGED.wait(locks).unwrap();
+        }
+
+        let state = locks.get_mut(&id).unwrap();
+        state.upgrading = false;
+        state.shared = 0;
+        state.exclusive = true;
+
+        Ok(())
+    }
+
+    pub(super) fn downgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<()> {
+        let id = super::file_id(fd)?;
+
+        let mut locks = LOCKS.lock().unwrap();
+        let state = locks.get_mut(&id).unwrap();
+        state.exclusive = false;
+        state.shared = 1;
+
+        CHANGED.notify_all();
+
+        Ok(())
+    }
+
+    pub(super) fn try_lock_shared(fd: BorrowedFd<'_>) -> rustix::io::Result<bool> {
+        let id = super::file_id(fd)?;
+
+        let mut locks = LOCKS.lock().unwrap();
+        let state = locks.entry(id).or_default();
+        if state.exclusive {
+            return Ok(false);
+        }
+
+        state.shared += 1;
+
+        Ok(true)
+    }
+
+    pub(super) fn try_upgrade(fd: BorrowedFd<'_>) -> rustix::io::Result<bool> {
+        let id = super::file_id(fd)?;
+
+        let mut locks = LOCKS.lock().unwrap();
+        let state = locks.get_mut(&id).unwrap();
+
+        // Someone else holds a shared lock.
+        if state.shared > 1 {
+            return Ok(false);
+        }
+
+        state.shared = 0;
+        state.exclusive = true;
+
+        Ok(true)
+    }
+
+    pub(super) fn unlock(fd: BorrowedFd<'_>) {
+        let Ok(id) = super::file_id(fd) else { return };
+
+        let mut locks = LOCKS.lock().unwrap();
+        let state = locks.get_mut(&id).unwrap();
+
+        // An exclusive lock excludes all other locks, so if there is one, it's ours.
+        if state.exclusive {
+            state.exclusive = false;
+        } else {
+            state.shared -= 1;
+        }
+
+        CHANGED.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::unstd::TempDir;
+
+    /// Creates a new (empty) lock file, unique to the test `name`, in a directory which is removed
+    /// when the test ends.
+    fn lock_file(name: &str) -> (TempDir, std::path::PathBuf) {
+        let dir = TempDir::new(&format!("lock-test-{name}"));
+        let path = dir.join("lock");
+        File::create(&path).unwrap();
+        (dir, path)
+    }
+
+    fn open(path: &std::path::Path) -> File {
+        File::options().read(true).write(true).open(path).unwrap()
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let (_dir, path) = lock_file("shared_locks_coexist");
+        let file = open(&path);
+        let _lock = lock_shared(&file).unwrap();
+
+        // (threads are treated as different processes)
+        thread::spawn({
+            let path = path.clone();
+            move || {
+                let file = open(&path);
+                let _lock = lock_shared(&file).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[should_panic = "the same file was locked twice"]
+    fn double_locking_is_caught() {
+        let (dir, path) = lock_file("double_locking_is_caught");
+        let (a, b) = (open(&path), open(&path));
+
+        // The (in-process) lock of `b` is taken before the panic, and never released, so the file
+        // must not be removed, another test's lock file could get its inode and then wait forever
+        std::mem::forget(dir);
+
+        let _a = lock_shared(&a).unwrap();
+        let _b = lock_shared(&b).unwrap();
+    }
+
+    #[test]
+    fn exclusive_blocks_shared() {
+        let (_dir, path) = lock_file("exclusive_blocks_shared");
+        let file = open(&path);
+        let exclusive = lock_shared(&file).unwrap().upgrade().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn({
+            let path = path.clone();
+            move || {
+                let file = open(&path);
+                let _lock = lock_shared(&file).unwrap();
+                tx.send(()).unwrap();
+            }
+        });
+
+        // The other thread can't lock, while we hold the exclusive lock...
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // ...but it can after we release it
+        drop(exclusive);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn try_lock_does_not_block() {
+        let (_dir, path) = lock_file("try_lock_does_not_block");
+        let file = open(&path);
+        let shared = lock_shared(&file).unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let file = open(&path);
+
+                // Both hold a shared lock, so neither can upgrade...
+                let lock = try_lock_shared(&file).unwrap().unwrap();
+                let lock = lock.try_upgrade().unwrap().err().unwrap();
+                drop(lock);
+            })
+            .join()
+            .unwrap();
+
+            // ...but once the other lock is released, the upgrade succeeds
+            let exclusive = shared.try_upgrade().unwrap().ok().unwrap();
+
+            s.spawn(|| {
+                let file = open(&path);
+                assert!(try_lock_shared(&file).unwrap().is_none());
+            })
+            .join()
+            .unwrap();
+
+            drop(exclusive);
+        });
+    }
+
+    #[test]
+    fn lock_times_out() {
+        let (_dir, path) = lock_file("lock_times_out");
+        let file = open(&path);
+        let exclusive = lock_shared(&file).unwrap().upgrade().unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let file = open(&path);
+                let res = lock_shared_timeout(&file, Duration::from_millis(150)).map(drop);
+                assert_eq!(res, Err(rustix::io::Errno::TIMEDOUT));
+            })
+            .join()
+            .unwrap();
+        });
+
+        drop(exclusive);
+    }
+
+    #[test]
+    fn downgraded_lock_blocks_upgrade() {
+        let (_dir, path) = lock_file("downgraded_lock_blocks_upgrade");
+        let file = open(&path);
+        let shared = lock_shared(&file)
+            .unwrap()
+            .upgrade()
+            .unwrap()
+            .downgrade()
+            .unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let file = open(&path);
+
+                // The downgraded lock is shared, so others can get a shared lock...
+                let lock = try_lock_shared(&file).unwrap().unwrap();
+
+                // ...but not upgrade it
+                assert!(lock.try_upgrade().unwrap().is_err());
+            })
+            .join()
+            .unwrap();
+        });
+
+        drop(shared);
+    }
+
+    #[test]
+    fn exactly_one_upgrade_wins() {
+        const THREADS: usize = 8;
+
+        let (_dir, path) = lock_file("exactly_one_upgrade_wins");
+        let barrier = Barrier::new(THREADS);
+
+        let results: Vec<_> = thread::scope(|s| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    s.spawn(|| {
+                        let file = open(&path);
+                        let lock = lock_shared(&file).unwrap();
+
+                        // Make sure everyone holds a shared lock before trying to upgrade
+                        barrier.wait();
+
+                        lock.upgrade().map(drop)
+                    })
+                })
+                .collect();
+
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .filter_map(|r| r.err())
+            .all(|e| e == rustix::io::Errno::DEADLK));
     }
 }
@@ -1,51 +1,147 @@
-mod lock;
-mod proxy;
-mod rustdn;
-mod toolchain;
-mod unstd;
+fn main() {
+    use std::{env, ffi::OsString, process};
 
-// FIXME: add actual error handling
+    // N.B.: `args_os`, so that non-UTF-8 paths (in arg0, or in the arguments of proxies)
+    //       work, instead of panicking
+    let mut args = env::args_os();
 
-fn main() {
-    use std::{env, ffi::OsStr, path::Path};
+    let Some(arg0) = args.next() else {
+        eprintln!("error: rustdn was run without arg0, can't tell which tool to run");
+        process::exit(2);
+    };
+    let args: Vec<OsString> = args.collect();
 
-    setup_tracing();
+    let name = bin_name(&arg0);
 
-    let mut args = env::args();
+    // Tracing is set up before `rustdn` parses its arguments, so `--quiet` (a global flag, i.e.
+    // before the subcommand) has to be found here
+    let quiet_flag = || {
+        args.iter()
+            .map_while(|arg| arg.to_str())
+            .take_while(|arg| arg.starts_with('-'))
+            .any(|arg| matches!(arg, "--quiet" | "-q"))
+    };
+    if name == Some("rustdn") && quiet_flag() {
+        env::set_var(rustdn::QUIET_VAR, "1");
+    }
 
-    let arg0 = args.next();
-    let bin = arg0
-        .as_deref()
-        .map(Path::new)
-        .and_then(|a| a.file_stem())
-        .and_then(OsStr::to_str);
+    setup_tracing();
 
     // `rustdn` is a "chimera binary" -- it changes behavior depending on the name of the
     // binary name (arg0). This is used to enable rustup-style "proxies" -- you can symlink `rustc`
     // to `rustdn` and `rustdn` will choose an appropriate `rustc` version and run it.
-    match bin {
-        Some("rustdn") => rustdn::main(args),
-        Some(tool) => proxy::main(tool, args),
+    let result = match name {
+        Some("rustdn") => rustdn::rustdn_main(args.into_iter()),
+        Some(tool) => rustdn::proxy_main(tool, args.into_iter()).map(|never| match never {}),
+
+        // The file name is not UTF-8 (or there is none), so it's not a tool we know of
+        None => {
+            eprintln!(
+                "error: `{}` is not the name of `rustdn` or a tool it can proxy",
+                arg0.to_string_lossy()
+            );
+            process::exit(2);
+        }
+    };
+
+    if let Err(error) = result {
+        eprintln!("error: {error}");
+        process::exit(error.exit_code());
+    }
+}
+
+/// Returns the name `rustdn` was invoked as, i.e. the file name of `arg0`, without a version
+/// suffix (`rustc-1.78` is `rustc`, since toolchains don't have versioned binaries).
+///
+/// Only the file name matters, so this works the same when `rustdn` is run through a path, e.g.
+/// by `RUSTC_WRAPPER=sccache` (`sccache /path/to/rustc ...`), see [`rustdn::proxy_main`].
+/// The rest of the path doesn't have to be UTF-8.
+fn bin_name(arg0: &std::ffi::OsStr) -> Option<&str> {
+    use std::path::Path;
+
+    let name = Path::new(arg0).file_name()?.to_str()?;
 
-        // Edge-case: no arg0 (or it's last part is not utf-8!)
-        None => panic!("No arg0?"),
+    let is_version = |s: &str| {
+        s.split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    };
+    match name.rsplit_once('-') {
+        Some((tool, version)) if !tool.is_empty() && is_version(version) => Some(tool),
+        _ => Some(name),
     }
 }
 
 fn setup_tracing() {
+    use std::io::IsTerminal as _;
     use tracing::level_filters::LevelFilter;
     use tracing_subscriber::{layer::SubscriberExt as _, EnvFilter, Layer as _, Registry};
 
-    let logger = tracing_subscriber::fmt::layer()
-        .with_writer(move || std::io::stderr())
-        .with_ansi(true);
+    let logger = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
 
+    // N.B.: `RUSTDN_LOG` still applies with `--quiet`
+    let default_level = if rustdn::quiet() {
+        LevelFilter::ERROR
+    } else {
+        LevelFilter::INFO
+    };
     let env_filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
+        .with_default_directive(default_level.into())
         .parse_lossy(std::env::var("RUSTDN_LOG").as_deref().unwrap_or(""));
 
-    let console_logger = logger.compact().with_filter(env_filter).boxed();
+    // `RUSTDN_LOG_FORMAT=json` is meant for tools which consume the logs
+    let console_logger = match std::env::var("RUSTDN_LOG_FORMAT").as_deref() {
+        Ok("json") => logger.json().with_filter(env_filter).boxed(),
+        _ => logger
+            // Logs go to stderr, see `rustdn::use_color` for how this can be changed
+            .with_ansi(rustdn::use_color(std::io::stderr().is_terminal()))
+            .compact()
+            .with_filter(env_filter)
+            .boxed(),
+    };
     let subscriber = Registry::default().with(console_logger);
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bin_names() {
+        fn bin_name(arg0: &str) -> Option<&str> {
+            super::bin_name(arg0.as_ref())
+        }
+
+        assert_eq!(bin_name("rustdn"), Some("rustdn"));
+        assert_eq!(bin_name("rustc"), Some("rustc"));
+        // How wrappers (`RUSTC_WRAPPER`) and cargo (`RUSTC`) usually run the proxies
+        assert_eq!(bin_name("/home/user/.local/bin/rustc"), Some("rustc"));
+        assert_eq!(bin_name("./bin/cargo-clippy"), Some("cargo-clippy"));
+        assert_eq!(bin_name("/usr/bin/rustc"), Some("rustc"));
+        assert_eq!(bin_name("./rustc"), Some("rustc"));
+        assert_eq!(bin_name("../rust-gdb"), Some("rust-gdb"));
+
+        // Versioned names, as some launchers use them
+        assert_eq!(bin_name("rustc-1.78"), Some("rustc"));
+        assert_eq!(bin_name("/opt/rust/bin/cargo-1.78.0"), Some("cargo"));
+        assert_eq!(bin_name("cargo-clippy-1"), Some("cargo-clippy"));
+        assert_eq!(bin_name("rustc-1.78-beta"), Some("rustc-1.78-beta"));
+        assert_eq!(bin_name("rustc-"), Some("rustc-"));
+        assert_eq!(bin_name("-1.78"), Some("-1.78"));
+
+        assert_eq!(bin_name(""), None);
+        assert_eq!(bin_name("/"), None);
+    }
+
+    #[test]
+    fn non_utf8_arg0() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+        fn bin_name(arg0: &[u8]) -> Option<&str> {
+            super::bin_name(OsStr::from_bytes(arg0))
+        }
+
+        assert_eq!(bin_name(b"/home/\xff\xfe/.local/bin/rustc"), Some("rustc"));
+        assert_eq!(bin_name(b"./\x80/cargo-1.78"), Some("cargo"));
+        assert_eq!(bin_name(b"/usr/bin/rust\xffc"), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Per-directory toolchain overrides (`rustdn override set`).
+//!
+//! Overrides are stored centrally in `~/.rustdn/overrides.toml` (see [`rustdn_home`]), like this:
+//! ```toml
+//! [overrides]
+//! "/home/user/projects/cool" = "nightly"
+//! ```
+//!
+//! Unlike `rust-toolchain.toml`, they don't need to be committed to the project.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::rustdn_home,
+    error::{Error, IoResultExt as _},
+    toolchain::{parse_toolchain_arg, ToolchainOverride},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    /// Directory → toolchain.
+    ///
+    /// Toolchains are stored in the same format they are accepted by `rustdn` subcommands.
+    #[serde(default)]
+    overrides: BTreeMap<String, String>,
+}
+
+/// Returns path to the file where overrides are stored.
+fn overrides_file() -> Result<PathBuf, Error> {
+    Ok(rustdn_home()?.join("overrides.toml"))
+}
+
+impl Overrides {
+    /// Loads the overrides (returns no overrides if the file doesn't exist).
+    pub fn load() -> Result<Self, Error> {
+        let path = overrides_file()?;
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_path(path),
+        };
+
+        toml::from_str(&contents).map_err(|e| Error::Config {
+            path,
+            reason: e.to_string(),
+        })
+    }
+
+    /// Saves the overrides, replacing the previous version of the file.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = overrides_file()?;
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).with_path(dir)?;
+
+        // Can't fail, all keys and values are strings
+        let contents = toml::to_string(self).unwrap();
+
+        // Write to a temporary file first, so that a concurrent reader never sees
+        // a partially written file.
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, contents).with_path(&tmp)?;
+        fs::rename(&tmp, &path).with_path(path)
+    }
+
+    /// Returns the override for `dir`, i.e. the override of the longest ancestor of `dir`
+    /// (including `dir` itself), along with the directory it was set for.
+    pub fn find(&self, dir: &Path) -> Result<Option<(PathBuf, ToolchainOverride)>, Error> {
+        let found = dir
+            .ancestors()
+            .filter_map(|d| Some((d, self.overrides.get(d.to_str()?)?)))
+            .next();
+
+        let Some((dir, toolchain)) = found else {
+            return Ok(None);
+        };
+
+        Ok(Some((dir.to_owned(), parse_toolchain_arg(toolchain)?)))
+    }
+
+    /// Sets the override for `dir` (replacing the previous override, if there was one).
+    pub fn set(&mut self, dir: &Path, toolchain: &ToolchainOverride) -> Result<(), Error> {
+        self.overrides.insert(dir_key(dir)?, toolchain.to_string());
+
+        Ok(())
+    }
+
+    /// Removes the override for `dir`, returns `false` if there wasn't one.
+    pub fn unset(&mut self, dir: &Path) -> Result<bool, Error> {
+        Ok(self.overrides.remove(&dir_key(dir)?).is_some())
+    }
+
+    /// Returns all overrides, as `(directory, toolchain)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.overrides.iter().map(|(d, t)| (&**d, &**t))
+    }
+}
+
+/// Returns the key `dir` is stored under.
+fn dir_key(dir: &Path) -> Result<String, Error> {
+    dir.to_str().map(str::to_owned).ok_or_else(|| {
+        Error::Usage(format!(
+            "`{}` is not valid UTF-8, overrides can't be set for it",
+            dir.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolchain::parse_toolchain_spec;
+
+    #[test]
+    fn longest_ancestor_wins() {
+        let t = |spec| parse_toolchain_spec(spec).unwrap();
+        let mut overrides = Overrides::default();
+
+        overrides.set(Path::new("/a"), &t("nightly")).unwrap();
+        overrides.set(Path::new("/a/b/c"), &t("beta")).unwrap();
+
+        let find = |dir| overrides.find(Path::new(dir)).unwrap();
+
+        assert_eq!(find("/a"), Some((PathBuf::from("/a"), t("nightly"))));
+        assert_eq!(find("/a/b"), Some((PathBuf::from("/a"), t("nightly"))));
+        assert_eq!(find("/a/b/c/d"), Some((PathBuf::from("/a/b/c"), t("beta"))));
+        assert_eq!(find("/ab"), None);
+        assert_eq!(find("/"), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut overrides = Overrides::default();
+        overrides
+            .set(Path::new("/a"), &parse_toolchain_spec("1.78").unwrap())
+            .unwrap();
+
+        let s = toml::to_string(&overrides).unwrap();
+        assert_eq!(s, "[overrides]\n\"/a\" = \"stable-1.78\"\n");
+
+        let parsed: Overrides = toml::from_str(&s).unwrap();
+        assert_eq!(parsed.overrides, overrides.overrides);
+    }
+}
@@ -1,14 +1,22 @@
 use std::{
+    convert::Infallible,
     env::{self},
+    ffi::OsString,
+    fmt::Display,
+    fs, io,
     os::unix::process::CommandExt as _,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
 use tracing::{debug, trace};
 
 use crate::{
+    error::{Error, IoResultExt as _},
     toolchain::{
-        find_toolchain_file, get_or_update_toolchain, parse_toolchain_override, ToolchainOverride,
+        component_for_tool, get_or_update_toolchain, parse_toolchain_override, pinned_toolchain,
+        resolve_toolchain, store_path, toolchain_bin, ToolchainSource, PIN_VAR,
+        RUSTUP_TOOLCHAIN_VAR,
     },
     unstd::AnyExt as _,
 };
@@ -18,76 +26,419 @@ use crate::{
 /// This chooses the appropriate toolchain and then runs `bin` from it with `args`[^1].
 ///
 /// Toolchain is chosen like this:
-/// 1. If the first argument in `args` starts with `+<...>`, `<...>` is the chosen toolchain
-///    - Currently `<...>` must match `(stable|beta|nightly)(-.*)?` regex
-/// 2. If the current directory or any of its recursive parents have a file named
-///    `rust-toolchain.toml`, it is used to specify toolchain
-/// 3. Otherwise a minimal stable toolchain is used
+/// 0. If `RUSTDN_TOOLCHAIN` environment variable is set, it is the path to the toolchain to use
+///    (in this case `+<...>` arguments are not treated specially)
+///    - Proxies set this variable for the tools they run, so that nested proxy invocations
+///      (e.g. `rustc` run by `cargo`) use the same toolchain
+///    - This also makes nested invocations cheap: the toolchain is resolved once, by the
+///      outermost proxy, the nested ones don't search for toolchain files, read the
+///      configuration or lock the cache (and `cargo` runs `rustc`/`rustdoc` directly anyway,
+///      see [`cargo_env`])
+/// 1. If an argument in `args` is `+<...>` (and `<...>` is a valid toolchain), `<...>` is the
+///    chosen toolchain. Only the first such argument before `--` is considered
+///    - Currently `<...>` must match `stable(-\d+\.\d+(\.\d+)?)?` or
+///      `(beta|nightly)(-\d{4}-\d{2}-\d{2})?` (with a valid date) regex, i.e. the versions
+///      rust-overlay has (it doesn't have betas by release)
+///    - Or `\d+\.\d+(\.\d+)?`, which is a shorthand for `stable-<...>`
+///    - Or `\d{4}-\d{2}-\d{2}` (a valid date), which is a shorthand for `nightly-<...>`
+///    - Or a path to a `rust-toolchain[.toml]` file, starting with `.` or `/`
+///      (to distinguish it from channels)
+///    - Or a name of a local toolchain, linked with `rustdn toolchain link <name> <path>`
+///    - Channels (and their shorthands) can be followed by `+<component>`s to add components to
+///      the toolchain, e.g. `+nightly+rust-src` (for `cargo +nightly+rust-src build -Z build-std`)
+///    - Channels (with or without components) can also be followed by `@<hash>` to require the
+///      toolchain to be at a specific store path (`/nix/store/<hash>-...`), e.g.
+///      `+stable@<hash>`. The toolchain is built as usual and rejected if its path has a different
+///      hash (a toolchain with the right hash is then never updated)
+/// 2. If `RUSTUP_TOOLCHAIN` environment variable is set, it is parsed the same way as `<...>`
+///    in `+<...>` and used (for compatibility with tools and scripts written for rustup)
+/// 3. If an override was set for the current directory or any of its recursive parents
+///    with `rustdn override set <toolchain>`, it is used (the longest matching ancestor wins)
+/// 4. If the current directory or any of its recursive parents have a file named
+///    `rust-toolchain.toml` (or `rust-toolchain`), it is used to specify toolchain
+///    - Legacy `rust-toolchain` files containing just the channel (e.g. `nightly-2023-01-01`)
+///      are treated the same as the corresponding `+<...>` override
+///    - If `RUSTDN_TOOLCHAIN_FILE` environment variable is set, it is the path to a toolchain file
+///      which is used instead (e.g. a toolchain file shared by all projects on a CI machine).
+///      The file must exist, unlike with toolchain files in directories, a missing file is an
+///      error
+///    - Toolchain files are ignored if `RUSTDN_NO_TOOLCHAIN_FILE=1` is set (or `rustdn` was run
+///      with `--no-toolchain-file`), to use the default toolchain in projects which pin a
+///      different one
+/// 5. Otherwise the default toolchain is used
+///    - Which is a minimal stable toolchain, unless changed with `rustdn default <toolchain>`
+///
+/// Proxies always run the binaries of the toolchain itself (by absolute path), never something
+/// from `PATH`, so they can't run themselves. This also makes `RUSTC_WRAPPER` (e.g. `sccache`)
+/// work: `cargo` runs `sccache <rustc> ...`, where `<rustc>` is either the `rustc` of the
+/// toolchain (see [`cargo_env`]) or, if `RUSTC` was set by the user, possibly the `rustc` proxy,
+/// which is then dispatched by its file name as usual and runs the real `rustc` of the pinned
+/// toolchain.
+///
+/// Environment variables of rustup are removed for the tools, see [`RUSTUP_VARS`].
 ///
 /// FIXME:
-/// - Allow custom toolchains in `+` similarly to what `rustup` allows with `rustup toolchain link`
-///   (I'm not sure where to store information about toolchains though)
-/// - Allow `+x.y.z` (shorthand for stable) and `+yyyy-mm-dd` (shorthand for nightly)
-/// - Allow overriding the default (again, not sure where to store it)
-/// - *Maybe* support outdated `rust-toolchain` file
-/// - *Maybe* support paths in `+<...>`
-///   - Paths to `rust-toolchain[.toml]`?
-///   - Paths to rustc checkouts?
-///   - Paths have to start with `.` or `/` (to distinguish them from local names and channels)?
-/// - Maybe support specifying hashes (where? `+stable@hash...?` a field in `rust-toolchain.toml`?)
+/// - *Maybe* support paths to rustc checkouts in `+<...>`?
+/// - Maybe support specifying hashes in `rust-toolchain.toml`?
 ///
-/// [^1]: if the first argument in `args` starts with `+` it is treated as a toolchain override and
-///       is not passed to the `bin`
-pub(super) fn main(bin: &str, mut args: env::Args) {
+/// [^1]: the argument which is treated as a toolchain override is not passed to the `bin`,
+///       other `+<...>` arguments (which are not valid toolchains) are passed as is
+pub(super) fn main(bin: &str, args: impl Iterator<Item = OsString>) -> Result<Infallible, Error> {
     trace!("proxying {bin}");
 
-    let toolchain_override_or_arg = args.next();
-    let mut toolchain_overridden_from_args = false;
+    if let Some(toolchain) = pinned_toolchain() {
+        debug!("toolchain is pinned to {toolchain:?}");
 
-    let toolchain = 't: {
-        if let Some(t) = parse_toolchain_override(toolchain_override_or_arg.as_deref()).unwrap() {
-            toolchain_overridden_from_args = true;
-            break 't t;
-        }
+        let bin_path = toolchain_bin(&toolchain, bin);
+        check_not_self(bin, &bin_path)?;
 
-        if let Some(t) = find_toolchain_file().unwrap() {
-            break 't t;
-        }
+        // The proxy which pinned the toolchain has already removed `RUSTUP_TOOLCHAIN`,
+        // unless it was used to choose the toolchain
+        let error = Command::new(&bin_path)
+            .also(|c| remove_rustup_env(c, &toolchain, true))
+            .envs(cargo_env(bin, &toolchain))
+            .args(args)
+            .exec();
 
-        ToolchainOverride::None
-    };
+        return Err(exec_error(bin, bin_path, toolchain.display(), error));
+    }
+
+    let mut args = args.collect();
+    let toolchain_override = take_toolchain_override(&mut args);
+
+    let (toolchain, source) = resolve_toolchain(toolchain_override.as_deref())?;
 
     debug!("toolchain override is {toolchain:?}");
 
-    let toolchain = get_or_update_toolchain(toolchain);
+    let toolchain_name = toolchain.to_string();
+    let toolchain = get_or_update_toolchain(toolchain)?;
 
     debug!("toolchain found");
 
-    let bin_path = toolchain
-        // directory with the binaries
-        .join("bin")
-        // the binary itself
-        .join(bin);
+    let bin_path = toolchain_bin(&toolchain, bin);
+    let store_path = store_path(&toolchain);
+    check_not_self(bin, &bin_path)?;
 
     debug!("starting {bin_path:?}");
 
-    // FIXME:
-    // we should probably set some env vars, to make sure toolchain doesn't change out of nowhere.
+    // Pin the toolchain, so that the toolchain doesn't change out of nowhere.
     // e.g. `cargo build` should use `rustc` from the same toolchain and not accidentally change
-    // toolchains when building a project with a different `rust-toolchain.toml`?
+    // toolchains when building a project with a different `rust-toolchain.toml`.
     let error = Command::new(&bin_path)
-        .also(|c| {
-            if !toolchain_overridden_from_args {
-                if let Some(arg) = toolchain_override_or_arg {
-                    c.arg(arg);
-                }
-            }
-        })
+        .also(|c| remove_rustup_env(c, &store_path, source == ToolchainSource::Environment))
+        .envs(cargo_env(bin, &store_path))
+        .env(PIN_VAR, store_path)
         .args(args)
         .stdin(Stdio::inherit())
         .stderr(Stdio::inherit())
         .stdout(Stdio::inherit())
         .exec();
 
-    panic!("couldn't execute {bin_path:?}: {error}");
+    Err(exec_error(bin, bin_path, toolchain_name, error))
+}
+
+/// Environment variables of rustup, which are removed for the tools run by proxies.
+///
+/// If rustup is installed alongside `rustdn`, these could make tools (like `cargo`) use rustup
+/// toolchains instead (e.g. rustup's proxies choose the toolchain with `RUSTUP_TOOLCHAIN`).
+const RUSTUP_VARS: &[&str] = &[
+    "RUSTUP_HOME",
+    RUSTUP_TOOLCHAIN_VAR,
+    // Makes rustup proxies act as a specific tool
+    "RUSTUP_FORCE_ARG0",
+];
+
+/// Removes [`RUSTUP_VARS`] from the environment of `command`, which runs a tool from `toolchain`.
+///
+/// `RUSTUP_TOOLCHAIN` is kept if `keep_toolchain_var` is `true` (i.e. if it was used to choose
+/// the toolchain). `CARGO` is removed too, unless it points into `toolchain`, since it might be
+/// the `cargo` of some other toolchain (e.g. a rustup proxy).
+fn remove_rustup_env(command: &mut Command, toolchain: &Path, keep_toolchain_var: bool) {
+    for var in rustup_vars_to_remove(|var| env::var_os(var), toolchain, keep_toolchain_var) {
+        command.env_remove(var);
+    }
+}
+
+/// Returns the variables [`remove_rustup_env`] should remove, given the environment (`get_var`).
+fn rustup_vars_to_remove(
+    get_var: impl Fn(&str) -> Option<OsString>,
+    toolchain: &Path,
+    keep_toolchain_var: bool,
+) -> Vec<&'static str> {
+    let mut vars: Vec<_> = RUSTUP_VARS
+        .iter()
+        .copied()
+        .filter(|&var| !(keep_toolchain_var && var == RUSTUP_TOOLCHAIN_VAR))
+        .filter(|&var| get_var(var).is_some())
+        .collect();
+
+    if get_var("CARGO").is_some_and(|cargo| !Path::new(&cargo).starts_with(toolchain)) {
+        vars.push("CARGO");
+    }
+
+    vars
+}
+
+/// Returns an error if `bin_path` is `rustdn` itself.
+///
+/// This happens with broken setups (e.g. a linked toolchain with proxies in its `bin`), running
+/// `bin_path` would then proxy to itself over and over again.
+fn check_not_self(bin: &str, bin_path: &Path) -> Result<(), Error> {
+    let is_self = env::current_exe()
+        .and_then(fs::canonicalize)
+        .is_ok_and(|exe| fs::canonicalize(bin_path).is_ok_and(|path| path == exe));
+
+    if is_self {
+        return Err(Error::ProxyLoop {
+            tool: bin.to_owned(),
+            path: bin_path.to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Tools `rustdn self install` creates proxies for (more can be added with `extra_proxies` in the
+/// config).
+pub const PROXIES: &[&str] = &[
+    "rustc",
+    "rustdoc",
+    "cargo",
+    "rustfmt",
+    "cargo-fmt",
+    "cargo-clippy",
+    "clippy-driver",
+    "rust-analyzer",
+    "cargo-miri",
+    "rust-gdb",
+    "rust-gdbgui",
+    "rust-lldb",
+];
+
+/// Returns the proxies in `dir`, i.e. symlinks (other than `rustdn` itself) which resolve to the
+/// `rustdn` binary at `rustdn` (which must be canonical).
+///
+/// Anything else (real binaries, links to other binaries, broken links) is never a proxy.
+pub fn find_proxies(dir: &Path, rustdn: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut proxies = Vec::new();
+
+    for entry in fs::read_dir(dir).with_path(dir)? {
+        let path = entry.with_path(dir)?.path();
+
+        let is_proxy = path.file_name() != Some("rustdn".as_ref())
+            && path.symlink_metadata().is_ok_and(|m| m.is_symlink())
+            && fs::canonicalize(&path).is_ok_and(|target| target == rustdn);
+        if is_proxy {
+            proxies.push(path);
+        }
+    }
+
+    proxies.sort();
+
+    Ok(proxies)
+}
+
+/// Environment variables `cargo` uses to find the tools it runs, and the tools.
+const CARGO_TOOL_VARS: &[(&str, &str)] = &[("RUSTC", "rustc"), ("RUSTDOC", "rustdoc")];
+
+/// Returns the environment variables which should be set when running `bin` from `toolchain`.
+///
+/// For `cargo` these are [`cargo_tool_env`].
+fn cargo_env(bin: &str, toolchain: &Path) -> Vec<(&'static str, PathBuf)> {
+    if bin != "cargo" {
+        return Vec::new();
+    }
+
+    cargo_tool_env(toolchain)
+}
+
+/// Returns [`CARGO_TOOL_VARS`] pointing directly to the binaries of `toolchain`.
+///
+/// These make cargo (and build scripts) use the same compiler even if the proxies are not in
+/// `PATH`. Variables which the user set themselves are left alone, so are tools which the
+/// toolchain doesn't have (so that running them through the proxy reports a proper error).
+pub(crate) fn cargo_tool_env(toolchain: &Path) -> Vec<(&'static str, PathBuf)> {
+    CARGO_TOOL_VARS
+        .iter()
+        .filter(|(var, _)| env::var_os(var).is_none())
+        .map(|&(var, tool)| (var, toolchain_bin(toolchain, tool)))
+        .filter(|(_, path)| path.exists())
+        .collect()
+}
+
+/// Removes the first `+<toolchain>` argument from `args` and returns it.
+///
+/// Arguments after `--` are never considered (they are usually passed to some other program),
+/// neither are arguments which start with `+`, but are not valid toolchains
+/// (they might mean something to the tool).
+fn take_toolchain_override(args: &mut Vec<OsString>) -> Option<String> {
+    let i = args.iter().take_while(|arg| *arg != "--").position(|arg| {
+        // Non-UTF-8 arguments are never toolchains
+        arg.to_str()
+            .is_some_and(|arg| matches!(parse_toolchain_override(Some(arg)), Ok(Some(_))))
+    })?;
+
+    args.remove(i).into_string().ok()
+}
+
+/// Creates an error for a failed `exec` of `bin_path`.
+///
+/// If `bin_path` doesn't exist, the toolchain doesn't have the tool (e.g. because it's not part
+/// of the minimal profile), so the error says so instead of reporting a generic IO error.
+fn exec_error(bin: &str, bin_path: PathBuf, toolchain: impl Display, error: io::Error) -> Error {
+    if error.kind() == io::ErrorKind::NotFound && !bin_path.exists() {
+        return Error::ToolNotFound {
+            tool: bin.to_owned(),
+            toolchain: toolchain.to_string(),
+            component: component_for_tool(bin),
+        };
+    }
+
+    Error::Spawn {
+        program: bin_path,
+        source: error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unstd::TempDir;
+
+    fn take(args: &[&str]) -> (Option<String>, Vec<String>) {
+        let mut args = args.iter().map(OsString::from).collect();
+        let toolchain = take_toolchain_override(&mut args);
+        let args = args.into_iter().map(|a| a.into_string().unwrap()).collect();
+
+        (toolchain, args)
+    }
+
+    #[test]
+    fn override_position() {
+        assert_eq!(
+            take(&["+nightly", "build"]),
+            (Some("+nightly".to_owned()), vec!["build".to_owned()])
+        );
+        assert_eq!(
+            take(&["build", "+nightly", "--release"]),
+            (
+                Some("+nightly".to_owned()),
+                vec!["build".to_owned(), "--release".to_owned()]
+            )
+        );
+        assert_eq!(
+            take(&["build", "--release"]),
+            (None, vec!["build".to_owned(), "--release".to_owned()])
+        );
+    }
+
+    #[test]
+    fn cargo_tools() {
+        let toolchain = TempDir::new("cargo-env");
+        fs::create_dir_all(toolchain.join("bin")).unwrap();
+        fs::write(toolchain.join("bin/rustc"), "").unwrap();
+
+        assert_eq!(cargo_env("rustc", &toolchain), []);
+
+        // `rustdoc` is not in the toolchain
+        let expected: &[_] = match env::var_os("RUSTC") {
+            Some(_) => &[],
+            None => &[("RUSTC", toolchain.join("bin/rustc"))],
+        };
+        assert_eq!(cargo_env("cargo", &toolchain), expected);
+    }
+
+    #[test]
+    fn self_invocation() {
+        let toolchain = TempDir::new("self");
+        fs::create_dir_all(toolchain.join("bin")).unwrap();
+        fs::write(toolchain.join("bin/cargo"), "").unwrap();
+        // The toolchain's `rustc` is the proxy (i.e. this binary)
+        std::os::unix::fs::symlink(env::current_exe().unwrap(), toolchain.join("bin/rustc"))
+            .unwrap();
+
+        assert!(matches!(
+            check_not_self("rustc", &toolchain_bin(&toolchain, "rustc")),
+            Err(Error::ProxyLoop { .. })
+        ));
+        assert!(check_not_self("cargo", &toolchain_bin(&toolchain, "cargo")).is_ok());
+        assert!(check_not_self("rustdoc", &toolchain_bin(&toolchain, "rustdoc")).is_ok());
+    }
+
+    #[test]
+    fn proxies() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("find-proxies");
+        let bin = dir.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+
+        let rustdn = dir.join("rustdn");
+        fs::write(&rustdn, "").unwrap();
+        fs::write(dir.join("other"), "").unwrap();
+
+        symlink(&rustdn, bin.join("rustc")).unwrap();
+        // Through another link
+        symlink(bin.join("rustc"), bin.join("cargo")).unwrap();
+        // Not proxies
+        symlink(&rustdn, bin.join("rustdn")).unwrap();
+        symlink(dir.join("other"), bin.join("rustfmt")).unwrap();
+        symlink(dir.join("missing"), bin.join("clippy-driver")).unwrap();
+        fs::write(bin.join("rustdoc"), "").unwrap();
+
+        let rustdn = fs::canonicalize(&rustdn).unwrap();
+        assert_eq!(
+            find_proxies(&bin, &rustdn).unwrap(),
+            [bin.join("cargo"), bin.join("rustc")]
+        );
+    }
+
+    #[test]
+    fn rustup_env() {
+        let toolchain = Path::new("/nix/store/abc-rust");
+        let vars = |env: &[(&str, &str)], keep| {
+            let get = |var: &str| {
+                env.iter()
+                    .find(|(v, _)| *v == var)
+                    .map(|(_, value)| value.into())
+            };
+            rustup_vars_to_remove(get, toolchain, keep)
+        };
+
+        let env = [
+            ("RUSTUP_HOME", "/home/user/.rustup"),
+            ("RUSTUP_TOOLCHAIN", "nightly"),
+            ("CARGO", "/home/user/.cargo/bin/cargo"),
+        ];
+        assert_eq!(
+            vars(&env, false),
+            ["RUSTUP_HOME", "RUSTUP_TOOLCHAIN", "CARGO"]
+        );
+        assert_eq!(vars(&env, true), ["RUSTUP_HOME", "CARGO"]);
+
+        // `cargo` of the toolchain itself (e.g. when it runs `cargo-clippy`)
+        assert_eq!(
+            vars(&[("CARGO", "/nix/store/abc-rust/bin/cargo")], false),
+            Vec::<&str>::new()
+        );
+        assert_eq!(vars(&[], false), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn only_first_valid_override() {
+        assert_eq!(
+            take(&["+not-a-toolchain", "+beta", "+nightly"]),
+            (
+                Some("+beta".to_owned()),
+                vec!["+not-a-toolchain".to_owned(), "+nightly".to_owned()]
+            )
+        );
+        assert_eq!(
+            take(&["run", "--", "+nightly"]),
+            (
+                None,
+                vec!["run".to_owned(), "--".to_owned(), "+nightly".to_owned()]
+            )
+        );
+    }
 }
@@ -1,82 +1,2018 @@
-use std::{env, fs};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal as _, Write as _},
+    iter,
+    os::unix::process::CommandExt as _,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, SystemTime},
+};
 
-use crate::toolchain::ToolchainOverride;
+use crate::{
+    completions,
+    config::{self, Config, NixCommand, QUIET_VAR},
+    error::{Error, IoResultExt as _},
+    overrides::Overrides,
+    proxy,
+    toolchain::{
+        cached_toolchains, check_toolchain, component_for_tool, current_dir, default_toolchain,
+        find_cached_toolchain, find_nixpkgs, get_or_update_toolchain, has_rustc,
+        is_valid_extra_name, last_used, link_toolchain, linked_toolchains, nix_can_evaluate,
+        offline, parse_toolchain_arg, remove_toolchain, resolve_toolchain, resolved_key,
+        set_default_toolchain, store_path, toolchain_bin, toolchain_store_path, toolchain_version,
+        toolchains_dir, try_remove_cached, update_toolchain, Extras, ToolchainOverride,
+        ToolchainSource, UpdateCheck, NO_TOOLCHAIN_FILE_VAR, OFFLINE_VAR, PIN_VAR,
+        RUSTUP_TOOLCHAIN_VAR, TOOLCHAIN_FILE_VAR,
+    },
+    toolchain_file,
+    unstd::{exit_like, json_string, shell_quote, AnyExt as _},
+};
+
+/// Prints a message about what a command did (like "removed `nightly`"), unless `--quiet` is
+/// used (see [`config::quiet`]).
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !config::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
 
 /// `rustdn` command entry point.
 ///
 /// This provides meta (?) commands to manage toolchains, like `rustdn shell 1.87`.
 ///
-/// **Completely unimplemented :thumbs_up:**
-///
-/// FIXME: (sub) commands that I'd like to have (most are shamelessly stollen from `rustup`)
-/// - `help`/`--help`/`-h` - self explanatory
-/// - `version`/`--version` - self explanatory
-/// - `show` - show a toolchain that would be chosen by `rustdn`
-/// - `which` - display what binary would be run
-/// - `run` - run a command in the toolchain environment
-/// - `shell` - creates a shell with an appropriate toolchain.
-///   - By default it should probably disable proxies, i.e.
-///     ```shell
-///     ; rustdn shell stable
-///     ; rustc +nightly
-///     error: couldn't read +nigthly: No such file or directory (os error 2)
-///
-///     error: aborting due to 1 previous error
-///     ```
-///   - But there should be a flag to keep proxies
-/// - `doc` - Open the documentation for the current toolchain
-/// - `list` - list "installed" toolchains
-///   - Is this even feasible?
-/// - A command to remove a toolchain from the nix cache?
-/// - `check` - check for updates
-///
-pub(super) fn main(mut args: env::Args) {
-    if args.next().as_deref() == Some("toolchain") {
-        toolchain(args);
-    } else {
-        unimplemented!()
+/// Implemented (sub) commands:
+/// - `toolchain list [--installed-only] [--json | --porcelain]` - list cached toolchains
+/// - `toolchain add <toolchain>...` - install toolchains (in parallel)
+/// - `toolchain remove <toolchain>` - remove a toolchain from the cache
+/// - `toolchain link <name> <path>` - make a local toolchain available as `+<name>`
+/// - `toolchain export` - print the keys of the installed toolchains (one per line)
+/// - `toolchain import [<file>]` - install the toolchains exported with `toolchain export`
+/// - `show [+toolchain] [--json]` - show a toolchain that would be chosen by `rustdn` (with
+///   `--json` as a JSON object, for other tools)
+/// - `show active-toolchain [--plain] [+toolchain]` - print just the chosen toolchain
+///   (with `--plain` without any decoration, for scripts)
+/// - `show (home | toolchains-dir | store-path [+toolchain])` - print the rustdn home, the
+///   directory with cached toolchains or the store path of a toolchain (without building it)
+/// - `version [+toolchain]` - print the version of `rustdn` and of the toolchain that would be
+///   chosen
+/// - `which [+toolchain] (<tool> | --all)` - display what binary would be run (or all of them)
+/// - `doc [+toolchain] [--std | <crate>]` - open the documentation of a toolchain
+/// - `env [+toolchain] [--json]` - print the environment for using a toolchain without proxies
+/// - `default [<toolchain> | --reset]` - show or change the default toolchain
+/// - `override set <toolchain>` - use `<toolchain>` in the current directory (and its children)
+/// - `override unset` - remove the override for the current directory
+/// - `override list` - list all directory overrides
+/// - `run <toolchain> <command> [args...]` - run a command in the toolchain environment
+/// - `shell [--keep-proxies] <toolchain>` - creates a shell with an appropriate toolchain
+/// - `gc [--dry-run] [--older-than <days>]` - remove toolchains which weren't used for a while
+/// - `check` - check for updates of cached toolchains (without installing them)
+/// - `update [+toolchain]` - update version-less toolchains (like `nightly`) now
+/// - `doctor` - diagnose problems with the setup (nix, `<nixpkgs>`, proxies, ...)
+/// - `completions <shell>` - print a completion script for `bash`, `zsh` or `fish`
+/// - `self install [--bin-dir <dir>] [--force]` - create the proxies
+/// - `self uninstall [--purge] [--yes] [<dir>...]` - remove the proxies (and with `--purge` all
+///   the state of `rustdn`)
+///
+/// `run` and `shell` accept `--component <components>` and `--target <targets>` (before the
+/// toolchain), to add components (like `rust-src`) or targets (like `wasm32-unknown-unknown`)
+/// to the toolchain. `--profile <profile>` (`minimal`, `default` or `complete`) selects the set
+/// of components the toolchain starts with.
+///
+/// Global flags (before the subcommand):
+/// - `--offline` - never build/download toolchains, only use the cached ones
+///   (same as setting `RUSTDN_OFFLINE=1`, which also works for proxies)
+/// - `--no-toolchain-file` - ignore `rust-toolchain.toml` (and `rust-toolchain`) files
+///   (same as setting `RUSTDN_NO_TOOLCHAIN_FILE=1`, which also works for proxies)
+/// - `--quiet`/`-q` - only print errors and what was asked for, i.e. no logs, throbbers or
+///   messages like "removed `nightly`" (same as setting `RUSTDN_QUIET=1`)
+/// - `--version`/`-V` - print the version of `rustdn`
+/// - `--help`/`-h` - print help
+///
+/// `help [command]`, `--help`/`-h` and `<command> --help` print help (see [`COMMANDS`]).
+pub(super) fn main(args: impl Iterator<Item = OsString>) -> Result<(), Error> {
+    let mut args = args
+        .map(|arg| {
+            arg.into_string().map_err(|arg| {
+                Error::Usage(format!(
+                    "argument `{}` is not valid UTF-8",
+                    arg.to_string_lossy()
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+
+    let mut command = args.next();
+
+    // Global flags
+    while let Some(flag) = command
+        .as_deref()
+        .filter(|c| c.starts_with('-') && !matches!(*c, "--help" | "-h"))
+    {
+        match flag {
+            // Set the variable (instead of just remembering the flag), so that proxies run by
+            // `rustdn run`/`rustdn shell` are offline too.
+            "--offline" => env::set_var(OFFLINE_VAR, "1"),
+            // Same as with `--offline`, this applies to proxies run by `rustdn` too
+            "--no-toolchain-file" => env::set_var(NO_TOOLCHAIN_FILE_VAR, "1"),
+            "--quiet" | "-q" => env::set_var(QUIET_VAR, "1"),
+            "--version" | "-V" => {
+                println!("rustdn {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ => return Err(Error::Usage(format!("unknown flag `{flag}`"))),
+        }
+
+        command = args.next();
+    }
+
+    let mut args = args.peekable();
+
+    let Some(command) = command else {
+        return Err(Error::Usage(format!(
+            "expected a command\n\n{}",
+            help_text()
+        )));
+    };
+
+    if let "help" | "--help" | "-h" = &*command {
+        return help(args.next().as_deref());
+    }
+
+    if let Some("--help" | "-h") = args.peek().map(String::as_str) {
+        return help(Some(&command));
+    }
+
+    match &*command {
+        "toolchain" => toolchain(args),
+        "show" => show(args),
+        "version" => version(args),
+        "which" => which(args),
+        "doc" => doc(args),
+        "env" => env_(args),
+        "default" => default(args),
+        "override" => override_(args),
+        "run" => run(args),
+        "shell" => shell(args),
+        "check" => check(args),
+        "update" => update(args),
+        "doctor" => doctor(args),
+        "gc" => gc(args),
+        "completions" => completions(args),
+        "self" => self_(args),
+        _ => Err(Error::Usage(format!(
+            "unknown command `{command}`\n\n{}",
+            help_text()
+        ))),
     }
 }
 
-fn toolchain(mut args: env::Args) {
-    if args.next().as_deref() == Some("list") {
-        let toolchains_dir = dirs::home_dir().unwrap().join(".rustdn/toolchains");
+/// Arguments of a subcommand.
+type Args = iter::Peekable<std::vec::IntoIter<String>>;
 
-        let dir = fs::read_dir(&toolchains_dir).unwrap();
-        let mut toolchains = Vec::new();
+/// `(name, usage, description)` of all the subcommands.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "toolchain",
+        "toolchain (list [--installed-only] [--json | --porcelain] | add <toolchain>... | remove <toolchain> \
+         | link <name> <path> | export | import [<file>])",
+        "List, install, remove, link, export or import toolchains",
+    ),
+    (
+        "show",
+        "show [active-toolchain [--plain] | home | toolchains-dir | store-path] [+toolchain] \
+         [--json]",
+        "Show the toolchain that would be chosen, and why (or where rustdn keeps things)",
+    ),
+    (
+        "version",
+        "version [+toolchain]",
+        "Print the version of rustdn and of the toolchain that would be chosen",
+    ),
+    (
+        "which",
+        "which [+toolchain] (<tool> | --all)",
+        "Print the path to the binary that would be run (or to all binaries)",
+    ),
+    (
+        "doc",
+        "doc [+toolchain] [--std | <crate>]",
+        "Open the documentation of the toolchain in a browser",
+    ),
+    (
+        "env",
+        "env [+toolchain] [--json]",
+        "Print shell exports for the toolchain, e.g. `eval \"$(rustdn env)\"`",
+    ),
+    (
+        "default",
+        "default [<toolchain> | --reset]",
+        "Show or change the default toolchain",
+    ),
+    (
+        "override",
+        "override (set <toolchain> | unset | list)",
+        "Manage per-directory toolchain overrides",
+    ),
+    (
+        "run",
+        "run [--component <components>] [--target <targets>] [--profile <profile>] <toolchain> <command> [args...]",
+        "Run a command with the toolchain",
+    ),
+    (
+        "shell",
+        "shell [--keep-proxies] [--component <components>] [--target <targets>] [--profile <profile>] <toolchain>",
+        "Start a shell with the toolchain (with proxies disabled by default)",
+    ),
+    (
+        "gc",
+        "gc [--dry-run] [--older-than <days>]",
+        "Remove cached toolchains which weren't used for a while (30 days by default)",
+    ),
+    (
+        "check",
+        "check",
+        "Check for updates of cached toolchains, without installing them",
+    ),
+    (
+        "update",
+        "update [+toolchain]",
+        "Update version-less toolchains (like `nightly`) to the latest version",
+    ),
+    (
+        "doctor",
+        "doctor",
+        "Check that nix, the proxies and everything else rustdn needs are set up correctly",
+    ),
+    (
+        "completions",
+        "completions (bash | zsh | fish)",
+        "Print a shell completion script, e.g. `source <(rustdn completions zsh)`",
+    ),
+    (
+        "self",
+        "self (install [--bin-dir <dir>] [--force] | uninstall [--purge] [--yes] [<dir>...])",
+        "Create or remove the proxies (`rustc`, `cargo`, ... symlinks to rustdn)",
+    ),
+    ("help", "help [command]", "Print help"),
+];
 
-        for res in dir {
-            match res {
-                Ok(entry) => {
-                    let name = entry.file_name();
-                    if let Some(toolchain) = ToolchainOverride::from_key(name) {
-                        toolchains.push(toolchain);
-                    }
-                    // FIXME: log if there is a non-toolchain file?
-                }
-                Err(err) => eprintln!(
-                    "error while reading `{}` directory: {err}",
-                    toolchains_dir.display()
+/// Returns the general help text, listing all the commands.
+fn help_text() -> String {
+    let mut text =
+        "Usage: rustdn [--offline] [--quiet] <command> [args...]\n\nCommands:\n".to_owned();
+    for (name, _, description) in COMMANDS {
+        text.push_str(&format!("  {name:<10} {description}\n"));
+    }
+    text.push_str(
+        "\nFlags:\n  \
+         --offline  Never build/download toolchains, only use the cached ones\n  \
+         --quiet    Only print errors and the output of the command\n  \
+         --no-toolchain-file\n             Ignore toolchain files (`rust-toolchain.toml`)\n  \
+         --version  Print the version of rustdn\n\n\
+         Run `rustdn help <command>` for more information on a command.",
+    );
+
+    text
+}
+
+/// Returns the usage of `command`, e.g. `Usage: rustdn show [+toolchain]`.
+fn command_usage(command: &str) -> String {
+    // All callers pass known commands
+    let (_, usage, _) = COMMANDS.iter().find(|(name, ..)| *name == command).unwrap();
+
+    format!("Usage: rustdn {usage}")
+}
+
+/// `rustdn gc [--dry-run] [--older-than <days>]`.
+///
+/// Removes cached toolchains which weren't used in the last `<days>` days (see [`last_used`]),
+/// as well as broken ones (e.g. garbage collected by nix). Toolchains which are currently being
+/// used (i.e. locked) are skipped.
+///
+/// N.B.: version-less channels (like `nightly`) are updated in place, so there is only ever
+///       one build of each of them in the cache (older builds are only kept in the nix store,
+///       until the next `nix-store --gc`).
+fn gc(mut args: Args) -> Result<(), Error> {
+    let mut dry_run = false;
+    let mut days = 30;
+
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--dry-run" => dry_run = true,
+            "--older-than" => {
+                days = args
+                    .next()
+                    .and_then(|d| d.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        Error::Usage("`--older-than` requires a number of days".to_owned())
+                    })?;
+            }
+            _ => {
+                return Err(Error::Usage(format!(
+                    "unexpected argument `{arg}`\n\n{}",
+                    command_usage("gc")
+                )))
+            }
+        }
+    }
+
+    // Saturates, since "older than forever" is a perfectly good way to say "keep everything"
+    let max_age = Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+
+    for (toolchain, dir) in cached_toolchains()? {
+        // N.B.: `exists` follows symlinks, so this is `false` for dangling links
+        let reason = if !dir.join("toolchain").exists() {
+            "missing".to_owned()
+        } else {
+            // If in doubt (e.g. the time is in the future), keep the toolchain
+            let unused_for = last_used(&dir)
+                .and_then(|t| t.elapsed().ok())
+                .unwrap_or(Duration::ZERO);
+            if unused_for < max_age {
+                continue;
+            }
+
+            format!("unused for {} days", unused_for.as_secs() / (24 * 60 * 60))
+        };
+
+        if dry_run {
+            println!("would remove `{toolchain}` ({reason})");
+        } else if try_remove_cached(&dir)? {
+            status!("removed `{toolchain}` ({reason})");
+        } else {
+            status!("skipped `{toolchain}` (in use)");
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn check`.
+///
+/// Only toolchains which can change (like `nightly`, as opposed to `nightly-2024-05-10`) are
+/// checked, see [`check_toolchain`].
+fn check(mut args: Args) -> Result<(), Error> {
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!(
+            "unexpected argument `{arg}`\n\n{}",
+            command_usage("check")
+        )));
+    }
+
+    if offline() {
+        return Err(Error::Usage(
+            "can't check for updates in offline mode".to_owned(),
+        ));
+    }
+
+    for (toolchain, _) in cached_toolchains()? {
+        match check_toolchain(&toolchain) {
+            Ok(Some(UpdateCheck::Pinned)) => println!("{toolchain}: pinned"),
+            Ok(Some(UpdateCheck::UpToDate)) => println!("{toolchain}: up to date"),
+            Ok(Some(UpdateCheck::Available { current, latest })) => println!(
+                "{toolchain}: update available ({} → {})",
+                short_hash(&current),
+                short_hash(&latest)
+            ),
+            // Removed (or garbage collected) in the meantime
+            Ok(None) => {}
+            Err(e) => eprintln!("error: couldn't check `{toolchain}` for updates: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn update [+toolchain]`.
+///
+/// Rebuilds the toolchain (or, without arguments, every cached toolchain) which can change, like
+/// `nightly` or the default toolchain, even if its cache would still be used, and prints the
+/// versions before and after the update. Pinned toolchains (with a version, a date or a hash,
+/// toolchain files and linked toolchains) are never touched, see [`update_toolchain`].
+///
+/// Without arguments all the toolchains are updated, even if some of them fail, but the command
+/// still fails then.
+fn update(mut args: Args) -> Result<(), Error> {
+    let toolchain = args
+        .next()
+        .map(|arg| parse_toolchain_arg(&arg))
+        .transpose()?;
+
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!(
+            "unexpected argument `{arg}`\n\n{}",
+            command_usage("update")
+        )));
+    }
+
+    if offline() {
+        return Err(Error::Usage(
+            "can't update toolchains in offline mode".to_owned(),
+        ));
+    }
+
+    let version =
+        |path: &Path| toolchain_version(path).unwrap_or_else(|| "unknown version".to_owned());
+    let update_one = |toolchain: &ToolchainOverride| -> Result<(), Error> {
+        let old = find_cached_toolchain(toolchain)?.map(|link| (store_path(&link), version(&link)));
+
+        let Some(link) = update_toolchain(toolchain)? else {
+            println!("{toolchain}: pinned");
+            return Ok(());
+        };
+        let new = version(&link);
+
+        match old {
+            Some((path, _)) if path == store_path(&link) => {
+                println!("{toolchain}: up to date ({new})")
+            }
+            Some((_, old)) => println!("{toolchain}: updated ({old} → {new})"),
+            None => println!("{toolchain}: installed ({new})"),
+        }
+
+        Ok(())
+    };
+
+    if let Some(toolchain) = toolchain {
+        return update_one(&toolchain);
+    }
+
+    let toolchains = cached_toolchains()?;
+    let total = toolchains.len();
+    let mut failed = 0;
+    for (toolchain, _) in toolchains {
+        if let Err(e) = update_one(&toolchain) {
+            eprintln!("error: couldn't update `{toolchain}`: {e}");
+            failed += 1;
+        }
+    }
+
+    if failed != 0 {
+        return Err(Error::UpdateFailed { failed, total });
+    }
+
+    Ok(())
+}
+
+/// The result of a check of `rustdn doctor`.
+enum Diagnosis {
+    Ok(String),
+    /// Something which might be a problem, with a hint on how to fix it.
+    Warning(String, String),
+    /// Something which stops `rustdn` from working, with a hint on how to fix it.
+    Error(String, String),
+}
+
+/// `rustdn doctor`.
+///
+/// Checks that nix works, that `<nixpkgs>` can be found (or nixpkgs is pinned), that the state
+/// directory is writable and that the proxies in `PATH` are `rustdn`'s (and not rustup's),
+/// printing the result of each check, with hints on how to fix the problems.
+fn doctor(mut args: Args) -> Result<(), Error> {
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!("unexpected argument `{arg}`")));
+    }
+
+    let mut diagnoses = Vec::new();
+
+    let default_config;
+    let config = match config::get() {
+        Ok(config) => config,
+        Err(e) => {
+            diagnoses.push(Diagnosis::Error(
+                e.to_string(),
+                "fix the configuration file, the defaults are used for the other checks".to_owned(),
+            ));
+
+            default_config = Config::default();
+            &default_config
+        }
+    };
+
+    diagnoses.push(diagnose_nix(config));
+    if let Ok(args) = config.extra_nix_args() {
+        diagnoses.push(diagnose_nix_eval(config, &args));
+        diagnoses.push(diagnose_nixpkgs(config));
+    }
+    diagnoses.push(diagnose_home());
+    diagnoses.extend(diagnose_proxies(config));
+
+    let mut failed = 0;
+    for diagnosis in diagnoses {
+        match diagnosis {
+            Diagnosis::Ok(message) => println!("ok: {message}"),
+            Diagnosis::Warning(message, hint) => println!("warning: {message}\n  hint: {hint}"),
+            Diagnosis::Error(message, hint) => {
+                println!("error: {message}\n  hint: {hint}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed != 0 {
+        return Err(Error::ChecksFailed { failed });
+    }
+
+    Ok(())
+}
+
+/// Checks that the nix CLI `rustdn` uses is installed.
+fn diagnose_nix(config: &Config) -> Diagnosis {
+    let program = match config.nix_command() {
+        NixCommand::Legacy => "nix-build",
+        NixCommand::New => "nix",
+    };
+
+    match config::find_in_path(program) {
+        Some(path) => Diagnosis::Ok(format!("`{program}` is installed (`{}`)", path.display())),
+        None => Diagnosis::Error(
+            format!("`{program}` is not in `PATH`"),
+            "install nix, see <https://nixos.org/download>".to_owned(),
+        ),
+    }
+}
+
+/// Checks that nix can evaluate expressions, with the extra nix arguments (`extra_args`).
+fn diagnose_nix_eval(config: &Config, extra_args: &[String]) -> Diagnosis {
+    if nix_can_evaluate(config) {
+        return Diagnosis::Ok("nix can evaluate expressions".to_owned());
+    }
+
+    let hint = match extra_args {
+        [] => "check that `nix-instantiate --eval --expr '1 + 1'` works".to_owned(),
+        _ => format!(
+            "check that `nix-instantiate --eval --expr '1 + 1'` works \
+             (with the extra nix arguments: `{}`)",
+            extra_args.join(" ")
+        ),
+    };
+
+    Diagnosis::Error("evaluating `1 + 1` with nix failed".to_owned(), hint)
+}
+
+/// Checks that there is a `<nixpkgs>`, unless nixpkgs is pinned in the config.
+fn diagnose_nixpkgs(config: &Config) -> Diagnosis {
+    if config.nixpkgs_url.is_some() || config.nixpkgs_rev.is_some() {
+        return Diagnosis::Ok("nixpkgs is pinned in the config".to_owned());
+    }
+
+    match find_nixpkgs(config) {
+        Ok(Some(path)) => Diagnosis::Ok(format!("`<nixpkgs>` is `{path}`")),
+        // nix itself doesn't work, which is already reported
+        Err(_) => Diagnosis::Warning(
+            "couldn't check if there is a `<nixpkgs>`".to_owned(),
+            "fix the nix installation first".to_owned(),
+        ),
+        Ok(None) => Diagnosis::Warning(
+            "`<nixpkgs>` is not in the nix search path, the fallback nixpkgs is used".to_owned(),
+            "add a nixpkgs channel (`nix-channel --add \
+             https://nixos.org/channels/nixpkgs-unstable nixpkgs`), set `NIX_PATH`, \
+             or pin nixpkgs with `nixpkgs_rev` in the config"
+                .to_owned(),
+        ),
+    }
+}
+
+/// Checks that the directory with the state of `rustdn` is writable.
+fn diagnose_home() -> Diagnosis {
+    let home = match config::rustdn_home() {
+        Ok(home) => home,
+        Err(e) => return Diagnosis::Error(e.to_string(), "set `RUSTDN_HOME`".to_owned()),
+    };
+
+    let probe = home.join(format!(".doctor-{}", std::process::id()));
+    let writable = fs::create_dir_all(&home)
+        .and_then(|()| fs::write(&probe, ""))
+        .and_then(|()| fs::remove_file(&probe));
+
+    match writable {
+        Ok(()) => Diagnosis::Ok(format!("`{}` is writable", home.display())),
+        Err(e) => Diagnosis::Error(
+            format!("`{}` is not writable: {e}", home.display()),
+            format!(
+                "check the permissions of `{}`, or set `RUSTDN_HOME` to a different directory",
+                home.display()
+            ),
+        ),
+    }
+}
+
+/// Checks that the tools in `PATH` are `rustdn` proxies: that there are any, that `rustc` and
+/// `cargo` are, and that none of the proxies are shadowed by other binaries (e.g. rustup's).
+fn diagnose_proxies(config: &Config) -> Vec<Diagnosis> {
+    let Ok(exe) = env::current_exe().and_then(fs::canonicalize) else {
+        return vec![Diagnosis::Warning(
+            "couldn't find the `rustdn` binary, the proxies are not checked".to_owned(),
+            "check that `rustdn` wasn't moved or deleted while running".to_owned(),
+        )];
+    };
+
+    let path = env::var_os("PATH").unwrap_or_default();
+    let is_proxy = |path: &Path| fs::canonicalize(path).is_ok_and(|target| target == exe);
+
+    let mut diagnoses = Vec::new();
+    let mut proxies = Vec::new();
+
+    let tools = proxy::PROXIES
+        .iter()
+        .copied()
+        .chain(config.extra_proxies.iter().map(String::as_str));
+    for tool in tools {
+        let mut found = env::split_paths(&path)
+            .map(|dir| dir.join(tool))
+            .filter(|path| path.is_file());
+
+        let first = found.next();
+        match first {
+            Some(first) if is_proxy(&first) => proxies.push(tool),
+            Some(first) if found.any(|path| is_proxy(&path)) => diagnoses.push(Diagnosis::Error(
+                format!(
+                    "`{tool}` in `PATH` is `{}`, which comes before the `rustdn` proxy",
+                    first.display()
+                ),
+                format!(
+                    "move the directory with the proxies earlier in `PATH` \
+                     (or remove the other `{tool}`)"
                 ),
+            )),
+            // Other tools don't need proxies, e.g. `rust-gdb` might come with `gdb`
+            first if matches!(tool, "rustc" | "cargo") => {
+                let message = match first {
+                    Some(first) => format!(
+                        "`{tool}` in `PATH` is `{}`, not a `rustdn` proxy",
+                        first.display()
+                    ),
+                    None => format!("there is no `{tool}` in `PATH`"),
+                };
+
+                diagnoses.push(Diagnosis::Error(
+                    message,
+                    "run `rustdn self install` (and make sure the directory with the proxies is \
+                     early in `PATH`)"
+                        .to_owned(),
+                ));
             }
+            _ => {}
         }
+    }
+
+    if !proxies.is_empty() {
+        diagnoses.insert(
+            0,
+            Diagnosis::Ok(format!(
+                "proxies in `PATH` run `rustdn` (`{}`): {}",
+                exe.display(),
+                proxies.join(", ")
+            )),
+        );
+    }
+
+    if let Some(rustup) = config::find_in_path("rustup") {
+        diagnoses.push(Diagnosis::Warning(
+            format!("rustup is installed (`{}`)", rustup.display()),
+            "rustup's proxies (in `~/.cargo/bin`) are used instead of `rustdn`'s, if they come \
+             first in `PATH`"
+                .to_owned(),
+        ));
+    }
+
+    diagnoses
+}
+
+/// Returns an abbreviated hash of a store path (like `git` does with commit hashes).
+fn short_hash(store_path: &Path) -> String {
+    let name = store_path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+
+    name.chars().take(7).collect()
+}
+
+/// `rustdn completions <shell>`.
+///
+/// `rustdn completions --toolchains` prints toolchains to complete, it's used by the scripts.
+fn completions(mut args: Args) -> Result<(), Error> {
+    let shell = args.next();
+
+    if shell.as_deref() == Some("--toolchains") {
+        print!("{}", completions::toolchains()?);
+        return Ok(());
+    }
+
+    let commands = COMMANDS.iter().map(|&(name, ..)| name).collect::<Vec<_>>();
+
+    match shell
+        .as_deref()
+        .and_then(|s| completions::script(s, &commands))
+    {
+        Some(script) => print!("{script}"),
+        None => {
+            return Err(Error::Usage(format!(
+                "expected one of: {}\n\n{}",
+                completions::SHELLS.join(", "),
+                command_usage("completions")
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn help [command]`.
+fn help(command: Option<&str>) -> Result<(), Error> {
+    let Some(command) = command else {
+        println!("{}", help_text());
+        return Ok(());
+    };
+
+    match COMMANDS.iter().find(|(name, ..)| *name == command) {
+        Some((_, usage, description)) => println!("Usage: rustdn {usage}\n\n{description}"),
+        None => {
+            return Err(Error::Usage(format!(
+                "unknown command `{command}`\n\n{}",
+                help_text()
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn show [+toolchain] [--json]` and `rustdn show active-toolchain [--plain] [+toolchain]`.
+///
+/// Resolves the toolchain the same way proxies do and reports what was chosen and why
+/// (with `--json` as a JSON object, see [`show_json`]).
+/// This never builds/downloads the toolchain.
+fn show(mut args: Args) -> Result<(), Error> {
+    let first = args.next();
+    match first.as_deref() {
+        Some("active-toolchain") => return show_active_toolchain(args),
+        Some("home") => return show_path(args, config::rustdn_home()),
+        Some("toolchains-dir") => return show_path(args, toolchains_dir()),
+        Some("store-path") => return show_store_path(args),
+        _ => {}
+    }
+
+    let mut json = false;
+    let mut toolchain = None;
+
+    for arg in first.into_iter().chain(args) {
+        match &*arg {
+            "--json" => json = true,
+            _ if toolchain.is_none() && arg.starts_with('+') => toolchain = Some(arg),
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    let (toolchain, source) = resolve_toolchain(toolchain.as_deref())?;
+
+    if json {
+        return show_json(&toolchain, &source);
+    }
+
+    println!("toolchain: {toolchain}");
+    println!("source: {}", describe_source(&source));
+
+    // Toolchain files far up (e.g. in `~`) apply to a lot more than intended, more often than not
+    if let ToolchainSource::ToolchainFile(path) = &source {
+        let cwd = current_dir()?;
+        let file_dir = path.parent();
+
+        match cwd.ancestors().position(|dir| Some(dir) == file_dir) {
+            Some(0) | None => {}
+            Some(1) => println!("note: the toolchain file is in the parent directory"),
+            Some(depth) => println!("note: the toolchain file is {depth} directories up"),
+        }
+
+        let home = dirs::home_dir();
+        if let Some(dir) = file_dir.filter(|&d| Some(d) == home.as_deref() || d == Path::new("/")) {
+            println!(
+                "note: the toolchain file applies to everything in `{}`",
+                dir.display()
+            );
+        }
+    }
+
+    // Toolchain files specify their components and targets themselves
+    if let ToolchainOverride::File(path) = &toolchain {
+        let file = toolchain_file::parse(path)?;
+
+        if !file.components.is_empty() {
+            println!("components: {}", file.components.join(", "));
+        }
+        if !file.targets.is_empty() {
+            println!("targets: {}", file.targets.join(", "));
+        }
+    }
+
+    match find_cached_toolchain(&toolchain)? {
+        Some(path) => {
+            // The cache is a symlink (`--out-link`) to a store path.
+            println!("path: {}", store_path(&path).display());
+
+            let version = toolchain_version(&path);
+            println!("version: {}", version.as_deref().unwrap_or("unknown"));
+
+            // Linked toolchains are not cached
+            if !matches!(toolchain, ToolchainOverride::LocalName(_)) {
+                match path.parent().and_then(last_used) {
+                    Some(time) => println!("last used: {}", time_ago(time)),
+                    None => println!("last used: unknown"),
+                }
+            }
+        }
+        None => println!("path: not installed"),
+    }
+
+    Ok(())
+}
+
+/// Prints the resolved `toolchain` as a JSON object, for editors and other tools:
+///
+/// ```json
+/// {"source": "toolchain-file", "spec": "/src/rust-toolchain.toml", "store_path": "/nix/store/...", "installed": true, "rustc_version": "rustc 1.80.0 (...)"}
+/// ```
+///
+/// `source` is one of `cli`, `environment`, `override`, `toolchain-file` and `default`. `spec` is
+/// the toolchain as it would be specified after `+` (or the path of the toolchain file), without
+/// targets and the profile, which can't be specified that way. `store_path` and `rustc_version` are `null` if the toolchain is not installed (or the version
+/// is unknown).
+fn show_json(toolchain: &ToolchainOverride, source: &ToolchainSource) -> Result<(), Error> {
+    let source = match source {
+        ToolchainSource::CommandLine => "cli",
+        ToolchainSource::Environment => "environment",
+        ToolchainSource::Override(_) => "override",
+        ToolchainSource::ToolchainFile(_) | ToolchainSource::EnvironmentFile(_) => "toolchain-file",
+        ToolchainSource::Default => "default",
+    };
+
+    let cached = find_cached_toolchain(toolchain)?;
+    let store_path = cached
+        .as_deref()
+        .map(|path| store_path(path).display().to_string());
+    let rustc_version = cached.as_deref().and_then(toolchain_version);
+
+    let json_or_null = |s: Option<String>| s.map_or_else(|| "null".to_owned(), |s| json_string(&s));
+    println!(
+        "{{\"source\": {}, \"spec\": {}, \"store_path\": {}, \"installed\": {}, \"rustc_version\": {}}}",
+        json_string(source),
+        json_string(&toolchain.to_string()),
+        json_or_null(store_path),
+        cached.is_some(),
+        json_or_null(rustc_version),
+    );
+
+    Ok(())
+}
+
+/// `rustdn show active-toolchain [--plain] [+toolchain]`.
+///
+/// Prints a single line with the resolved toolchain (as it would be specified after `+`, or the
+/// path of the toolchain file, see [`ToolchainOverride`]'s `Display`) and where it came from. With `--plain` only the toolchain is
+/// printed, so that it can be used in scripts (`$(rustdn show active-toolchain --plain)`).
+fn show_active_toolchain(args: Args) -> Result<(), Error> {
+    let mut plain = false;
+    let mut toolchain = None;
+
+    for arg in args {
+        match &*arg {
+            "--plain" => plain = true,
+            _ if toolchain.is_none() && arg.starts_with('+') => toolchain = Some(arg),
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    let (toolchain, source) = resolve_toolchain(toolchain.as_deref())?;
+
+    if plain {
+        println!("{toolchain}");
+    } else {
+        println!("{toolchain} ({})", describe_source(&source));
+    }
+
+    Ok(())
+}
+
+/// `rustdn show home` and `rustdn show toolchains-dir`.
+///
+/// Prints just the `path` (which may or may not exist), so that it can be used in scripts.
+fn show_path(mut args: Args, path: Result<PathBuf, Error>) -> Result<(), Error> {
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!("unexpected argument `{arg}`")));
+    }
+
+    println!("{}", path?.display());
+
+    Ok(())
+}
+
+/// `rustdn show store-path [+toolchain]`.
+///
+/// Prints the store path of the chosen toolchain, without building it (see
+/// [`toolchain_store_path`]).
+fn show_store_path(args: Args) -> Result<(), Error> {
+    let mut toolchain = None;
+
+    for arg in args {
+        match &*arg {
+            _ if toolchain.is_none() && arg.starts_with('+') => toolchain = Some(arg),
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    let (toolchain, _) = resolve_toolchain(toolchain.as_deref())?;
+    println!("{}", toolchain_store_path(&toolchain)?.display());
+
+    Ok(())
+}
+
+/// Returns a human readable description of where a toolchain came from.
+fn describe_source(source: &ToolchainSource) -> String {
+    match source {
+        ToolchainSource::CommandLine => "command line override".to_owned(),
+        ToolchainSource::Environment => format!("`{RUSTUP_TOOLCHAIN_VAR}` environment variable"),
+        ToolchainSource::Override(dir) => format!("directory override for `{}`", dir.display()),
+        ToolchainSource::ToolchainFile(path) => format!("toolchain file `{}`", path.display()),
+        ToolchainSource::EnvironmentFile(path) => format!(
+            "toolchain file `{}` (`{TOOLCHAIN_FILE_VAR}` environment variable)",
+            path.display()
+        ),
+        ToolchainSource::Default => "default".to_owned(),
+    }
+}
+
+/// Formats how long ago `time` was, e.g. `3 days ago`.
+fn time_ago(time: SystemTime) -> String {
+    let secs = time.elapsed().unwrap_or(Duration::ZERO).as_secs();
+
+    let (n, unit) = match secs {
+        0..=59 => return "just now".to_owned(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    let s = if n == 1 { "" } else { "s" };
+
+    format!("{n} {unit}{s} ago")
+}
+
+/// `rustdn version [+toolchain]`.
+///
+/// Like `rustdn --version`, but also prints the version of the toolchain `rustdn` would choose.
+/// This never builds/downloads the toolchain.
+fn version(mut args: Args) -> Result<(), Error> {
+    println!("rustdn {}", env!("CARGO_PKG_VERSION"));
+
+    let (toolchain, _) = resolve_toolchain(args.next().as_deref())?;
+    let version = match find_cached_toolchain(&toolchain)? {
+        Some(path) => toolchain_version(&path).unwrap_or_else(|| "unknown version".to_owned()),
+        None => "not installed".to_owned(),
+    };
+
+    println!("toolchain: {toolchain} ({version})");
+
+    Ok(())
+}
+
+/// `rustdn which [+toolchain] (<tool> | --all)`.
+///
+/// Prints the path to the binary that the `<tool>` proxy would execute, or with `--all`, every
+/// binary of the toolchain as `<tool> -> <path>` (see [`which_all`]).
+fn which(mut args: Args) -> Result<(), Error> {
+    let first = args.next();
+    let (toolchain, source) = resolve_toolchain(first.as_deref())?;
+
+    let tool = match source {
+        ToolchainSource::CommandLine => args.next(),
+        _ => first,
+    };
+    let Some(tool) = tool else {
+        return Err(Error::Usage(
+            "expected a tool name, e.g. `rustdn which cargo`".to_owned(),
+        ));
+    };
+
+    if tool == "--all" {
+        return which_all(toolchain);
+    }
+
+    let toolchain_name = toolchain.to_string();
+    let bin_path = toolchain_bin(&get_or_update_toolchain(toolchain)?, &tool);
+
+    if !bin_path.exists() {
+        return Err(Error::ToolNotFound {
+            component: component_for_tool(&tool),
+            tool,
+            toolchain: toolchain_name,
+        });
+    }
+
+    println!("{}", bin_path.display());
+
+    Ok(())
+}
+
+/// Tools every toolchain is expected to have, `which --all` points out if they are missing.
+const EXPECTED_TOOLS: [&str; 3] = ["rustc", "cargo", "rustdoc"];
+
+/// Lists the binaries in the `bin` directory of `toolchain`, sorted by name, as well as the
+/// [`EXPECTED_TOOLS`] which are not there (as `<tool> -> missing`).
+fn which_all(toolchain: ToolchainOverride) -> Result<(), Error> {
+    let bin_dir = get_or_update_toolchain(toolchain)?.join("bin");
+
+    let mut tools = Vec::new();
+    for entry in fs::read_dir(&bin_dir).with_path(&bin_dir)? {
+        let entry = entry.with_path(&bin_dir)?;
+        tools.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            Some(entry.path()),
+        ));
+    }
+
+    for tool in EXPECTED_TOOLS {
+        if !tools.iter().any(|(name, _)| name == tool) {
+            tools.push((tool.to_owned(), None));
+        }
+    }
+
+    tools.sort();
+
+    for (tool, path) in tools {
+        match path {
+            Some(path) => println!("{tool} -> {}", path.display()),
+            None => println!("{tool} -> missing"),
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn doc [+toolchain] [--std | <crate>]`.
+///
+/// Opens the documentation index of the toolchain (from the `rust-docs` component), or the docs
+/// of `std`/`<crate>` (e.g. `core`, `alloc`, `proc_macro`), with `xdg-open` (`open` on macOS).
+fn doc(mut args: Args) -> Result<(), Error> {
+    let first = args.next();
+    let (toolchain, source) = resolve_toolchain(first.as_deref())?;
+
+    let page = match source {
+        ToolchainSource::CommandLine => args.next(),
+        _ => first,
+    };
+    let krate = match page.as_deref() {
+        None => None,
+        Some("--std") => Some("std".to_owned()),
+        Some(arg) if arg.starts_with('-') => {
+            return Err(Error::Usage(format!("unknown flag `{arg}`")))
+        }
+        // Crate directories use underscores, e.g. `proc_macro`
+        Some(krate) => Some(krate.replace('-', "_")),
+    };
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!("unexpected argument `{arg}`")));
+    }
+
+    let toolchain_name = toolchain.to_string();
+    let html = get_or_update_toolchain(toolchain)?.join("share/doc/rust/html");
+
+    let index = match &krate {
+        Some(krate) => html.join(krate).join("index.html"),
+        None => html.join("index.html"),
+    };
+    if !index.exists() {
+        // Without `rust-docs` there are no docs at all, which is worth pointing out
+        let krate = krate.filter(|_| html.join("index.html").exists());
+        return Err(Error::DocsNotFound {
+            toolchain: toolchain_name,
+            krate,
+        });
+    }
 
-        for toolchain in toolchains {
-            // FIXME: figure out the actual toolchain versions, somehow
-            match toolchain {
-                ToolchainOverride::File(p) => println!("{} (???)", p.display()),
-                ToolchainOverride::Version {
-                    channel,
-                    version: Some(version),
-                } => println!("{channel}-{version}"),
-                ToolchainOverride::Version {
-                    channel,
-                    version: None,
-                } => println!("{channel} (???)"),
-                ToolchainOverride::None => println!("default (???)"),
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+
+    let status = Command::new(opener)
+        .arg(&index)
+        .status()
+        .map_err(|source| Error::Spawn {
+            program: opener.into(),
+            source,
+        })?;
+
+    if !status.success() {
+        // The opener reports its own errors, just forward the exit code
+        exit_like(status);
+    }
+
+    Ok(())
+}
+
+/// `rustdn env [+toolchain] [--json]`.
+///
+/// Prints the environment proxies would run tools with (`PATH` with the `bin` directory of the
+/// toolchain, `RUSTC`/`RUSTDOC` and [`PIN_VAR`]), as `export` lines for `eval`, or as a JSON object.
+fn env_(mut args: Args) -> Result<(), Error> {
+    let first = args.next();
+    let (toolchain, source) = resolve_toolchain(first.as_deref())?;
+
+    let rest = match source {
+        ToolchainSource::CommandLine => None,
+        _ => first,
+    }
+    .into_iter()
+    .chain(args);
+
+    let mut json = false;
+    for arg in rest {
+        match &*arg {
+            "--json" => json = true,
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    let toolchain = store_path(&get_or_update_toolchain(toolchain)?);
+
+    let mut vars = vec![(
+        "PATH",
+        path_with_toolchain(&toolchain)?
+            .to_string_lossy()
+            .into_owned(),
+    )];
+    vars.extend(
+        proxy::cargo_tool_env(&toolchain)
+            .into_iter()
+            .map(|(var, path)| (var, path.display().to_string())),
+    );
+    vars.push((PIN_VAR, toolchain.display().to_string()));
+
+    if json {
+        let fields: Vec<_> = vars
+            .iter()
+            .map(|(var, value)| format!("{}: {}", json_string(var), json_string(value)))
+            .collect();
+        println!("{{{}}}", fields.join(", "));
+    } else {
+        for (var, value) in vars {
+            println!("export {var}={}", shell_quote(&value));
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn default [<toolchain> | --reset]`.
+///
+/// Without arguments prints the default toolchain, otherwise changes it.
+/// `--reset` (or `default` as the toolchain) resets it to `default_channel` from the config
+/// (the latest stable, if it's not set).
+fn default(mut args: Args) -> Result<(), Error> {
+    let Some(arg) = args.next() else {
+        match default_toolchain()? {
+            ToolchainOverride::None => {
+                println!("default (`{}`)", config::get()?.default_toolchain());
+            }
+            toolchain => println!("{toolchain}"),
+        }
+
+        return Ok(());
+    };
+
+    let toolchain = match &*arg {
+        "--reset" => ToolchainOverride::None,
+        _ => parse_toolchain_arg(&arg)?,
+    };
+    set_default_toolchain(&toolchain)?;
+
+    match toolchain {
+        ToolchainOverride::None => status!(
+            "reset the default toolchain to `{}`",
+            config::get()?.default_toolchain()
+        ),
+        toolchain => status!("default toolchain set to `{toolchain}`"),
+    }
+
+    Ok(())
+}
+
+/// `rustdn override (set <toolchain> | unset | list)`.
+fn override_(mut args: Args) -> Result<(), Error> {
+    let mut overrides = Overrides::load()?;
+
+    match args.next().as_deref() {
+        Some("set") => {
+            let Some(arg) = args.next() else {
+                return Err(Error::Usage(
+                    "expected a toolchain, e.g. `rustdn override set nightly`".to_owned(),
+                ));
             };
+
+            let toolchain = parse_toolchain_arg(&arg)?;
+            let dir = current_dir()?;
+
+            overrides.set(&dir, &toolchain)?;
+            overrides.save()?;
+
+            status!("override set for `{}`: `{toolchain}`", dir.display());
+        }
+        Some("unset") => {
+            let dir = current_dir()?;
+
+            if overrides.unset(&dir)? {
+                overrides.save()?;
+                status!("override removed for `{}`", dir.display());
+            } else {
+                status!("no override set for `{}`", dir.display());
+            }
+        }
+        Some("list") => {
+            for (dir, toolchain) in overrides.iter() {
+                println!("{dir}\t{toolchain}");
+            }
+        }
+        _ => {
+            return Err(Error::Usage(
+                "expected `rustdn override set <toolchain>`, `rustdn override unset` \
+                 or `rustdn override list`"
+                    .to_owned(),
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// `rustdn run [--component <components>] [--target <targets>] [--profile <profile>] <toolchain> <command> [args...]`.
+///
+/// Runs `command` with the `bin` directory of `toolchain` prepended to `PATH`
+/// and the toolchain pinned for proxies (see [`PIN_VAR`]).
+/// `rustdn` exits with the same exit code (or signal) as the command.
+fn run(mut args: Args) -> Result<(), Error> {
+    let mut extras = Extras::default();
+    let mut toolchain = None;
+
+    // Flags are only accepted before the toolchain, everything after it belongs to the command.
+    while let Some(arg) = args.next() {
+        if !parse_extras_flag(&arg, &mut args, &mut extras)? {
+            toolchain = Some(arg);
+            break;
+        }
+    }
+
+    let (Some(toolchain), Some(command)) = (toolchain, args.next()) else {
+        return Err(Error::Usage(
+            "expected a toolchain and a command, e.g. `rustdn run nightly cargo test`".to_owned(),
+        ));
+    };
+
+    let toolchain = parse_toolchain_arg(&toolchain)?.with_extras(extras)?;
+    let toolchain = get_or_update_toolchain(toolchain)?;
+
+    let status = Command::new(&command)
+        .args(args)
+        .env("PATH", path_with_toolchain(&toolchain)?)
+        .env(PIN_VAR, store_path(&toolchain))
+        .status()
+        .map_err(|source| Error::Spawn {
+            program: command.into(),
+            source,
+        })?;
+
+    exit_like(status)
+}
+
+/// `rustdn shell [--keep-proxies] [--component <components>] [--target <targets>] [--profile <profile>] <toolchain>`.
+///
+/// Starts `$SHELL` with the `bin` directory of `toolchain` prepended to `PATH`.
+///
+/// By default proxies are disabled inside the shell (by setting [`PIN_VAR`]), i.e.
+/// ```shell
+/// ; rustdn shell stable
+/// ; rustc +nightly
+/// error: couldn't read +nigthly: No such file or directory (os error 2)
+///
+/// error: aborting due to 1 previous error
+/// ```
+///
+/// With `--keep-proxies`, `+<toolchain>` and `rust-toolchain.toml` work as usual.
+fn shell(mut args: Args) -> Result<(), Error> {
+    let mut keep_proxies = false;
+    let mut extras = Extras::default();
+    let mut toolchain = None;
+
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--keep-proxies" => keep_proxies = true,
+            _ if parse_extras_flag(&arg, &mut args, &mut extras)? => {}
+            _ if toolchain.is_none() => toolchain = Some(arg),
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    let Some(toolchain) = toolchain else {
+        return Err(Error::Usage(
+            "expected a toolchain, e.g. `rustdn shell nightly`".to_owned(),
+        ));
+    };
+
+    let toolchain = parse_toolchain_arg(&toolchain)?.with_extras(extras)?;
+    let toolchain = get_or_update_toolchain(toolchain)?;
+    let shell = env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+
+    let error = Command::new(&shell)
+        .env("PATH", path_with_toolchain(&toolchain)?)
+        .also(|c| {
+            if !keep_proxies {
+                c.env(PIN_VAR, store_path(&toolchain));
+            }
+        })
+        .exec();
+
+    Err(Error::Spawn {
+        program: shell.into(),
+        source: error,
+    })
+}
+
+/// Parses `--component <components>`, `--target <targets>` (values are comma separated lists)
+/// and `--profile <profile>` flags.
+///
+/// Returns `false` if `arg` is not such a flag.
+fn parse_extras_flag(arg: &str, args: &mut Args, extras: &mut Extras) -> Result<bool, Error> {
+    if !matches!(arg, "--component" | "--target" | "--profile") {
+        return Ok(false);
+    }
+
+    let Some(value) = args.next() else {
+        return Err(Error::Usage(format!("`{arg}` requires a value")));
+    };
+
+    let set = match arg {
+        "--component" => &mut extras.components,
+        "--target" => &mut extras.targets,
+        _ => {
+            extras.profile = value.parse().map_err(|()| {
+                Error::Usage(format!(
+                    "unknown profile `{value}`, expected one of: minimal, default, complete"
+                ))
+            })?;
+            return Ok(true);
+        }
+    };
+
+    for name in value.split(',').filter(|v| !v.is_empty()) {
+        if !is_valid_extra_name(name) {
+            return Err(Error::Usage(format!(
+                "invalid name `{name}` in `{arg}`, expected something like `rust-src` or \
+                 `wasm32-unknown-unknown`"
+            )));
+        }
+
+        set.insert(name.to_owned());
+    }
+
+    Ok(true)
+}
+
+/// Returns the value of `PATH` with the `bin` directory of `toolchain` prepended to it.
+fn path_with_toolchain(toolchain: &Path) -> Result<OsString, Error> {
+    let bin = toolchain.join("bin");
+    let path = env::var_os("PATH").unwrap_or_default();
+
+    env::join_paths(iter::once(bin.clone()).chain(env::split_paths(&path)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        .with_path(bin)
+}
+
+fn self_(mut args: Args) -> Result<(), Error> {
+    match args.next().as_deref() {
+        Some("install") => self_install(args),
+        Some("uninstall") => self_uninstall(args),
+        _ => Err(Error::Usage(format!(
+            "expected a subcommand\n\n{}",
+            command_usage("self")
+        ))),
+    }
+}
+
+/// `rustdn self install [--bin-dir <dir>] [--force]`.
+///
+/// Creates the proxies ([`proxy::PROXIES`] and `extra_proxies` from the config), i.e. symlinks to
+/// the `rustdn` binary, in `<dir>` (`~/.local/bin` by default). Existing symlinks are replaced,
+/// other files only with `--force`.
+fn self_install(mut args: Args) -> Result<(), Error> {
+    let mut bin_dir = None;
+    let mut force = false;
+
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--bin-dir" => match args.next() {
+                Some(dir) => bin_dir = Some(PathBuf::from(dir)),
+                None => {
+                    return Err(Error::Usage(
+                        "expected a directory after `--bin-dir`".to_owned(),
+                    ))
+                }
+            },
+            "--force" => force = true,
+            _ => {
+                return Err(Error::Usage(format!(
+                    "unexpected argument `{arg}`\n\n{}",
+                    command_usage("self")
+                )))
+            }
+        }
+    }
+
+    let bin_dir = match bin_dir {
+        Some(dir) => dir,
+        None => dirs::home_dir()
+            .ok_or_else(|| Error::Usage("no home directory, specify `--bin-dir`".to_owned()))?
+            .join(".local/bin"),
+    };
+
+    let config = config::get()?;
+    let mut tools: Vec<&str> = proxy::PROXIES.to_vec();
+    for tool in &config.extra_proxies {
+        if tool.is_empty() || tool.contains('/') || tool == "rustdn" {
+            return Err(Error::Usage(format!(
+                "invalid tool name `{tool}` in `extra_proxies`"
+            )));
+        }
+        if !tools.contains(&&**tool) {
+            tools.push(tool);
+        }
+    }
+
+    let exe = env::current_exe().with_path("/proc/self/exe")?;
+    let canonical_exe = fs::canonicalize(&exe).with_path(&exe)?;
+
+    // Check everything first, so that nothing is created if some proxies conflict
+    let mut to_create = Vec::new();
+    for tool in tools {
+        let path = bin_dir.join(tool);
+
+        match path.symlink_metadata() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_path(&path),
+            Ok(_) if fs::canonicalize(&path).is_ok_and(|target| target == canonical_exe) => {
+                status!("`{}` is already a proxy", path.display());
+                continue;
+            }
+            Ok(meta) if !meta.is_symlink() && !force => {
+                return Err(Error::ProxyConflict { path });
+            }
+            Ok(_) => {}
+        }
+
+        to_create.push(path);
+    }
+
+    fs::create_dir_all(&bin_dir).with_path(&bin_dir)?;
+
+    for path in to_create {
+        // Replace existing files atomically, so that the tool is never missing
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".rustdn-tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let _ = fs::remove_file(&tmp);
+        std::os::unix::fs::symlink(&exe, &tmp).with_path(&tmp)?;
+        fs::rename(&tmp, &path).with_path(&path)?;
+
+        status!("created `{}` -> `{}`", path.display(), exe.display());
+    }
+
+    let in_path =
+        env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir == bin_dir));
+    if !in_path {
+        status!(
+            "note: `{}` is not in `PATH`, add it for the proxies to be used",
+            bin_dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// `rustdn self uninstall [--purge] [--yes] [<dir>...]`.
+///
+/// Removes the proxies (see [`proxy::find_proxies`]) from `<dir>`s, or from all directories in
+/// `PATH` if none are given. With `--purge` also removes the `rustdn` home (toolchains, overrides,
+/// configuration, ...). Everything that will be removed is listed first, and nothing is removed
+/// without a confirmation (unless `--yes` is passed). Fails if it's not confirmed, so that scripts
+/// can tell that nothing was removed.
+///
+/// N.B.: the `rustdn` binary itself is kept, it's usually managed by a package manager.
+fn self_uninstall(args: Args) -> Result<(), Error> {
+    let mut purge = false;
+    let mut yes = false;
+    let mut dirs = Vec::new();
+
+    for arg in args {
+        match &*arg {
+            "--purge" => purge = true,
+            "--yes" | "-y" => yes = true,
+            _ if arg.starts_with('-') => {
+                return Err(Error::Usage(format!(
+                    "unexpected argument `{arg}`\n\n{}",
+                    command_usage("self")
+                )))
+            }
+            _ => dirs.push(PathBuf::from(arg)),
+        }
+    }
+
+    let exe = env::current_exe()
+        .and_then(fs::canonicalize)
+        .with_path("/proc/self/exe")?;
+
+    let mut proxies = Vec::new();
+    if dirs.is_empty() {
+        let path = env::var_os("PATH").unwrap_or_default();
+        for dir in env::split_paths(&path) {
+            if dirs.contains(&dir) {
+                continue;
+            }
+
+            // Directories in `PATH` don't have to exist (or be readable)
+            if let Ok(found) = proxy::find_proxies(&dir, &exe) {
+                proxies.extend(found);
+            }
+            dirs.push(dir);
         }
     } else {
-        unimplemented!()
+        for dir in &dirs {
+            proxies.extend(proxy::find_proxies(dir, &exe)?);
+        }
+    }
+
+    let home = config::rustdn_home()?;
+    let purge_home = purge && home.exists();
+
+    if proxies.is_empty() && !purge_home {
+        status!("nothing to uninstall");
+        return Ok(());
+    }
+
+    println!("this will remove:");
+    for proxy in &proxies {
+        println!("  {} (proxy)", proxy.display());
+    }
+    if purge_home {
+        println!(
+            "  {} (all toolchains, overrides and configuration)",
+            home.display()
+        );
+    }
+
+    if !yes {
+        print!("continue? [y/N] ");
+        io::stdout().flush().with_path("<stdout>")?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).with_path("<stdin>")?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            // Finish the prompt's line, if it was EOF rather than an answer
+            if answer.is_empty() {
+                println!();
+            }
+            return Err(Error::Aborted {
+                terminal: io::stdin().is_terminal(),
+            });
+        }
+    }
+
+    for proxy in &proxies {
+        fs::remove_file(proxy).with_path(proxy)?;
+        status!("removed `{}`", proxy.display());
+    }
+
+    if purge_home {
+        fs::remove_dir_all(&home).with_path(&home)?;
+        status!("removed `{}`", home.display());
+    }
+
+    Ok(())
+}
+
+fn toolchain(mut args: Args) -> Result<(), Error> {
+    match args.next().as_deref() {
+        Some("list") => toolchain_list(args),
+        Some("add") => toolchain_add(args),
+        Some("remove") => toolchain_remove(args),
+        Some("link") => toolchain_link(args),
+        Some("export") => toolchain_export(args),
+        Some("import") => toolchain_import(args),
+        _ => Err(Error::Usage(format!(
+            "expected a subcommand\n\n{}",
+            command_usage("toolchain")
+        ))),
+    }
+}
+
+/// `rustdn toolchain add <toolchain>...`.
+///
+/// Toolchains are installed in parallel, each one still under its own lock
+/// (see [`get_or_update_toolchain`]).
+fn toolchain_add(args: Args) -> Result<(), Error> {
+    let mut toolchains = Vec::new();
+    let mut keys = Vec::new();
+    for arg in args {
+        let toolchain = parse_toolchain_arg(&arg)?;
+
+        // N.B.: locks don't exclude threads of the same process,
+        //       so the same toolchain must not be built twice at the same time.
+        //       Different names can mean the same toolchain (e.g. `default` and `stable`),
+        //       so this compares the keys (i.e. cache directories) instead.
+        let key = resolved_key(&toolchain)?;
+        if !keys.contains(&key) {
+            keys.push(key);
+            toolchains.push((toolchain.to_string(), toolchain));
+        }
+    }
+
+    if toolchains.is_empty() {
+        return Err(Error::Usage(
+            "expected toolchains to install, e.g. `rustdn toolchain add +nightly +1.78`".to_owned(),
+        ));
+    }
+
+    install_toolchains(toolchains)
+}
+
+/// Installs `toolchains` (named by the first element, which must be unique) in parallel,
+/// reporting each failure separately.
+fn install_toolchains(toolchains: Vec<(String, ToolchainOverride)>) -> Result<(), Error> {
+    let total = toolchains.len();
+    let results = thread::scope(|s| {
+        let handles = toolchains
+            .into_iter()
+            .map(|(name, toolchain)| (name, s.spawn(|| get_or_update_toolchain(toolchain))))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|(name, handle)| (name, handle.join().unwrap()))
+            .collect::<Vec<_>>()
+    });
+
+    let mut failed = 0;
+    for (name, result) in results {
+        match result {
+            Ok(_) => status!("installed `{name}`"),
+            Err(e) => {
+                eprintln!("error: couldn't install `{name}`: {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed != 0 {
+        return Err(Error::InstallFailed { failed, total });
+    }
+
+    Ok(())
+}
+
+/// `rustdn toolchain export`.
+///
+/// Prints the keys of installed (cached) toolchains, one per line, for `rustdn toolchain import`.
+/// This is a snapshot of *which* toolchains are installed, not of the toolchains themselves
+/// (those are in the nix store), see [`config::cache_dir`] for what is portable.
+/// Linked toolchains are not exported.
+fn toolchain_export(mut args: Args) -> Result<(), Error> {
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!("unexpected argument `{arg}`")));
+    }
+
+    let mut stdout = io::stdout().lock();
+    for (toolchain, dir) in cached_toolchains()? {
+        if !has_rustc(&dir.join("toolchain")) {
+            continue;
+        }
+
+        let key = toolchain.key();
+
+        // Can only happen for toolchain files with a newline in their path
+        if key.as_encoded_bytes().contains(&b'\n') {
+            eprintln!("warning: can't export `{toolchain}`, its key has a newline in it");
+            continue;
+        }
+
+        // N.B.: keys are written as is (they might not be UTF-8), `import` reads them the same way
+        stdout
+            .write_all(key.as_encoded_bytes())
+            .and_then(|()| stdout.write_all(b"\n"))
+            .with_path("<stdout>")?;
+    }
+
+    Ok(())
+}
+
+/// `rustdn toolchain import [<file>]`.
+///
+/// Installs the toolchains exported with `rustdn toolchain export`, from `<file>` or stdin.
+/// Empty lines and lines starting with `#` are ignored. The file might come from anywhere (e.g. a
+/// CI cache), so only keys which [`ToolchainOverride::key`] would produce are accepted.
+fn toolchain_import(mut args: Args) -> Result<(), Error> {
+    use std::{ffi::OsStr, io::Read as _, os::unix::ffi::OsStrExt as _};
+
+    let path = args.next();
+    if let Some(arg) = args.next() {
+        return Err(Error::Usage(format!("unexpected argument `{arg}`")));
     }
+
+    let contents = match &path {
+        Some(path) if path != "-" => fs::read(path).with_path(path)?,
+        _ => {
+            let mut contents = Vec::new();
+            io::stdin()
+                .read_to_end(&mut contents)
+                .with_path("<stdin>")?;
+            contents
+        }
+    };
+
+    let mut toolchains = Vec::new();
+    let mut keys = Vec::new();
+    for line in contents.split(|&b| b == b'\n') {
+        // N.B.: this also removes `\r`, for files which were edited on windows
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+
+        let key = OsStr::from_bytes(line);
+        let toolchain = ToolchainOverride::from_key(key.to_owned())
+            .filter(|toolchain| toolchain.key() == key)
+            .ok_or_else(|| {
+                Error::Usage(format!(
+                    "`{}` is not a toolchain exported by `rustdn toolchain export`",
+                    String::from_utf8_lossy(line)
+                ))
+            })?;
+
+        // Same as in `toolchain_add`, `default` and the toolchain it stands for share a key
+        let key = resolved_key(&toolchain)?;
+        if !keys.contains(&key) {
+            keys.push(key);
+            toolchains.push((toolchain.to_string(), toolchain));
+        }
+    }
+
+    if toolchains.is_empty() {
+        status!("no toolchains to import");
+        return Ok(());
+    }
+
+    install_toolchains(toolchains)
+}
+
+/// `rustdn toolchain link <name> <path>`.
+///
+/// `<path>` is a toolchain directory (i.e. it has `bin/rustc`), for example
+/// `build/host/stage1` of a rustc checkout.
+fn toolchain_link(mut args: Args) -> Result<(), Error> {
+    let (Some(name), Some(path)) = (args.next(), args.next()) else {
+        return Err(Error::Usage(
+            "expected a toolchain name and a path, e.g. \
+             `rustdn toolchain link stage1 build/host/stage1`"
+                .to_owned(),
+        ));
+    };
+
+    let path = std::path::absolute(&path).with_path(&path)?;
+    link_toolchain(&name, &path)?;
+
+    status!("linked `{name}` to `{}`", path.display());
+
+    Ok(())
+}
+
+/// `rustdn toolchain remove <toolchain>`.
+///
+/// `<toolchain>` can be specified as `+<spec>`, `<spec>`, `default`, or a path to a toolchain file.
+fn toolchain_remove(mut args: Args) -> Result<(), Error> {
+    let Some(arg) = args.next() else {
+        return Err(Error::Usage(
+            "expected a toolchain to remove, e.g. `rustdn toolchain remove +nightly`".to_owned(),
+        ));
+    };
+
+    let toolchain = parse_toolchain_arg(&arg)?;
+    remove_toolchain(&toolchain)?;
+
+    status!("removed `{toolchain}`");
+
+    Ok(())
+}
+
+/// `rustdn toolchain list [--installed-only] [--json | --porcelain]`.
+///
+/// Lists cached and linked toolchains, with their versions, in aligned columns (colored, see
+/// [`config::use_color`]). The active toolchain (the one proxies would use in the current
+/// directory) is marked with `*` and the default one with `(default)`. Cached toolchains whose
+/// store path doesn't exist anymore (e.g. it was garbage collected), or is broken, are marked as
+/// missing, or, with `--installed-only`, not listed at all.
+///
+/// With `--porcelain` there is a tab-separated line per toolchain, for scripts. The format is
+/// stable and never colored:
+///
+/// ```text
+/// <spec>\t<installed|missing>\t<rustc version>\t<cached|linked>\t<path>\t<active,default>
+/// ```
+///
+/// `<spec>` is the toolchain as it would be specified after `+`, without targets and the profile
+/// (so it's not unique, the path is). The version and the path are empty if they are unknown,
+/// the last field is a comma-separated list of `active` and `default` (or empty).
+///
+/// With `--json` a JSON array is printed instead, with an object per toolchain:
+///
+/// ```json
+/// {"spec": "nightly", "linked": false, "installed": true, "path": "/nix/store/...", "rustc_version": "rustc 1.82.0-nightly (...)"}
+/// ```
+///
+/// `path` (the store path, or the target of a linked toolchain) and `rustc_version` are `null`
+/// for missing toolchains (or if the version is unknown).
+fn toolchain_list(args: Args) -> Result<(), Error> {
+    let mut installed_only = false;
+    let mut json = false;
+    let mut porcelain = false;
+
+    for arg in args {
+        match &*arg {
+            "--installed-only" => installed_only = true,
+            "--json" => json = true,
+            "--porcelain" => porcelain = true,
+            _ => return Err(Error::Usage(format!("unexpected argument `{arg}`"))),
+        }
+    }
+
+    if json && porcelain {
+        return Err(Error::Usage(
+            "only one of `--json` and `--porcelain` can be used".to_owned(),
+        ));
+    }
+
+    /// A toolchain to list, `path` and `version` are `None` if it's not installed.
+    struct Listed {
+        spec: String,
+        key: OsString,
+        /// For linked toolchains, where they link to.
+        link: Option<PathBuf>,
+        path: Option<PathBuf>,
+        version: Option<String>,
+    }
+
+    let mut toolchains = Vec::new();
+
+    for (toolchain, dir) in cached_toolchains()? {
+        let link = dir.join("toolchain");
+
+        // N.B.: dangling links (e.g. when the toolchain was garbage collected) are not installed
+        let installed = has_rustc(&link);
+        toolchains.push(Listed {
+            spec: toolchain.to_string(),
+            key: toolchain.key(),
+            link: None,
+            path: installed.then(|| store_path(&link)),
+            version: installed.then(|| toolchain_version(&link)).flatten(),
+        });
+    }
+
+    for (name, target) in linked_toolchains()? {
+        let installed = has_rustc(&target);
+        toolchains.push(Listed {
+            key: ToolchainOverride::LocalName(name.clone()).key(),
+            spec: name,
+            path: installed.then(|| target.clone()),
+            version: installed.then(|| toolchain_version(&target)).flatten(),
+            link: Some(target),
+        });
+    }
+
+    if installed_only {
+        toolchains.retain(|t| t.path.is_some());
+    }
+
+    if json {
+        let json_or_null =
+            |s: Option<String>| s.map_or_else(|| "null".to_owned(), |s| json_string(&s));
+
+        let objects: Vec<_> = toolchains
+            .into_iter()
+            .map(|t| {
+                format!(
+                    "{{\"spec\": {}, \"linked\": {}, \"installed\": {}, \"path\": {}, \"rustc_version\": {}}}",
+                    json_string(&t.spec),
+                    t.link.is_some(),
+                    t.path.is_some(),
+                    json_or_null(t.path.map(|p| p.display().to_string())),
+                    json_or_null(t.version),
+                )
+            })
+            .collect();
+        println!("[{}]", objects.join(", "));
+
+        return Ok(());
+    }
+
+    // The lines (and the columns) must not be broken up, `rustc --version` is one line anyway
+    for t in &mut toolchains {
+        t.version = t
+            .version
+            .take()
+            .and_then(|v| v.lines().next().map(str::to_owned));
+    }
+
+    // N.B.: the list is still useful if e.g. the toolchain file in the current directory is
+    //       broken, so errors only mean that nothing is marked
+    let active = resolve_toolchain(None)
+        .and_then(|(t, _)| resolved_key(&t))
+        .ok();
+    let default = default_toolchain().and_then(|t| resolved_key(&t)).ok();
+
+    if porcelain {
+        for t in toolchains {
+            let flags = [(&active, "active"), (&default, "default")]
+                .into_iter()
+                .filter(|(key, _)| key.as_ref() == Some(&t.key))
+                .map(|(_, flag)| flag)
+                .collect::<Vec<_>>();
+
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                t.spec,
+                if t.path.is_some() {
+                    "installed"
+                } else {
+                    "missing"
+                },
+                t.version.unwrap_or_default(),
+                if t.link.is_some() { "linked" } else { "cached" },
+                t.path.map(|p| p.display().to_string()).unwrap_or_default(),
+                flags.join(","),
+            );
+        }
+
+        return Ok(());
+    }
+
+    let color = config::use_color(io::stdout().is_terminal());
+    let paint = |s: &str, code: Option<&str>| match code {
+        Some(code) if color => format!("\x1b[{code}m{s}\x1b[0m"),
+        _ => s.to_owned(),
+    };
+
+    let rows: Vec<_> = toolchains
+        .into_iter()
+        .map(|t| {
+            let (version, version_color) = match (&t.path, t.version) {
+                (Some(_), Some(version)) => (version, None),
+                (Some(_), None) => ("unknown version".to_owned(), Some("2")),
+                (None, _) if t.link.is_some() => ("missing".to_owned(), Some("33")),
+                (None, _) => ("missing, will be rebuilt when used".to_owned(), Some("33")),
+            };
+            let source = match t.link {
+                Some(target) => format!("linked to {}", target.display()),
+                None => "cached".to_owned(),
+            };
+
+            (t.spec, t.key, version, version_color, source)
+        })
+        .collect();
+
+    let spec_width = rows.iter().map(|r| r.0.chars().count()).max().unwrap_or(0);
+    let version_width = rows.iter().map(|r| r.2.chars().count()).max().unwrap_or(0);
+
+    for (spec, key, version, version_color, source) in rows {
+        let is_active = active.as_ref() == Some(&key);
+        let marker = if is_active { "*" } else { " " };
+        let spec_color = is_active.then_some("1;32");
+        let default_marker = if default.as_ref() == Some(&key) {
+            format!(" {}", paint("(default)", Some("36")))
+        } else {
+            String::new()
+        };
+
+        println!(
+            "{} {}  {}  {}{default_marker}",
+            paint(marker, spec_color),
+            paint(&format!("{spec:spec_width$}"), spec_color),
+            paint(&format!("{version:version_width$}"), version_color),
+            paint(&source, Some("2")),
+        );
+    }
+
+    Ok(())
 }
@@ -0,0 +1,186 @@
+//! A tiny throbber (spinner), to show that *something* is happening while we wait on a long
+//! running operation (like `nix-build` downloading a toolchain).
+//!
+//! When the operation knows how far along it is, the throbber also shows a progress bar, see
+//! [`Throbber::set_progress`].
+
+use std::{
+    fmt,
+    io::{stderr, IsTerminal as _, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::config;
+
+/// How long to wait before showing the throbber.
+///
+/// Fast operations shouldn't flash a throbber for a split second.
+const DELAY: Duration = Duration::from_secs(1);
+
+/// How long each frame is shown for.
+const FRAME_DURATION: Duration = Duration::from_millis(80);
+
+const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Width of the progress bar, in characters.
+const BAR_WIDTH: usize = 20;
+
+/// `true` if there is a throbber which draws itself.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// A throbber guard.
+///
+/// While this type exists, a throbber is drawn on stderr (after a [small delay](DELAY)).
+/// Nothing is drawn if stderr is not a terminal, with `--quiet` (see [`config::quiet`]), or if
+/// another throbber is already drawn (e.g. when toolchains are built in parallel), since they
+/// would fight over the same line.
+///
+/// Clears the throbber line on drop.
+pub struct Throbber {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+struct State {
+    done: bool,
+    /// `true` if the throbber is currently drawn on the last line of stderr.
+    drawn: bool,
+    progress: Option<Progress>,
+}
+
+/// How far along the operation is, see [`Throbber::set_progress`].
+struct Progress {
+    done: usize,
+    total: usize,
+    /// Shown after the numbers, e.g. `paths (45.67 MiB download)`.
+    unit: String,
+}
+
+impl Throbber {
+    /// Starts a throbber which shows `message` followed by a spinning thingy.
+    pub fn start(message: &'static str) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                done: false,
+                drawn: false,
+                progress: None,
+            }),
+            cvar: Condvar::new(),
+        });
+
+        let draw =
+            stderr().is_terminal() && !config::quiet() && !ACTIVE.swap(true, Ordering::Relaxed);
+        let thread = draw.then(|| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || run(&shared, message))
+        });
+
+        Self { shared, thread }
+    }
+
+    /// Shows a progress bar (`done` out of `total` `unit`s) after the spinner, instead of only
+    /// the spinner. This can be called any number of times, e.g. after each step.
+    ///
+    /// Nothing is shown if `total` is `0`, i.e. if it's not known how much there is to do.
+    pub fn set_progress(&self, done: usize, total: usize, unit: &str) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.progress = (total != 0).then(|| Progress {
+            done: done.min(total),
+            total,
+            unit: unit.to_owned(),
+        });
+    }
+
+    /// Prints `line` to stderr, above the throbber.
+    ///
+    /// This should be used instead of printing to stderr directly, while the throbber exists,
+    /// so that the output is not mangled.
+    pub fn println(&self, line: &[u8]) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        let mut stderr = stderr().lock();
+        if state.drawn {
+            clear_line(&mut stderr);
+            state.drawn = false;
+        }
+
+        // the throbber will be redrawn on the next frame
+        _ = stderr.write_all(line);
+        _ = stderr.write_all(b"\n");
+    }
+}
+
+fn run(shared: &Shared, message: &str) {
+    let mut state = shared.state.lock().unwrap();
+
+    for (i, frame) in FRAMES.iter().cycle().enumerate() {
+        let timeout = if i == 0 { DELAY } else { FRAME_DURATION };
+        state = shared
+            .cvar
+            .wait_timeout_while(state, timeout, |state| !state.done)
+            .unwrap()
+            .0;
+
+        if state.done {
+            break;
+        }
+
+        // Errors are ignored, failing to draw a throbber is not a big deal.
+        let mut stderr = stderr().lock();
+        clear_line(&mut stderr);
+        _ = match &state.progress {
+            Some(progress) => write!(stderr, "{message} {frame} {progress}"),
+            None => write!(stderr, "{message} {frame}"),
+        };
+        _ = stderr.flush();
+        state.drawn = true;
+    }
+
+    if state.drawn {
+        clear_line(&mut stderr());
+        state.drawn = false;
+    }
+}
+
+impl fmt::Display for Progress {
+    /// Formats the progress as `[######--------------] 3/10 <unit>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Progress { done, total, unit } = self;
+        let filled = BAR_WIDTH * done / total;
+
+        write!(
+            f,
+            "[{}{}] {done}/{total} {unit}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled)
+        )
+    }
+}
+
+/// Returns to the start of the line and clears it.
+fn clear_line(stderr: &mut impl Write) {
+    _ = stderr.write_all(b"\r\x1b[2K");
+}
+
+impl Drop for Throbber {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().done = true;
+        self.shared.cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+            ACTIVE.store(false, Ordering::Relaxed);
+        }
+    }
+}
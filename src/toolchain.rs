@@ -2,424 +2,3622 @@
 
 use core::{fmt, slice, str};
 use std::{
-    env::current_dir,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    env,
     ffi::{OsStr, OsString},
     fs,
-    io::{stderr, Write as _},
-    iter,
+    hash::BuildHasher as _,
+    io::{self, BufRead as _, BufReader},
     ops::{ControlFlow, Deref},
-    os::unix::ffi::OsStrExt,
+    os::unix::ffi::{OsStrExt, OsStringExt as _},
     path::{Path, PathBuf},
-    process::{self, Command},
+    process::{Command, ExitStatus, Stdio},
     str::FromStr,
+    sync::{Mutex, OnceLock},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span, trace, warn};
 
 use crate::{
+    config::{self, Config, NixCommand},
+    error::{Error, IoResultExt as _},
     lock::{Exclusive, Lock},
-    unstd::AnyExt as _,
+    overrides::Overrides,
+    throbber::Throbber,
+    toolchain_file,
+    unstd::{nix_string, AnyExt as _},
 };
 
-/// Returns path to a toolchain directory somewhere in nix store.
-pub fn get_or_update_toolchain(toolchain: ToolchainOverride) -> PathBuf {
-    let toolchain_key = toolchain.key();
+/// How long to wait for someone else to finish updating a toolchain, before giving up.
+///
+/// Toolchain updates can legitimately take minutes (it's a download after all),
+/// but if it takes longer than this, the update is likely stuck.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-    let toolchain_dir = dirs::home_dir()
-        .unwrap()
-        .join(".rustdn/toolchains")
-        .join(toolchain_key);
+/// First delay of [`UpgradeBackoff`].
+const UPGRADE_BACKOFF_INITIAL: Duration = Duration::from_millis(50);
 
-    fs::create_dir_all(&toolchain_dir).unwrap();
+/// Maximal delay of [`UpgradeBackoff`].
+const UPGRADE_BACKOFF_MAX: Duration = Duration::from_secs(5);
 
-    let lock_file = fs::File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(toolchain_dir.join("lock"))
-        .unwrap();
+/// Backoff between attempts to upgrade a lock, after a failed upgrade (`DEADLK`).
+///
+/// `DEADLK` is returned to all, but one, processes which are trying to upgrade at the same time.
+/// The delays grow exponentially (up to [`UPGRADE_BACKOFF_MAX`]) and are randomized, so that many
+/// processes (e.g. CI jobs) waiting for the same toolchain don't keep retrying in lockstep.
+/// After [`LOCK_TIMEOUT`] in total, this gives up.
+struct UpgradeBackoff {
+    delay: Duration,
+    deadline: Instant,
+}
 
-    debug!("starting looking for the toolchain");
+impl UpgradeBackoff {
+    fn new() -> Self {
+        UpgradeBackoff {
+            delay: UPGRADE_BACKOFF_INITIAL,
+            deadline: Instant::now() + LOCK_TIMEOUT,
+        }
+    }
 
-    loop {
-        let lock = crate::lock::lock_shared(&lock_file).unwrap();
+    /// Returns how long to wait before the next attempt, or `None` if it's time to give up.
+    fn next_delay(&mut self) -> Option<Duration> {
+        let left = self.deadline.checked_duration_since(Instant::now())?;
 
-        if toolchain_dir.join("toolchain").exists()
-            && toolchain.cache_is_valid(&toolchain_dir, &lock)
-        {
-            // we are free
-            break;
-        }
+        // Somewhere between half of the delay and the full delay.
+        // `RandomState` is randomly seeded, which is good enough for jitter.
+        let random = RandomState::new().hash_one(self.delay);
+        let jitter = self.delay.mul_f64((random % 1024) as f64 / 2048.0);
+        let delay = (self.delay - jitter).min(left);
 
-        let mut lock = match lock.upgrade() {
-            Ok(l) => l,
-            Err(e) if e == rustix::io::Errno::DEADLK => {
-                // DEADLK error is returned when multiple readers are trying to upgrade.
-                // it's returned to all, but one, processes.
+        self.delay = (self.delay * 2).min(UPGRADE_BACKOFF_MAX);
 
-                // a small delay to make sure the one process that didn't get the error can actually get an exclusive lock.
-                // this is likely unnecessary, but since updates usually take much more than a second, this is fine to leave.
-                thread::sleep(Duration::from_secs_f32(0.1));
-                continue;
-            }
-            e => e.unwrap(),
-        };
+        Some(delay)
+    }
 
-        let expr = format!(
-            "{}{}",
-            r#"{}: (import <nixpkgs> {overlays = [(import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz"))];}).rust-bin."#,
-            match &toolchain {
-                ToolchainOverride::File(f) =>
-                    format!(r#"fromRustupToolchainFile "{}""#, f.display()),
-                ToolchainOverride::Version { channel, version } => format!(
-                    r#"{}."{}".default"#,
-                    channel.as_str(),
-                    version.as_deref().unwrap_or("latest")
-                ),
-                ToolchainOverride::None => format!("stable.latest.default"),
-            }
-        );
+    /// Sleeps before the next attempt, returns `TIMEDOUT` if it's time to give up.
+    fn wait(&mut self) -> rustix::io::Result<()> {
+        let delay = self.next_delay().ok_or(rustix::io::Errno::TIMEDOUT)?;
+        thread::sleep(delay);
 
-        debug!("starting nix-build");
-
-        // FIXME: we should report *something* if `nix-build` is running for longer than, say, a second.
-        //        some kind of throbber would be nice, to show that *something* is happening,
-        //        toolchain is being downloaded
-        let output = Command::new("nix-build")
-            // Don't create `./result` symlinks.
-            // N.B.: this means that the result of the build does not become a gc root,
-            //       so `nix-store --gc` might delete the toolchain.
-            //       we might want to provide options to deal with it.
-            // IDEA: have a directory like `~/.rustup/toolchains` and use `--out-link` to link the
-            //       results to there. then we can list "installed" toolchains and "uninstalling"
-            //       them becomes a reasonable operation.
-            .arg("--out-link")
-            .arg(toolchain_dir.join("toolchain"))
-            .arg("--expr")
-            .arg(expr)
-            .output()
-            .expect("couldn't start `nix-build` to build rust toolchain");
+        Ok(())
+    }
+}
 
-        // Very important: fail if `nix-build` failed.
-        // This *must* happen before we commit to the cache,
-        // since otherwise we might create an invalid cache and go insane.
-        if !output.status.success() {
-            eprintln!("`nix-build` failed:");
-            stderr().write_all(&output.stderr).unwrap();
+/// Creates [`Error::Lock`] or [`Error::LockTimeout`] (if `source` is `TIMEDOUT`).
+fn lock_error(path: PathBuf, source: rustix::io::Errno) -> Error {
+    match source {
+        rustix::io::Errno::TIMEDOUT => Error::LockTimeout {
+            path,
+            timeout: LOCK_TIMEOUT,
+        },
+        source => Error::Lock { path, source },
+    }
+}
 
-            // Just to be safe (and, well, correct for non-file toolchains),
-            // remove the cache entirely.
-            fs::remove_dir_all(toolchain_dir).unwrap();
+/// Environment variable which pins the toolchain to a specific path.
+///
+/// When set, proxies use the toolchain at this path directly, without any resolution
+/// (`+<toolchain>` arguments are passed to the tool as is).
+pub const PIN_VAR: &str = "RUSTDN_TOOLCHAIN";
 
-            process::exit(output.status.code().unwrap_or(1));
-        }
+/// Returns the toolchain pinned via [`PIN_VAR`], if any.
+pub fn pinned_toolchain() -> Option<PathBuf> {
+    env::var_os(PIN_VAR)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
 
-        debug!("starting nix-build finished");
+/// Returns the nix store path `toolchain` (an out-link in the cache) points to.
+///
+/// Pinning the store path instead of the out-link makes sure that the toolchain doesn't change
+/// even if the cache is updated in the meantime.
+pub fn store_path(toolchain: &Path) -> PathBuf {
+    fs::canonicalize(toolchain).unwrap_or_else(|_| toolchain.to_owned())
+}
 
-        if let ControlFlow::Break(()) = toolchain.commit_cache(&toolchain_dir, &mut lock) {
-            break;
-        }
-    }
+/// Environment variable which enables offline mode (see [`get_or_update_toolchain`]).
+pub const OFFLINE_VAR: &str = "RUSTDN_OFFLINE";
 
-    toolchain_dir.join("toolchain")
+/// Returns `true` if offline mode is enabled via [`OFFLINE_VAR`].
+pub fn offline() -> bool {
+    env::var_os(OFFLINE_VAR).is_some_and(|v| !v.is_empty() && v != "0")
 }
 
-#[derive(Debug)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
-pub enum ToolchainOverride {
-    File(Box<Path>),
-    Version {
-        channel: Channel,
-        version: Option<String>,
-    },
-    None,
-    // FIXME: how is this supposed to work??
-    //LocalName(String),
+/// Environment variable with the path to a toolchain file to use when nothing more specific
+/// overrides the toolchain, instead of searching for one (see [`resolve_toolchain`]).
+pub const TOOLCHAIN_FILE_VAR: &str = "RUSTDN_TOOLCHAIN_FILE";
+
+/// Environment variable which disables toolchain files (see [`resolve_toolchain`]).
+pub const NO_TOOLCHAIN_FILE_VAR: &str = "RUSTDN_NO_TOOLCHAIN_FILE";
+
+/// Returns `true` if toolchain files should be ignored, i.e. [`NO_TOOLCHAIN_FILE_VAR`] is set.
+fn toolchain_files_disabled() -> bool {
+    env::var_os(NO_TOOLCHAIN_FILE_VAR).is_some_and(|v| !v.is_empty() && v != "0")
 }
 
-impl ToolchainOverride {
-    // N.B. all function here must agree with each other.
+/// Environment variable which rustup uses to force a toolchain.
+///
+/// It is supported for compatibility with tools and scripts which set it
+/// (see [`resolve_toolchain`]).
+pub const RUSTUP_TOOLCHAIN_VAR: &str = "RUSTUP_TOOLCHAIN";
 
-    fn key(&self) -> OsString {
-        match self {
-            ToolchainOverride::File(f) => {
-                let mut key = OsString::from("file-");
+/// Where the chosen toolchain came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolchainSource {
+    /// `+<toolchain>` argument.
+    CommandLine,
+    /// [`RUSTUP_TOOLCHAIN_VAR`] environment variable.
+    Environment,
+    /// `rustdn override set` for the directory (the current directory or one of its parents).
+    Override(PathBuf),
+    /// `rust-toolchain.toml` (or `rust-toolchain`) in the current directory or one of its parents.
+    ToolchainFile(PathBuf),
+    /// The toolchain file in [`TOOLCHAIN_FILE_VAR`] environment variable.
+    EnvironmentFile(PathBuf),
+    /// Nothing overrode the toolchain.
+    Default,
+}
 
-                // FIXME: figure out an encoding for paths which is less cursed
+/// Chooses the toolchain to use.
+///
+/// `override_arg` is the argument which might be a `+<toolchain>` override.
+/// See [`proxy::main`] docs for the exact algorithm.
+///
+/// [`proxy::main`]: crate::proxy::main
+pub fn resolve_toolchain(
+    override_arg: Option<&str>,
+) -> Result<(ToolchainOverride, ToolchainSource), Error> {
+    resolve_toolchain_in(override_arg, &current_dir()?)
+}
 
-                const ESC: u8 = 0x10;
-                f.as_os_str().as_encoded_bytes().iter().for_each(|&b| {
-                    if b.is_ascii() && b != ESC && b != b'/' {
-                        key.push(str::from_utf8(slice::from_ref(&b)).unwrap())
-                    } else {
-                        key.push(format!("\x10{b:x}"))
-                    }
-                });
+/// Returns the current directory.
+///
+/// Unlike [`env::current_dir`], the error explains what's wrong, since the most common reason
+/// for this to fail is the directory being deleted from under a shell.
+pub fn current_dir() -> Result<PathBuf, Error> {
+    env::current_dir().map_err(|source| Error::NoCurrentDir { source })
+}
 
-                key
-            }
-            // FIXME: use different keys for channel and channel+version
-            //        (channel should be a "global" key?)
-            ToolchainOverride::Version {
-                channel,
-                version: Some(version),
-            } => format!("external-{channel}-{version}").into(),
-            ToolchainOverride::Version {
-                channel,
-                version: None,
-            } => format!("external-{channel}").into(),
-            ToolchainOverride::None => "default".to_owned().into(),
-        }
+/// Same as [`resolve_toolchain`], but as if it was run in `dir`.
+///
+/// N.B.: relative paths in `override_arg` are still relative to the current directory.
+pub fn resolve_toolchain_in(
+    override_arg: Option<&str>,
+    dir: &Path,
+) -> Result<(ToolchainOverride, ToolchainSource), Error> {
+    let parsed = parse_toolchain_override(override_arg).map_err(|()| Error::InvalidToolchain {
+        // `parse_toolchain_override` only fails for `Some`
+        spec: override_arg.unwrap_or_default().to_owned(),
+    })?;
+
+    if let Some(t) = parsed {
+        return Ok((t, ToolchainSource::CommandLine));
     }
 
-    pub fn from_key(k: OsString) -> Option<Self> {
-        if let Some(mut encoded_path) = k.as_bytes().strip_prefix(b"file-") {
-            const ESC: u8 = 0x10;
-            const SEP: u8 = b'/';
+    // N.B.: like everything else, this goes through `parse_toolchain_spec`, which only accepts
+    //       valid toolchains (the variable might be set by anything, e.g. a build script)
+    if let Some(spec) = env::var(RUSTUP_TOOLCHAIN_VAR)
+        .ok()
+        .filter(|s| !s.is_empty())
+    {
+        let t = parse_toolchain_spec(&spec).map_err(|()| Error::InvalidToolchain { spec })?;
+        return Ok((t, ToolchainSource::Environment));
+    }
 
-            let mut path = PathBuf::new();
-            let mut buf = Vec::new();
+    if let Some((dir, t)) = Overrides::load()?.find(dir)? {
+        return Ok((t, ToolchainSource::Override(dir)));
+    }
 
-            loop {
-                match encoded_path {
-                    &[ESC, a, b, ref rest @ ..] => {
-                        let x = u8::from_str_radix(str::from_utf8(&[a, b]).ok()?, 16).ok()?;
+    if toolchain_files_disabled() {
+        debug!("toolchain files are disabled by `{NO_TOOLCHAIN_FILE_VAR}`");
+    } else if let Some(path) = env::var_os(TOOLCHAIN_FILE_VAR).filter(|p| !p.is_empty()) {
+        let path = PathBuf::from(path);
 
-                        match x {
-                            ESC => buf.push(x),
-                            SEP => {
-                                path.push(OsStr::from_bytes(&buf));
-                                buf.clear();
-                            }
-                            _ => return None,
-                        }
-                        encoded_path = rest;
-                    }
-                    &[ESC, ..] => return None,
-                    &[fst, ref rest @ ..] => {
-                        buf.push(fst);
-                        encoded_path = rest;
-                    }
-                    [] => break,
-                }
-            }
+        // Unlike with the files we search for, this one was asked for, so it must exist
+        if !path.is_file() {
+            return Err(Error::InvalidToolchainFile {
+                path,
+                reason: format!("the file doesn't exist (it's set by `{TOOLCHAIN_FILE_VAR}`)"),
+            });
+        }
 
-            if !buf.is_empty() {
-                path.push(OsStr::from_bytes(&buf));
-                drop(buf);
-            }
+        return Ok((
+            toolchain_from_file(&path)?,
+            ToolchainSource::EnvironmentFile(path),
+        ));
+    } else if let Some(path) = find_toolchain_file(dir, config::get()?) {
+        return Ok((
+            toolchain_from_file(&path)?,
+            ToolchainSource::ToolchainFile(path),
+        ));
+    }
 
-            return Some(Self::File(path.into_boxed_path()));
-        }
+    Ok((default_toolchain()?, ToolchainSource::Default))
+}
 
-        if let Some(rest) = k.as_bytes().strip_prefix(b"external-") {
-            let rest = str::from_utf8(rest).ok()?;
-            let toolchain = match rest.split_once("-") {
-                Some((channel, version)) => ToolchainOverride::Version {
-                    channel: channel.parse().ok()?,
-                    version: Some(version.to_owned()),
-                },
-                None => ToolchainOverride::Version {
-                    channel: rest.parse().ok()?,
-                    version: None,
-                },
-            };
+/// Returns the [key](ToolchainOverride::key) of the toolchain `toolchain` stands for, i.e. the
+/// same for [`ToolchainOverride::None`] and the toolchain it's replaced with.
+pub fn resolved_key(toolchain: &ToolchainOverride) -> Result<OsString, Error> {
+    Ok(toolchain.clone().resolve_default()?.key())
+}
 
-            return Some(toolchain);
-        }
+/// Returns path to the file which stores the default toolchain set by the user.
+///
+/// The file contains the [key](ToolchainOverride::key) of the toolchain.
+fn default_toolchain_file() -> Result<PathBuf, Error> {
+    Ok(config::rustdn_home()?.join("default"))
+}
 
-        if k.as_bytes() == b"default" {
-            return Some(ToolchainOverride::None);
+/// Returns the toolchain used when nothing overrides it.
+///
+/// This is [`ToolchainOverride::None`] (i.e. [`Config::default_toolchain`]), unless the user
+/// changed it with [`set_default_toolchain`].
+pub fn default_toolchain() -> Result<ToolchainOverride, Error> {
+    let path = default_toolchain_file()?;
+
+    let key = match fs::read(&path) {
+        Ok(key) => key,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ToolchainOverride::None),
+        Err(e) => return Err(e).with_path(path),
+    };
+
+    ToolchainOverride::from_key(OsString::from_vec(key)).ok_or_else(|| {
+        Error::InvalidToolchainFile {
+            path,
+            reason: "couldn't parse the default toolchain".to_owned(),
         }
+    })
+}
 
-        None
+/// Changes the default toolchain (see [`default_toolchain`]).
+///
+/// Setting it to [`ToolchainOverride::None`] resets it to the built-in default.
+pub fn set_default_toolchain(toolchain: &ToolchainOverride) -> Result<(), Error> {
+    let path = default_toolchain_file()?;
+
+    if let ToolchainOverride::None = toolchain {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).with_path(path),
+            _ => Ok(()),
+        };
     }
 
-    /// Returns `true` if the cached version of the toolchain for this override can be trusted.
-    /// Or, in other words, that the toolchain version can be solely determined on input
-    /// parameters/cache key, so the cached version can't change.
-    ///
-    /// For [`File`] this checks if the toolchain file we used before is exactly the same as the current one.
-    /// For [`Version`] this checks that [`Version::version`] is specified.
-    /// [`None`] can never depend on cache.
-    ///
-    /// [`File`]: ToolchainOverride::File
-    /// [`Version`]: ToolchainOverride::Version
-    /// [`Version::version`]: ToolchainOverride::Version::version
-    ///
-    /// **N.B.**: you still need to check that the cache actually exists.
-    fn cache_is_valid(
-        &self,
-        path: &Path,
-        _lock: &Lock<impl Deref<Target = fs::File>, impl Sized>,
-    ) -> bool {
-        match self {
-            ToolchainOverride::File(current) => {
-                let current_contents = fs::read(current).unwrap();
-                let Ok(cached_contents) = fs::read(path.join("rust-toolchain.toml")) else {
-                    return false;
-                };
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).with_path(dir)?;
 
-                current_contents == cached_contents
-            }
+    fs::write(&path, toolchain.key().as_bytes()).with_path(path)
+}
+
+/// Returns path to the directory with all the cached toolchains (which may or may not exist).
+pub fn toolchains_dir() -> Result<PathBuf, Error> {
+    Ok(config::cache_dir()?.join("toolchains"))
+}
 
-            ToolchainOverride::Version { version, .. } => version.is_some(),
+/// Returns path to the directory with links to local toolchains (which may or may not exist).
+///
+/// Each link is a symlink named after the toolchain, pointing to the toolchain directory.
+pub fn links_dir() -> Result<PathBuf, Error> {
+    Ok(config::rustdn_home()?.join("links"))
+}
+
+/// Returns path to the directory with lock files of cached toolchains (which may or may not exist).
+///
+/// Locks are kept separately from the cache directories, so that removing a cache directory
+/// never removes a lock someone might be holding (or waiting on).
+fn locks_dir() -> Result<PathBuf, Error> {
+    Ok(config::cache_dir()?.join("locks"))
+}
+
+/// An opened lock file, see [`open_lock`].
+struct LockFile {
+    file: fs::File,
+    path: PathBuf,
+    /// `false` if the cache is read-only, so the lock file could only be opened for reading, i.e.
+    /// only shared locks can be taken (and the toolchain can't be updated anyway).
+    writable: bool,
+}
+
+/// Opens (creating it if necessary) the lock file of the cached toolchain in `toolchain_dir`.
+///
+/// Each cached toolchain has its own lock, `locks/<key>`. Lock files are never removed (they are
+/// empty, so they don't take any space), since removing a lock file which someone else has
+/// opened would let two processes "hold" the same lock.
+///
+/// If the cache (see [`config::cache_dir`]) is read-only (e.g. a pre-provisioned cache in a
+/// sandbox), the lock file is opened for reading only. If it doesn't exist (and can't be
+/// created), nobody can update the toolchain, so the toolchain directory itself is "locked"
+/// instead (shared locks on directories work, see the `lock` module).
+fn open_lock(toolchain_dir: &Path) -> Result<LockFile, Error> {
+    let locks_dir = locks_dir()?;
+    // `toolchain_dir` is always `toolchains/<key>`
+    let path = locks_dir.join(toolchain_dir.file_name().unwrap());
 
-            // FIXME: If we could force somehow that update of the default toolchain causes the
-            //        `toolchains/default` cache to be deleted, then we could actually trust this
-            //        (and similarly for version-less version spec).
-            //        Jono says it's possible, but I'm not sure how.
-            ToolchainOverride::None => false,
+    let opened = fs::create_dir_all(&locks_dir).and_then(|()| {
+        fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+    });
+
+    match opened {
+        Ok(file) => {
+            return Ok(LockFile {
+                file,
+                path,
+                writable: true,
+            })
         }
+        Err(e) if !is_read_only(&e) => return Err(e).with_path(&path),
+        Err(_) => debug!(
+            "`{}` is read-only, only taking shared locks",
+            path.display()
+        ),
     }
 
-    /// Commits the new toolchain to cache.
-    ///
-    /// Returns [`ControlFlow::Continue`] if the cache should be re-checked.
-    /// Returns [`ControlFlow::Break`] if the cache mustn't be rechecked.
-    fn commit_cache(
-        &self,
-        toolchain_dir: &PathBuf,
-        _lock: &mut Lock<impl Deref<Target = fs::File>, Exclusive>,
-    ) -> ControlFlow<()> {
-        match self {
-            ToolchainOverride::File(p) => {
-                fs::copy(p, toolchain_dir.join("rust-toolchain.toml")).unwrap();
-                ControlFlow::Continue(())
-            }
-            ToolchainOverride::Version {
-                version: Some(_), ..
-            } => ControlFlow::Continue(()),
+    let (file, path) = match fs::File::open(&path) {
+        Ok(file) => (file, path),
+        Err(_) => {
+            let file = fs::File::open(toolchain_dir).with_path(toolchain_dir)?;
+            (file, toolchain_dir.to_owned())
+        }
+    };
 
-            // These never say that the cache is valid, so there is no reason to re-check it after `nix-build`
-            ToolchainOverride::None | ToolchainOverride::Version { version: None, .. } => {
-                ControlFlow::Break(())
+    Ok(LockFile {
+        file,
+        path,
+        writable: false,
+    })
+}
+
+/// Returns `true` if `e` means that we can't write to the file (or the file system it's on).
+fn is_read_only(e: &io::Error) -> bool {
+    use rustix::io::Errno;
+
+    matches!(
+        Errno::from_io_error(e),
+        Some(Errno::ROFS | Errno::ACCESS | Errno::PERM)
+    )
+}
+
+/// Returns all toolchains in the cache, along with their cache directories.
+///
+/// Entries which are not toolchain directories are ignored.
+pub fn cached_toolchains() -> Result<Vec<(ToolchainOverride, PathBuf)>, Error> {
+    let toolchains_dir = toolchains_dir()?;
+
+    let dir = match fs::read_dir(&toolchains_dir) {
+        Ok(dir) => dir,
+        // Nothing was ever installed
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_path(&toolchains_dir),
+    };
+    let mut toolchains = Vec::new();
+
+    for res in dir {
+        match res {
+            Ok(entry) => {
+                let name = entry.file_name();
+                if let Some(toolchain) = ToolchainOverride::from_key(name) {
+                    toolchains.push((toolchain, entry.path()));
+                }
+                // FIXME: log if there is a non-toolchain file?
             }
+            Err(err) => eprintln!(
+                "error while reading `{}` directory: {err}",
+                toolchains_dir.display()
+            ),
         }
     }
+
+    Ok(toolchains)
 }
 
-#[derive(Debug, Clone, Copy)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
-pub enum Channel {
-    Stable,
-    Beta,
-    Nightly,
+/// Returns names of all local toolchains, along with the paths they link to.
+pub fn linked_toolchains() -> Result<Vec<(String, PathBuf)>, Error> {
+    let links_dir = links_dir()?;
+    let links = match fs::read_dir(&links_dir) {
+        Ok(dir) => dir,
+        // Nothing was ever linked
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_path(&links_dir),
+    };
+    let mut linked = Vec::new();
+
+    for res in links {
+        let entry = res.with_path(&links_dir)?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let target = fs::read_link(entry.path()).with_path(entry.path())?;
+
+        linked.push((name, target));
+    }
+
+    Ok(linked)
 }
 
-impl Channel {
-    fn as_str(self) -> &'static str {
-        match self {
-            Channel::Stable => "stable",
-            Channel::Beta => "beta",
-            Channel::Nightly => "nightly",
-        }
+/// Links a local toolchain at `path` (e.g. a rustc checkout build), so that it can be used
+/// as `+<name>`.
+///
+/// If there already is a link with the same name, it is replaced.
+pub fn link_toolchain(name: &str, path: &Path) -> Result<(), Error> {
+    if !is_valid_link_name(name) {
+        return Err(Error::Usage(format!(
+            "`{name}` can't be used as a toolchain name \
+             (it must not be a channel, a version or a path)"
+        )));
+    }
+
+    if !has_rustc(path) {
+        return Err(Error::InvalidLink {
+            name: name.to_owned(),
+            path: path.to_owned(),
+        });
+    }
+
+    let links_dir = links_dir()?;
+    fs::create_dir_all(&links_dir).with_path(&links_dir)?;
+
+    let link = links_dir.join(name);
+    match fs::remove_file(&link) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_path(link),
     }
+
+    std::os::unix::fs::symlink(path, &link).with_path(link)
 }
 
-impl FromStr for Channel {
-    type Err = ();
+/// Returns the path the local toolchain `name` links to.
+fn resolve_link(name: &str) -> Result<PathBuf, Error> {
+    let link = links_dir()?.join(name);
+    let path = fs::read_link(&link).with_path(&link)?;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "stable" => Ok(Channel::Stable),
-            "beta" => Ok(Channel::Beta),
-            "nightly" => Ok(Channel::Nightly),
-            _ => Err(()),
-        }
+    // The linked toolchain might have been moved or deleted since it was linked
+    if !has_rustc(&path) {
+        return Err(Error::InvalidLink {
+            name: name.to_owned(),
+            path,
+        });
     }
+
+    Ok(path)
 }
 
-impl fmt::Display for Channel {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(self.as_str())
+/// Returns `true` if `name` can be used as a name of a local toolchain,
+/// i.e. if it can't be confused with other toolchain specifications.
+fn is_valid_link_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "default"
+        && !name.starts_with(['.', '/', '+'])
+        && !name.contains(['/', ','])
+        && parse_builtin_toolchain_spec(name).is_err()
+}
+
+/// Returns path to the cache directory of `toolchain` (which may or may not exist).
+///
+/// Fails if the key of the toolchain is not a single path component, since the directory would
+/// then be outside of the cache (and it's removed if building the toolchain fails). Keys are
+/// never like this, unless something (e.g. parsing of versions) is badly broken.
+fn toolchain_dir(toolchain: &ToolchainOverride) -> Result<PathBuf, Error> {
+    let key = toolchain.key();
+    if key.is_empty() || key == "." || key == ".." || key.as_bytes().contains(&b'/') {
+        return Err(Error::InvalidToolchain {
+            spec: toolchain.to_string(),
+        });
     }
+
+    Ok(toolchains_dir()?.join(key))
 }
 
-pub fn parse_toolchain_override(s: Option<&str>) -> Result<Option<ToolchainOverride>, ()> {
-    let Some(s) = s else { return Ok(None) };
+/// Returns path to a cached toolchain, if there is one.
+///
+/// Unlike [`get_or_update_toolchain`] this never builds anything (and never creates any files,
+/// other than the lock file of a cached toolchain), so this can be used to inspect the state of
+/// the cache.
+///
+/// **N.B.**: the cached toolchain may be outdated, i.e. [`get_or_update_toolchain`] might still
+/// decide to update it.
+pub fn find_cached_toolchain(toolchain: &ToolchainOverride) -> Result<Option<PathBuf>, Error> {
+    let toolchain = &toolchain.clone().resolve_default()?;
 
-    let Some(s) = s.strip_prefix('+') else {
-        return Ok(None);
-    };
+    // Linked toolchains are not cached, they are always "installed"
+    if let ToolchainOverride::LocalName(name) = toolchain {
+        return resolve_link(name).map(Some);
+    }
 
-    if let Some(s) = s.strip_prefix("stable") {
-        let version = parse_toolchain_version(s)?;
-        return Ok(Some(ToolchainOverride::Version {
-            channel: Channel::Stable,
-            version,
-        }));
+    let toolchain_dir = toolchain_dir(toolchain)?;
+    if !toolchain_dir.exists() {
+        return Ok(None);
     }
 
-    if let Some(s) = s.strip_prefix("beta") {
-        let version = parse_toolchain_version(s)?;
-        return Ok(Some(ToolchainOverride::Version {
-            channel: Channel::Beta,
-            version,
-        }));
+    let LockFile {
+        file: lock_file,
+        path: lock_path,
+        ..
+    } = open_lock(&toolchain_dir)?;
+    let _lock = crate::lock::lock_shared_timeout(&lock_file, LOCK_TIMEOUT)
+        .map_err(|source| lock_error(lock_path, source))?;
+
+    Ok(toolchain_link_exists(&toolchain_dir).then(|| toolchain_dir.join("toolchain")))
+}
+
+/// Removes the cached `toolchain`.
+///
+/// This waits for an exclusive lock, so that we don't remove a toolchain from under someone who
+/// is currently updating it.
+pub fn remove_toolchain(toolchain: &ToolchainOverride) -> Result<(), Error> {
+    // "Removing" a linked toolchain just removes the link (the toolchain itself is not ours)
+    if let ToolchainOverride::LocalName(name) = toolchain {
+        let link = links_dir()?.join(name);
+        return fs::remove_file(&link).with_path(link);
     }
 
-    if let Some(s) = s.strip_prefix("nightly") {
-        let version = parse_toolchain_version(s)?;
-        return Ok(Some(ToolchainOverride::Version {
-            channel: Channel::Nightly,
-            version,
-        }));
+    let toolchain_dir = toolchain_dir(toolchain)?;
+    if !toolchain_dir.exists() {
+        return Err(Error::ToolchainNotInstalled {
+            toolchain: toolchain.to_string(),
+        });
     }
 
-    // Invalid toolchain override specification
-    Err(())
+    let LockFile {
+        file: lock_file,
+        path: lock_path,
+        ..
+    } = open_lock(&toolchain_dir)?;
+    let to_lock_error = |source| lock_error(lock_path.clone(), source);
+
+    let mut backoff = UpgradeBackoff::new();
+    let lock = loop {
+        let lock =
+            crate::lock::lock_shared_timeout(&lock_file, LOCK_TIMEOUT).map_err(to_lock_error)?;
+
+        match lock.upgrade() {
+            Ok(l) => break l,
+            // Someone else is trying to upgrade (i.e. update the toolchain),
+            // let them, and then remove what they've built.
+            Err(e) if e == rustix::io::Errno::DEADLK => backoff.wait().map_err(to_lock_error)?,
+            Err(e) => return Err(to_lock_error(e)),
+        }
+    };
+
+    remove_locked(&toolchain_dir, &lock)
 }
 
-fn parse_toolchain_version(s: &str) -> Result<Option<String>, ()> {
-    if s.is_empty() {
-        return Ok(None);
+/// Removes the cached toolchain in `toolchain_dir`, unless someone is using it right now
+/// (i.e. holds its lock), in which case `false` is returned.
+///
+/// Unlike [`remove_toolchain`] this never waits for the lock.
+pub fn try_remove_cached(toolchain_dir: &Path) -> Result<bool, Error> {
+    let LockFile {
+        file: lock_file,
+        path: lock_path,
+        ..
+    } = open_lock(toolchain_dir)?;
+    let to_lock_error = |source| lock_error(lock_path.clone(), source);
+
+    let Some(lock) = crate::lock::try_lock_shared(&lock_file).map_err(to_lock_error)? else {
+        return Ok(false);
+    };
+    let Ok(lock) = lock.try_upgrade().map_err(to_lock_error)? else {
+        return Ok(false);
+    };
+
+    remove_locked(toolchain_dir, &lock)?;
+
+    Ok(true)
+}
+
+/// Removes the cached toolchain in `toolchain_dir`, while holding its lock.
+fn remove_locked(
+    toolchain_dir: &Path,
+    _lock: &Lock<impl Deref<Target = fs::File>, Exclusive>,
+) -> Result<(), Error> {
+    // Removing the out-link also removes the gc root,
+    // so the toolchain itself will be deleted by the next `nix-store --gc`.
+    let link = toolchain_dir.join("toolchain");
+    match fs::remove_file(&link) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_path(link),
     }
 
-    s.strip_prefix("-").map(str::to_owned).map(Some).ok_or(())
+    // N.B.: the lock file is kept (see `open_lock`).
+    //       Caches created by older versions have it inside, removing it is fine, nothing uses it.
+    fs::remove_dir_all(toolchain_dir).with_path(toolchain_dir)
 }
 
-pub fn find_toolchain_file() -> Result<Option<ToolchainOverride>, ()> {
-    let current_dir = current_dir().map_err(drop)?;
+/// Name of the file (in the toolchain cache directory), whose modification time is the time
+/// the toolchain was last updated (for toolchains which update in place, like `nightly`).
+const UPDATED_FILE: &str = "updated";
 
-    iter::successors(Some(&*current_dir), |d| d.parent())
-        .map(|d| d.join("rust-toolchain.toml"))
-        .find(|f| f.exists())
-        .map(PathBuf::into_boxed_path)
-        .map(ToolchainOverride::File)
-        .apply(Ok)
+/// Returns `true` if the toolchain in `toolchain_dir` was updated less than `ttl`
+/// (i.e. [`Config::cache_ttl`]) ago (see [`UPDATED_FILE`]).
+fn updated_recently(toolchain_dir: &Path, ttl: Duration) -> bool {
+    let updated = fs::metadata(toolchain_dir.join(UPDATED_FILE)).and_then(|m| m.modified());
+
+    // N.B.: `elapsed` fails if the time is in the future, then just check for updates
+    updated.is_ok_and(|t| t.elapsed().is_ok_and(|elapsed| elapsed < ttl))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Returns `true` if the toolchain file at `path` pins a version (`channel = "1.78.0"`,
+/// `channel = "nightly-2024-05-01"`, ...), i.e. the toolchain built from it can't change unless
+/// the file does. Files with `channel = "stable"` and similar update in place.
+fn file_pins_version(path: &Path) -> Result<bool, Error> {
+    let channel = toolchain_file::parse(path)?.channel;
+
+    let pinned = channel
+        .as_deref()
+        .map(parse_builtin_toolchain_spec)
+        .is_some_and(|t| {
+            matches!(
+                t,
+                Ok(ToolchainOverride::Version {
+                    version: Some(_),
+                    ..
+                })
+            )
+        });
+
+    Ok(pinned)
+}
+
+/// Name of the file (in the toolchain cache directory) with a copy of the toolchain file,
+/// which the cached toolchain was built from (for [`ToolchainOverride::File`]).
+const TOOLCHAIN_FILE_COPY: &str = "rust-toolchain.toml";
+
+/// Writes `contents` to `path`, such that readers either see the old file, or the new one
+/// (even if we are interrupted in the middle).
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, contents).with_path(&tmp)?;
+    fs::rename(&tmp, path).with_path(path)
+}
+
+/// Result of [`check_toolchain`].
+#[derive(Debug)]
+pub enum UpdateCheck {
+    /// The toolchain never changes (e.g. it has a specific version), so there is nothing to check.
+    Pinned,
+    /// The cached toolchain is the latest one.
+    UpToDate,
+    /// [`get_or_update_toolchain`] would replace `current` with `latest`.
+    Available { current: PathBuf, latest: PathBuf },
+}
+
+/// Checks if there is an update for the cached `toolchain`, without building or downloading it.
+///
+/// This only evaluates the nix expression of the toolchain (with `nix-instantiate`) and compares
+/// the resulting store path with the cached one. Only a shared lock is taken and the cache is
+/// never modified.
+///
+/// Returns `None` if the toolchain is not cached.
+pub fn check_toolchain(toolchain: &ToolchainOverride) -> Result<Option<UpdateCheck>, Error> {
+    // Linked toolchains are managed by the user
+    if let ToolchainOverride::LocalName(_) = toolchain {
+        return Ok(Some(UpdateCheck::Pinned));
+    }
+
+    let toolchain_dir = toolchain_dir(toolchain)?;
+    if !toolchain_dir.exists() {
+        return Ok(None);
+    }
+
+    let LockFile {
+        file: lock_file,
+        path: lock_path,
+        ..
+    } = open_lock(&toolchain_dir)?;
+    let lock = crate::lock::lock_shared_timeout(&lock_file, LOCK_TIMEOUT)
+        .map_err(|source| lock_error(lock_path, source))?;
+
+    if !toolchain_link_exists(&toolchain_dir) {
+        return Ok(None);
+    }
+
+    // N.B.: toolchains which update in place are checked even if their cache is still trusted
+    let ttl = config::get()?.cache_ttl();
+    if !toolchain.updates_in_place() && toolchain.cache_is_valid(&toolchain_dir, &lock, ttl)? {
+        return Ok(Some(UpdateCheck::Pinned));
+    }
+
+    let link = toolchain_dir.join("toolchain");
+    let current = fs::read_link(&link).with_path(&link)?;
+    let latest = eval_toolchain(toolchain)?;
+
+    let check = if current == latest {
+        UpdateCheck::UpToDate
+    } else {
+        UpdateCheck::Available { current, latest }
+    };
+
+    Ok(Some(check))
+}
+
+/// Returns the store path of `toolchain`, i.e. what [`get_or_update_toolchain`] would return,
+/// except that this never builds or downloads the toolchain (but nix might still need to fetch
+/// nixpkgs and rust-overlay to evaluate it).
+///
+/// In offline mode only cached toolchains have a known store path.
+pub fn toolchain_store_path(toolchain: &ToolchainOverride) -> Result<PathBuf, Error> {
+    let toolchain = toolchain.clone().resolve_default()?;
+
+    if let ToolchainOverride::LocalName(name) = &toolchain {
+        return resolve_link(name).map(|path| store_path(&path));
+    }
+
+    if offline() {
+        return match find_cached_toolchain(&toolchain)? {
+            Some(path) => Ok(store_path(&path)),
+            None => Err(Error::Offline {
+                toolchain: toolchain.to_string(),
+            }),
+        };
+    }
+
+    eval_toolchain(&toolchain)
+}
+
+/// Returns the store path `nix-build` would produce for `toolchain`, without building it.
+fn eval_toolchain(toolchain: &ToolchainOverride) -> Result<PathBuf, Error> {
+    toolchain.validate()?;
+
+    let config = config::get()?;
+
+    // `nix-build` calls the function automatically, `nix-instantiate --eval` doesn't
+    let expr = format!("(({}) {{}}).outPath", toolchain.nix_expr(config));
+
+    let nix = config.nix_command();
+    let mut command = nix_eval_command(nix, &config.extra_nix_args()?, &expr);
+
+    debug!("starting {command:?}");
+
+    let output = command
+        .stdin(Stdio::null())
+        // Let the errors (and the fetching progress) through
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|source| nix_spawn_error(command.get_program(), source))?;
+
+    let path = str::from_utf8(&output.stdout)
+        .ok()
+        .map(str::trim)
+        .and_then(|s| match nix {
+            // The path is printed as a nix string, e.g. `"/nix/store/...-rust-default-1.80.0"`
+            NixCommand::Legacy => s.strip_prefix('"')?.strip_suffix('"'),
+            // `--raw`
+            NixCommand::New => Some(s),
+        })
+        .filter(|s| !s.is_empty() && output.status.success());
+
+    match path {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Err(Error::NixEval {
+            status: output.status,
+        }),
+    }
+}
+
+/// Returns `true` if the toolchain (out-link) in `toolchain_dir` exists and points to a
+/// store path with an executable `bin/rustc`.
+///
+/// If the link is dangling (the store path was garbage collected or deleted manually), or the
+/// toolchain is broken in some other way, this returns `false`, so that the toolchain is
+/// transparently rebuilt (instead of proxies failing to `exec` a missing binary).
+fn toolchain_link_exists(toolchain_dir: &Path) -> bool {
+    let link = toolchain_dir.join("toolchain");
+
+    if has_rustc(&link) {
+        return true;
+    }
+
+    if link.symlink_metadata().is_ok() {
+        warn!(
+            "toolchain `{}` points to a store path which doesn't exist anymore \
+             (or doesn't have `bin/rustc`), it will be rebuilt",
+            link.display()
+        );
+    }
+
+    false
+}
+
+/// Returns `true` if `toolchain` has an executable `bin/rustc`.
+pub fn has_rustc(toolchain: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    // N.B.: `metadata` follows symlinks (toolchains built by nix are mostly symlinks)
+    fs::metadata(toolchain_bin(toolchain, "rustc"))
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Returns path to the binary `bin` in the `toolchain` returned by [`get_or_update_toolchain`].
+pub fn toolchain_bin(toolchain: &Path, bin: &str) -> PathBuf {
+    toolchain
+        // directory with the binaries
+        .join("bin")
+        // the binary itself
+        .join(bin)
+}
+
+/// Returns the name of the component which provides `tool`, if `tool` is known to not be
+/// included in minimal toolchains.
+pub fn component_for_tool(tool: &str) -> Option<&'static str> {
+    match tool {
+        "rustfmt" | "cargo-fmt" => Some("rustfmt"),
+        "cargo-clippy" | "clippy-driver" => Some("clippy"),
+        "rust-analyzer" => Some("rust-analyzer"),
+        "miri" | "cargo-miri" => Some("miri"),
+        _ => None,
+    }
+}
+
+/// Returns the version of the `toolchain` (the output of `rustc --version`), or `None` if it
+/// can't be determined.
+///
+/// For cached toolchains (as returned by [`find_cached_toolchain`]) the version recorded in
+/// [`ToolchainMeta`] is used, without running any binaries.
+pub fn toolchain_version(toolchain: &Path) -> Option<String> {
+    match read_meta(toolchain) {
+        Some(meta) => meta.rustc_version,
+        None => rustc_version(toolchain),
+    }
+}
+
+/// Name of the file (in the toolchain cache directory) with [`ToolchainMeta`].
+const META_FILE: &str = "meta.toml";
+
+/// Information about a cached toolchain, recorded when it's built.
+///
+/// N.B.: unknown fields are ignored and all of the fields have defaults, so that newer and older
+///       versions of `rustdn` can read each other's metadata.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ToolchainMeta {
+    /// The output of `rustc --version`.
+    #[serde(default)]
+    pub rustc_version: Option<String>,
+    /// When the toolchain was built, in seconds since the unix epoch.
+    #[serde(default)]
+    pub installed: u64,
+    /// The store path of the toolchain.
+    #[serde(default)]
+    pub store_path: PathBuf,
+}
+
+/// Records [`ToolchainMeta`] of the toolchain which was just built into `toolchain_dir`.
+fn write_meta(toolchain_dir: &Path) -> Result<(), Error> {
+    let link = toolchain_dir.join("toolchain");
+
+    let meta = ToolchainMeta {
+        rustc_version: rustc_version(&link),
+        installed: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        store_path: fs::read_link(&link).with_path(&link)?,
+    };
+
+    // Can only fail for non-UTF-8 store paths, which nix doesn't produce
+    let Ok(contents) = toml::to_string(&meta) else {
+        return Ok(());
+    };
+
+    write_atomically(&toolchain_dir.join(META_FILE), contents.as_bytes())
+}
+
+/// Reads [`ToolchainMeta`] of a cached toolchain (`<toolchain dir>/toolchain`).
+///
+/// Returns `None` if there is no metadata, or if it's stale (describes a different store path).
+fn read_meta(toolchain: &Path) -> Option<ToolchainMeta> {
+    if toolchain.file_name()? != "toolchain" {
+        return None;
+    }
+
+    let contents = fs::read_to_string(toolchain.with_file_name(META_FILE)).ok()?;
+    let meta: ToolchainMeta = toml::from_str(&contents).ok()?;
+
+    // e.g. the toolchain was updated by an older version of `rustdn`, which doesn't know about
+    // metadata, or the update was interrupted
+    (fs::read_link(toolchain).ok()? == meta.store_path).then_some(meta)
+}
+
+/// Returns the command which evaluates `expr` (to a string) with `nix`.
+///
+/// `extra_args` (see [`Config::extra_nix_args`]) are passed before the expression.
+fn nix_eval_command(nix: NixCommand, extra_args: &[String], expr: &str) -> Command {
+    match nix {
+        NixCommand::Legacy => Command::new("nix-instantiate").also(|c| {
+            c.arg("--eval").args(extra_args).args(["--expr", expr]);
+        }),
+        NixCommand::New => Command::new("nix").also(|c| {
+            c.args(NIX_COMMAND_FLAGS)
+                .args(["eval", "--impure", "--raw"])
+                .args(extra_args)
+                .args(["--expr", expr]);
+        }),
+    }
+}
+
+/// Returns `true` if `<nixpkgs>` can be found in the nix search path (`NIX_PATH`, or `-I` in
+/// [`Config::extra_nix_args`]).
+///
+/// This is checked (by evaluating `<nixpkgs>`) at most once per process. Warns if there is no
+/// `<nixpkgs>`, since the fallback nixpkgs (see [`Config::nixpkgs_expr`]) is used then.
+pub fn nixpkgs_in_search_path(config: &Config) -> bool {
+    static FOUND: OnceLock<bool> = OnceLock::new();
+
+    *FOUND.get_or_init(|| {
+        // If nix is not installed (or the arguments are invalid), building the toolchain is
+        // going to report that
+        let found = find_nixpkgs(config).map_or(true, |path| path.is_some());
+
+        if !found {
+            warn!(
+                "`<nixpkgs>` is not in the nix search path (`NIX_PATH`), using the fallback \
+                 nixpkgs (`nixpkgs_fallback_url` in the config) instead"
+            );
+        }
+
+        found
+    })
+}
+
+/// Returns the path `<nixpkgs>` refers to, or `None` if it's not in the nix search path.
+///
+/// Unlike [`nixpkgs_in_search_path`], this is not cached and doesn't warn.
+pub fn find_nixpkgs(config: &Config) -> Result<Option<String>, Error> {
+    // N.B.: `toString`, so that nixpkgs is not copied to the store
+    let path = quiet_nix_eval(config, "builtins.toString <nixpkgs>")?;
+
+    // `nix-instantiate` prints strings quoted
+    Ok(path.map(|path| path.trim_matches('"').to_owned()))
+}
+
+/// Returns `true` if nix can evaluate a trivial expression, i.e. nix works at all.
+pub fn nix_can_evaluate(config: &Config) -> bool {
+    quiet_nix_eval(config, "1 + 1").is_ok_and(|result| result.as_deref() == Some("2"))
+}
+
+/// Evaluates `expr` with nix, without showing any of its output.
+///
+/// Returns the (trimmed) result, `None` if the evaluation failed, or an error if nix couldn't be
+/// run at all (e.g. it's not installed).
+fn quiet_nix_eval(config: &Config, expr: &str) -> Result<Option<String>, Error> {
+    let mut command = nix_eval_command(config.nix_command(), &config.extra_nix_args()?, expr);
+
+    debug!("starting {command:?}");
+
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|source| nix_spawn_error(command.get_program(), source))?;
+
+    let result = String::from_utf8(output.stdout)
+        .ok()
+        .filter(|_| output.status.success());
+
+    Ok(result.map(|s| s.trim().to_owned()))
+}
+
+/// Returns the command which builds `expr` (a function, see [`ToolchainOverride::nix_expr`])
+/// with `nix`, linking the result to `out_link`.
+///
+/// `extra_args` (see [`Config::extra_nix_args`]) are passed before the expression.
+fn nix_build_command(
+    nix: NixCommand,
+    extra_args: &[String],
+    out_link: &Path,
+    expr: &str,
+) -> Command {
+    match nix {
+        NixCommand::Legacy => Command::new("nix-build").also(|c| {
+            c.arg("--out-link")
+                .arg(out_link)
+                .args(extra_args)
+                .args(["--expr", expr]);
+        }),
+        // `nix build` doesn't call functions automatically
+        NixCommand::New => Command::new("nix").also(|c| {
+            c.args(NIX_COMMAND_FLAGS)
+                .args(["build", "--impure", "--out-link"])
+                .arg(out_link)
+                .args(extra_args)
+                .arg("--expr")
+                .arg(format!("({expr}) {{}}"));
+        }),
+    }
+}
+
+/// Flags for the new nix CLI, which make it work even if it's not enabled in the nix config.
+const NIX_COMMAND_FLAGS: [&str; 2] = ["--extra-experimental-features", "nix-command"];
+
+/// Creates an error for a nix `program` which couldn't be started.
+fn nix_spawn_error(program: impl AsRef<OsStr>, source: io::Error) -> Error {
+    let program = program.as_ref();
+
+    if source.kind() == io::ErrorKind::NotFound {
+        return Error::NixNotFound {
+            program: program.to_string_lossy().into_owned(),
+        };
+    }
+
+    Error::Spawn {
+        program: program.into(),
+        source,
+    }
+}
+
+/// Returns the output of `rustc --version` of the `toolchain`, or `None` if it can't be run.
+fn rustc_version(toolchain: &Path) -> Option<String> {
+    let output = Command::new(toolchain_bin(toolchain, "rustc"))
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|v| v.trim().to_owned())
+}
+
+/// Returns path to a toolchain directory somewhere in nix store.
+///
+/// In offline mode (see [`OFFLINE_VAR`]) this never runs `nix-build`: a cached toolchain is used
+/// even if it might be outdated, and a missing toolchain is an error.
+///
+/// Each toolchain is only resolved once per process, later calls return the same store path
+/// (without checking the cache again), even if the cache was updated in the meantime.
+pub fn get_or_update_toolchain(toolchain: ToolchainOverride) -> Result<PathBuf, Error> {
+    // Cached under the key of the toolchain it stands for, so that e.g. `+nightly` and the default
+    // toolchain (if it's configured to be `nightly`) share the cache
+    let toolchain = toolchain.resolve_default()?;
+
+    memoized(toolchain.key(), || {
+        resolve_uncached(toolchain, false).map(|path| store_path(&path))
+    })
+}
+
+/// Rebuilds `toolchain` even if its cache is still trusted, i.e. updates it to the latest
+/// version, and returns the out-link in the cache (installing the toolchain, if it's not
+/// cached).
+///
+/// Only toolchains which [update in place](ToolchainOverride::updates_in_place) (version-less
+/// channels and the default toolchain) are rebuilt, `None` is returned for the rest, since they
+/// never change.
+///
+/// Unlike [`get_or_update_toolchain`] this always resolves the toolchain again, and must not be
+/// used in offline mode.
+pub fn update_toolchain(toolchain: &ToolchainOverride) -> Result<Option<PathBuf>, Error> {
+    let toolchain = toolchain.clone().resolve_default()?;
+    if !toolchain.updates_in_place() {
+        return Ok(None);
+    }
+
+    resolve_uncached(toolchain, true).map(Some)
+}
+
+/// Returns the path resolved for `key` earlier in this process, or resolves it with `resolve`.
+fn memoized(
+    key: OsString,
+    resolve: impl FnOnce() -> Result<PathBuf, Error>,
+) -> Result<PathBuf, Error> {
+    /// Paths of the toolchains resolved by [`get_or_update_toolchain`], by their keys.
+    static RESOLVED: Mutex<BTreeMap<OsString, PathBuf>> = Mutex::new(BTreeMap::new());
+
+    if let Some(path) = RESOLVED.lock().unwrap().get(&key) {
+        return Ok(path.clone());
+    }
+
+    // N.B.: the mutex is not held while resolving, building a toolchain can take a while.
+    //       If someone resolved the same toolchain in the meantime, the first result wins.
+    let path = resolve()?;
+
+    Ok(RESOLVED.lock().unwrap().entry(key).or_insert(path).clone())
+}
+
+/// [`get_or_update_toolchain`], without the memoization. Returns the out-link in the cache.
+///
+/// With `force` the toolchain is rebuilt, even if the cache is valid (see [`update_toolchain`]).
+///
+/// Everything happens inside of a `resolve_toolchain` span, with debug events for each step
+/// (with the time `elapsed` since the start), i.e. `RUSTDN_LOG=debug` shows where the time goes.
+fn resolve_uncached(toolchain: ToolchainOverride, mut force: bool) -> Result<PathBuf, Error> {
+    let _span = info_span!("resolve_toolchain", key = %toolchain.key().to_string_lossy()).entered();
+    let start = Instant::now();
+
+    if offline() {
+        // N.B.: this only takes a shared lock (to wait for anyone who is currently updating the
+        //       toolchain), offline mode never upgrades to an exclusive lock, since it never
+        //       updates anything. This also doesn't create the cache directory.
+        let path = find_cached_toolchain(&toolchain)?.ok_or_else(|| Error::Offline {
+            toolchain: toolchain.to_string(),
+        })?;
+
+        if !matches!(toolchain, ToolchainOverride::LocalName(_)) {
+            touch_last_used(&toolchain_dir(&toolchain)?);
+        }
+
+        return Ok(path);
+    }
+
+    // Linked toolchains are managed by the user, there is nothing to build
+    if let ToolchainOverride::LocalName(name) = &toolchain {
+        return resolve_link(name);
+    }
+
+    let toolchain_dir = toolchain_dir(&toolchain)?;
+
+    let read_only_error = || match config::cache_dir() {
+        Ok(path) => Error::ReadOnlyCache {
+            toolchain: toolchain.to_string(),
+            path,
+        },
+        Err(e) => e,
+    };
+
+    match fs::create_dir_all(&toolchain_dir) {
+        Ok(()) => {}
+        Err(e) if is_read_only(&e) => return Err(read_only_error()),
+        Err(e) => return Err(e).with_path(&toolchain_dir),
+    }
+
+    let LockFile {
+        file: lock_file,
+        path: lock_path,
+        writable,
+    } = open_lock(&toolchain_dir)?;
+    let to_lock_error = |source| lock_error(lock_path.clone(), source);
+
+    let mut lock =
+        crate::lock::lock_shared_timeout(&lock_file, LOCK_TIMEOUT).map_err(to_lock_error)?;
+
+    debug!(elapsed = ?start.elapsed(), "acquired shared lock");
+
+    let ttl = config::get()?.cache_ttl();
+    let mut backoff = UpgradeBackoff::new();
+    loop {
+        if !force
+            && toolchain_link_exists(&toolchain_dir)
+            && toolchain.cache_is_valid(&toolchain_dir, &lock, ttl)?
+        {
+            debug!(elapsed = ?start.elapsed(), "cache hit");
+
+            // we are free
+            break;
+        }
+
+        debug!(elapsed = ?start.elapsed(), "cache miss");
+
+        // A read-only cache can't be updated, but an outdated toolchain is better than none, as
+        // long as it's the right one (e.g. it's built from the current toolchain file)
+        if !writable {
+            if !force
+                && toolchain_link_exists(&toolchain_dir)
+                && toolchain.cache_matches(&toolchain_dir, &lock)?
+            {
+                debug!("the cache is read-only, using the (possibly outdated) toolchain");
+                break;
+            }
+
+            return Err(read_only_error());
+        }
+
+        let mut exclusive = match lock.upgrade() {
+            Ok(l) => l,
+            Err(e) if e == rustix::io::Errno::DEADLK => {
+                // DEADLK error is returned when multiple readers are trying to upgrade.
+                // it's returned to all, but one, processes.
+
+                // a delay to make sure the one process that didn't get the error can actually get an exclusive lock.
+                backoff.wait().map_err(to_lock_error)?;
+
+                lock = crate::lock::lock_shared_timeout(&lock_file, LOCK_TIMEOUT)
+                    .map_err(to_lock_error)?;
+                continue;
+            }
+            Err(e) => return Err(to_lock_error(e)),
+        };
+
+        debug!(elapsed = ?start.elapsed(), "acquired exclusive lock");
+
+        toolchain.validate()?;
+
+        // The cache might have been removed while we were waiting for the lock
+        // (the lock itself lives outside of it, see `open_lock`)
+        fs::create_dir_all(&toolchain_dir).with_path(&toolchain_dir)?;
+        toolchain.invalidate_cache(&toolchain_dir, &mut exclusive)?;
+
+        let config = config::get()?;
+        let expr = toolchain.nix_expr(config);
+
+        // Link the result into our cache (instead of creating `./result` symlinks).
+        // N.B.: `nix-build` registers out-links as indirect gc roots
+        //       (in `/nix/var/nix/gcroots/auto`), so `nix-store --gc` won't delete the
+        //       toolchain for as long as the link exists. "uninstalling" is just removing the
+        //       link (the gc root then goes away automatically).
+        //       in case the toolchain does get deleted anyway (i.e. the link is dangling),
+        //       see `toolchain_link_exists`.
+        let mut retries = 0;
+        let build = loop {
+            let mut command = nix_build_command(
+                config.nix_command(),
+                &config.extra_nix_args()?,
+                &toolchain_dir.join("toolchain"),
+                &expr,
+            );
+
+            debug!(elapsed = ?start.elapsed(), ?command, "nix-build started");
+
+            let build = run_nix_build(&mut command, config)?;
+
+            debug!(elapsed = ?start.elapsed(), status = %build.status, "nix-build finished");
+
+            if build.status.success() || !build.transient || retries >= config.build_retries() {
+                break build;
+            }
+
+            retries += 1;
+            let delay = build_retry_delay(retries);
+            warn!(
+                "building the toolchain failed, because of what looks like a network problem, \
+                 retrying in {}s (retry {retries} of {})",
+                delay.as_secs(),
+                config.build_retries()
+            );
+            thread::sleep(delay);
+        };
+        let status = build.status;
+
+        // Very important: fail if `nix-build` failed.
+        // This *must* happen before we commit to the cache,
+        // since otherwise we might create an invalid cache and go insane.
+        if !status.success() {
+            for line in build.quiet_output {
+                eprintln!("{}", String::from_utf8_lossy(&line));
+            }
+
+            // Just to be safe (and, well, correct for non-file toolchains),
+            // remove the cache entirely.
+            fs::remove_dir_all(&toolchain_dir).with_path(&toolchain_dir)?;
+
+            return Err(Error::NixBuild { status });
+        }
+
+        // Otherwise it would be cached, and proxies would fail to run it with a confusing error
+        if !has_rustc(&toolchain_dir.join("toolchain")) {
+            fs::remove_dir_all(&toolchain_dir).with_path(&toolchain_dir)?;
+
+            return Err(Error::BuildWithoutRustc { expr });
+        }
+
+        if let Some(expected) = toolchain.expected_hash() {
+            let link = toolchain_dir.join("toolchain");
+            let actual = fs::read_link(&link).with_path(&link)?;
+
+            if store_hash(&actual) != Some(expected) {
+                fs::remove_dir_all(&toolchain_dir).with_path(&toolchain_dir)?;
+
+                return Err(Error::StoreHashMismatch {
+                    toolchain: toolchain.to_string(),
+                    actual,
+                });
+            }
+        }
+
+        // Only the first build is forced, if the cache needs to be re-checked after the commit
+        // (see `commit_cache`), this build is as new as it gets
+        force = false;
+
+        let committed = toolchain.commit_cache(&toolchain_dir, &mut exclusive)?;
+
+        debug!(elapsed = ?start.elapsed(), "committed");
+
+        if let ControlFlow::Break(()) = committed {
+            break;
+        }
+
+        // Re-check the cache without giving anyone a chance to update it in-between.
+        lock = exclusive.downgrade().map_err(to_lock_error)?;
+    }
+
+    touch_last_used(&toolchain_dir);
+
+    Ok(toolchain_dir.join("toolchain"))
+}
+
+/// Delay before the first retry of a build which failed because of a transient problem (see
+/// [`Config::is_transient_error`]), the delay doubles with each retry.
+const BUILD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Maximal delay between build retries, see [`build_retry_delay`].
+const BUILD_RETRY_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Returns the delay before the `retry`th (starting at 1) retry of a build, i.e.
+/// [`BUILD_RETRY_DELAY`] doubled for each previous retry, up to [`BUILD_RETRY_DELAY_MAX`].
+fn build_retry_delay(retry: u32) -> Duration {
+    let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+    BUILD_RETRY_DELAY
+        .saturating_mul(factor)
+        .min(BUILD_RETRY_DELAY_MAX)
+}
+
+/// The result of [`run_nix_build`].
+struct NixBuild {
+    status: ExitStatus,
+    /// `true` if nix reported an error which is likely to go away on its own
+    /// (see [`Config::is_transient_error`]).
+    transient: bool,
+    /// The output of nix, if it wasn't printed because of `--quiet` (see [`config::quiet`]).
+    quiet_output: Vec<Vec<u8>>,
+}
+
+/// Runs the `nix-build` `command`, forwarding its progress to stderr (with a throbber).
+fn run_nix_build(command: &mut Command, config: &Config) -> Result<NixBuild, Error> {
+    let nix_build = command.get_program().to_owned();
+
+    // Show that *something* is happening, toolchain downloads can take a while.
+    let throbber = Throbber::start("Building toolchain...");
+
+    let mut child = command
+        // `nix-build` prints the resulting store path to stdout, we don't need it.
+        .stdout(Stdio::null())
+        // Progress is reported to stderr, forward it live, so that users can see what is
+        // being downloaded/built.
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| nix_spawn_error(&nix_build, source))?;
+
+    // With `--quiet` the output is only shown if the build fails
+    let quiet = config::quiet();
+    let mut quiet_output = Vec::new();
+    let mut transient = false;
+
+    let mut fetch = FetchProgress::default();
+
+    let nix_stderr = BufReader::new(child.stderr.take().unwrap());
+    for line in nix_stderr.split(b'\n') {
+        let line = line.with_path(&nix_build)?;
+        let text = String::from_utf8_lossy(&line);
+        transient |= config.is_transient_error(&text);
+
+        if fetch.update(&text) {
+            throbber.set_progress(fetch.fetched, fetch.paths, &fetch.unit());
+        }
+
+        if quiet {
+            quiet_output.push(line);
+        } else {
+            throbber.println(&line);
+        }
+    }
+
+    let status = child.wait().with_path(&nix_build)?;
+
+    Ok(NixBuild {
+        status,
+        transient,
+        quiet_output,
+    })
+}
+
+/// How far along nix is in fetching store paths, parsed from its output.
+///
+/// nix prints which paths it's going to fetch (with the total size), and then a line for each
+/// path it starts copying, but not the progress of the individual downloads, so the progress is
+/// counted in paths. This is best-effort: if the output is not recognized (e.g. the format
+/// changed, or everything is built instead), there is just no progress to show.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FetchProgress {
+    /// Number of paths nix said it's going to fetch.
+    paths: usize,
+    /// Number of paths nix started to copy.
+    fetched: usize,
+    /// The total download size, as nix printed it (e.g. `45.67 MiB`).
+    download: Option<String>,
+    /// `true` while nix lists the paths it's going to fetch.
+    listing: bool,
+}
+
+impl FetchProgress {
+    /// Updates the progress with a `line` of nix output, returns `true` if it changed.
+    ///
+    /// The relevant lines look like this (older versions of nix don't print the number of paths,
+    /// so the listed paths are counted instead):
+    ///
+    /// ```text
+    /// these 3 paths will be fetched (45.67 MiB download, 312.34 MiB unpacked):
+    ///   /nix/store/...-rust-std-1.80.0-x86_64-unknown-linux-gnu
+    ///   ...
+    /// copying path '/nix/store/...-rust-std-1.80.0-x86_64-unknown-linux-gnu' from 'https://cache.nixos.org'...
+    /// ```
+    fn update(&mut self, line: &str) -> bool {
+        if self.listing {
+            if line.trim_start().starts_with('/') && line.starts_with(char::is_whitespace) {
+                self.paths += 1;
+                return true;
+            }
+
+            self.listing = false;
+        }
+
+        let is_header = (line.starts_with("these ") && line.contains(" paths will be fetched"))
+            || line.starts_with("this path will be fetched");
+        if is_header {
+            self.listing = true;
+            // e.g. `(45.67 MiB download, 312.34 MiB unpacked):`
+            let download = line
+                .split_once('(')
+                .and_then(|(_, sizes)| sizes.split_once(" download"))
+                .map(|(download, _)| download.to_owned());
+            // N.B.: nix can fetch paths in several batches, the sizes are only shown for the
+            //       first one
+            if self.download.is_none() {
+                self.download = download;
+            }
+
+            return false;
+        }
+
+        if line.starts_with("copying path '") && self.fetched < self.paths {
+            self.fetched += 1;
+            return true;
+        }
+
+        false
+    }
+
+    /// The unit for [`Throbber::set_progress`].
+    fn unit(&self) -> String {
+        match &self.download {
+            Some(download) => format!("paths ({download} download)"),
+            None => "paths".to_owned(),
+        }
+    }
+}
+
+/// Name of the file (in the toolchain cache directory), whose modification time is the time
+/// the toolchain was last used.
+const LAST_USED_FILE: &str = "last_used";
+
+/// Records that the toolchain in `toolchain_dir` was just used.
+///
+/// This is best-effort: failing to record the time is not a reason to fail running a tool.
+///
+/// N.B.: this is done on every successful resolution, including the fast path, where only
+///       a shared lock is held. This is fine, since nothing else reads or writes this file
+///       under an exclusive lock (and concurrent bumps are all equally good).
+fn touch_last_used(toolchain_dir: &Path) {
+    let path = toolchain_dir.join(LAST_USED_FILE);
+    let res = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .and_then(|f| f.set_modified(SystemTime::now()));
+
+    if let Err(e) = res {
+        debug!("couldn't update `{}`: {e}", path.display());
+    }
+}
+
+/// Returns when the cached toolchain in `toolchain_dir` was last used
+/// (or when it was built, if it wasn't used since).
+pub fn last_used(toolchain_dir: &Path) -> Option<SystemTime> {
+    fs::metadata(toolchain_dir.join(LAST_USED_FILE))
+        // N.B.: this is the time the link was created, i.e. when the toolchain was built
+        .or_else(|_| fs::symlink_metadata(toolchain_dir.join("toolchain")))
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub enum ToolchainOverride {
+    File(Box<Path>),
+    Version {
+        channel: Channel,
+        version: Option<String>,
+        extras: Extras,
+    },
+    None,
+    /// A local toolchain, linked with `rustdn toolchain link` (see [`link_toolchain`]).
+    LocalName(String),
+}
+
+impl ToolchainOverride {
+    // N.B. all function here must agree with each other.
+
+    /// Returns the key the toolchain is cached under (the name of its cache directory), which
+    /// can be parsed back with [`ToolchainOverride::from_key`].
+    pub fn key(&self) -> OsString {
+        match self {
+            ToolchainOverride::File(f) => {
+                let mut key = OsString::from("file-");
+
+                // FIXME: figure out an encoding for paths which is less cursed
+
+                const ESC: u8 = 0x10;
+                f.as_os_str().as_encoded_bytes().iter().for_each(|&b| {
+                    if b.is_ascii() && b != ESC && b != b'/' {
+                        key.push(str::from_utf8(slice::from_ref(&b)).unwrap())
+                    } else {
+                        key.push(format!("\x10{b:x}"))
+                    }
+                });
+
+                key
+            }
+            // N.B.: version-less channels (`external-nightly`) are "global" keys, which are
+            //       updated in place, while specific versions (`pinned-nightly-2024-05-01`) never
+            //       change once built.
+            ToolchainOverride::Version {
+                channel,
+                version,
+                extras,
+            } => {
+                let mut key = match version {
+                    Some(version) => format!("pinned-{channel}-{version}"),
+                    None => format!("external-{channel}"),
+                };
+                key.push_str(&extras.key_suffix());
+
+                key.into()
+            }
+            // N.B.: the default toolchain is cached under the key of the toolchain it stands for
+            //       (see `resolve_default`), this is only used by caches of older versions
+            ToolchainOverride::None => "default".to_owned().into(),
+            // N.B.: linked toolchains are not cached, but it's still nice to have a key for them
+            ToolchainOverride::LocalName(name) => format!("link-{name}").into(),
+        }
+    }
+
+    /// Returns `true` if the toolchain can change without its specification changing,
+    /// i.e. for version-less channels (like `nightly`, unless a hash is specified) and the
+    /// default toolchain.
+    fn updates_in_place(&self) -> bool {
+        match self {
+            ToolchainOverride::None => true,
+            ToolchainOverride::Version {
+                version: None,
+                extras,
+                ..
+            } => extras.hash.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Returns the hash the store path of the toolchain must have (see [`Extras::hash`]).
+    fn expected_hash(&self) -> Option<&str> {
+        match self {
+            ToolchainOverride::Version { extras, .. } => extras.hash.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn from_key(k: OsString) -> Option<Self> {
+        if let Some(mut encoded_path) = k.as_bytes().strip_prefix(b"file-") {
+            const ESC: u8 = 0x10;
+
+            // N.B.: the path is decoded as raw bytes (rather than pushing components to a
+            //       `PathBuf`), so that leading separators (i.e. absolute paths) survive.
+            let mut path = Vec::new();
+
+            loop {
+                match encoded_path {
+                    &[ESC, a, b, ref rest @ ..] => {
+                        // `key` never produces single digit escapes
+                        let x = u8::from_str_radix(str::from_utf8(&[a, b]).ok()?, 16).ok()?;
+                        if x < 0x10 {
+                            return None;
+                        }
+
+                        path.push(x);
+                        encoded_path = rest;
+                    }
+                    &[ESC, ..] => return None,
+                    &[fst, ref rest @ ..] => {
+                        path.push(fst);
+                        encoded_path = rest;
+                    }
+                    [] => break,
+                }
+            }
+
+            let path = PathBuf::from(OsString::from_vec(path));
+            return Some(Self::File(path.into_boxed_path()));
+        }
+
+        let channel_key = match k.as_bytes().strip_prefix(b"pinned-") {
+            Some(rest) => Some((true, rest)),
+            None => k
+                .as_bytes()
+                .strip_prefix(b"external-")
+                .map(|rest| (false, rest)),
+        };
+        if let Some((pinned, rest)) = channel_key {
+            let rest = str::from_utf8(rest).ok()?;
+            let (rest, extras) = match rest.split_once(',') {
+                Some((rest, suffix)) => (rest, Extras::from_key_suffix(suffix)?),
+                None => (rest, Extras::default()),
+            };
+            // N.B.: `external-<channel>-<version>` is the legacy key of versioned toolchains.
+            //       Such caches are not used anymore (the toolchains are built again under the
+            //       new key), but they are still listed, so that `gc` can clean them up.
+            let toolchain = match rest.split_once("-") {
+                Some((channel, version)) => {
+                    let channel = channel.parse().ok()?;
+                    if !is_valid_version(channel, version) {
+                        return None;
+                    }
+
+                    ToolchainOverride::Version {
+                        channel,
+                        version: Some(version.to_owned()),
+                        extras,
+                    }
+                }
+                None if !pinned => ToolchainOverride::Version {
+                    channel: rest.parse().ok()?,
+                    version: None,
+                    extras,
+                },
+                _ => return None,
+            };
+
+            return Some(toolchain);
+        }
+
+        if k.as_bytes() == b"default" {
+            return Some(ToolchainOverride::None);
+        }
+
+        if let Some(name) = k.as_bytes().strip_prefix(b"link-") {
+            let name = str::from_utf8(name).ok()?;
+            return Some(ToolchainOverride::LocalName(name.to_owned()));
+        }
+
+        None
+    }
+
+    /// Adds `extras` to the toolchain.
+    ///
+    /// Toolchain files specify their components and targets themselves, so this fails for [`File`] overrides
+    /// (unless `extras` are empty).
+    ///
+    /// [`File`]: ToolchainOverride::File
+    pub fn with_extras(self, extras: Extras) -> Result<Self, Error> {
+        if extras.is_empty() {
+            return Ok(self);
+        }
+
+        match self {
+            ToolchainOverride::File(path) => Err(Error::Usage(format!(
+                "components, targets and profiles can't be added to a toolchain file (`{}`), edit the file instead",
+                path.display()
+            ))),
+            ToolchainOverride::LocalName(name) => Err(Error::Usage(format!(
+                "components, targets and profiles can't be added to a linked toolchain (`{name}`)"
+            ))),
+            ToolchainOverride::Version {
+                channel,
+                version,
+                extras: mut current,
+            } => {
+                current.components.extend(extras.components);
+                current.targets.extend(extras.targets);
+                if extras.profile != Profile::Default {
+                    current.profile = extras.profile;
+                }
+                if extras.hash.is_some() {
+                    current.hash = extras.hash;
+                }
+
+                Ok(ToolchainOverride::Version {
+                    channel,
+                    version,
+                    extras: current,
+                })
+            }
+            ToolchainOverride::None => config::get()?.default_toolchain().with_extras(extras),
+        }
+    }
+
+    /// Replaces [`ToolchainOverride::None`] with the toolchain it stands for
+    /// (see [`Config::default_toolchain`]).
+    fn resolve_default(self) -> Result<Self, Error> {
+        match self {
+            ToolchainOverride::None => Ok(config::get()?.default_toolchain()),
+            toolchain => Ok(toolchain),
+        }
+    }
+
+    /// Checks that nix will (most likely) be able to build the toolchain,
+    /// to report mistakes in toolchain files nicer than nix would.
+    fn validate(&self) -> Result<(), Error> {
+        if let ToolchainOverride::File(path) = self {
+            toolchain_file::parse(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a nix expression (a function, for `nix-build --expr`) which builds the toolchain.
+    fn nix_expr(&self, config: &Config) -> String {
+        let toolchain = match self {
+            ToolchainOverride::File(f) => {
+                format!(
+                    "fromRustupToolchainFile {}",
+                    nix_string(&f.to_string_lossy())
+                )
+            }
+            ToolchainOverride::Version {
+                channel,
+                version,
+                extras,
+            } => format!(
+                "{}.{}.{}{}",
+                channel.as_str(),
+                nix_string(version.as_deref().unwrap_or("latest")),
+                extras.profile,
+                extras.nix_override()
+            ),
+            ToolchainOverride::None => return config.default_toolchain().nix_expr(config),
+            ToolchainOverride::LocalName(_) => unreachable!("linked toolchains are not built"),
+        };
+
+        format!(
+            "{{}}: (import {} {{overlays = [(import {})];}}).rust-bin.{toolchain}",
+            config.nixpkgs_expr(),
+            config.rust_overlay_expr()
+        )
+    }
+
+    /// Returns `true` if the cached version of the toolchain for this override can be trusted.
+    /// Or, in other words, that the toolchain version can be solely determined on input
+    /// parameters/cache key, so the cached version can't change.
+    ///
+    /// For [`File`] this checks if the toolchain file we used before is exactly the same as the current one.
+    /// For [`Version`] with [`Version::version`] specified the cache is always valid.
+    /// Toolchains which update in place ([`None`], [`Version`] without a version and toolchain
+    /// files which don't pin a version, see [`file_pins_version`]) are trusted for `ttl`
+    /// (i.e. [`Config::cache_ttl`]) after they were last updated.
+    ///
+    /// [`File`]: ToolchainOverride::File
+    /// [`Version`]: ToolchainOverride::Version
+    /// [`Version::version`]: ToolchainOverride::Version::version
+    ///
+    /// **N.B.**: you still need to check that the cache actually exists.
+    fn cache_is_valid(
+        &self,
+        path: &Path,
+        lock: &Lock<impl Deref<Target = fs::File>, impl Sized>,
+        ttl: Duration,
+    ) -> Result<bool, Error> {
+        let valid = match self {
+            ToolchainOverride::File(current) => {
+                self.cache_matches(path, lock)?
+                    && (file_pins_version(current)? || updated_recently(path, ttl))
+            }
+
+            ToolchainOverride::Version {
+                version: Some(_), ..
+            } => true,
+
+            // The hash is checked when the toolchain is built, so it's always the same one
+            ToolchainOverride::Version { .. } if self.expected_hash().is_some() => true,
+
+            // These can change at any moment (e.g. every day for `nightly`), but checking for
+            // updates on every invocation is slow, so they are trusted for a while after an update.
+            ToolchainOverride::None | ToolchainOverride::Version { version: None, .. } => {
+                updated_recently(path, ttl)
+            }
+
+            // Linked toolchains never use the cache
+            ToolchainOverride::LocalName(_) => true,
+        };
+
+        Ok(valid)
+    }
+
+    /// Returns `true` if the cached toolchain was built from the same inputs as this override, i.e.
+    /// it might be outdated, but it's the right toolchain (unlike e.g. a toolchain built from an
+    /// old version of a toolchain file).
+    ///
+    /// **N.B.**: you still need to check that the cache actually exists.
+    fn cache_matches(
+        &self,
+        path: &Path,
+        _lock: &Lock<impl Deref<Target = fs::File>, impl Sized>,
+    ) -> Result<bool, Error> {
+        let ToolchainOverride::File(current) = self else {
+            return Ok(true);
+        };
+
+        let current_contents = fs::read(current).with_path(current)?;
+        let Ok(cached_contents) = fs::read(path.join(TOOLCHAIN_FILE_COPY)) else {
+            return Ok(false);
+        };
+
+        Ok(current_contents == cached_contents)
+    }
+
+    /// Invalidates the cache before the toolchain is (re)built.
+    ///
+    /// This makes sure that an update which was interrupted after `nix-build` has replaced
+    /// the toolchain, but before [`ToolchainOverride::commit_cache`], doesn't leave behind
+    /// metadata describing the old toolchain (which could then be trusted for the new one).
+    fn invalidate_cache(
+        &self,
+        toolchain_dir: &Path,
+        _lock: &mut Lock<impl Deref<Target = fs::File>, Exclusive>,
+    ) -> Result<(), Error> {
+        let metadata = match self {
+            ToolchainOverride::File(_) => TOOLCHAIN_FILE_COPY,
+            ToolchainOverride::None | ToolchainOverride::Version { version: None, .. } => {
+                UPDATED_FILE
+            }
+
+            // The toolchain itself is the only state
+            ToolchainOverride::Version {
+                version: Some(_), ..
+            }
+            | ToolchainOverride::LocalName(_) => return Ok(()),
+        };
+
+        let path = toolchain_dir.join(metadata);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_path(path),
+        }
+    }
+
+    /// Commits the new toolchain to cache.
+    ///
+    /// Metadata is written atomically, so the cache is either valid for the new toolchain,
+    /// or (if we are interrupted) invalid.
+    ///
+    /// Returns [`ControlFlow::Continue`] if the cache should be re-checked.
+    /// Returns [`ControlFlow::Break`] if the cache mustn't be rechecked.
+    fn commit_cache(
+        &self,
+        toolchain_dir: &Path,
+        _lock: &mut Lock<impl Deref<Target = fs::File>, Exclusive>,
+    ) -> Result<ControlFlow<()>, Error> {
+        // Linked toolchains are not built by us
+        if !matches!(self, ToolchainOverride::LocalName(_)) {
+            write_meta(toolchain_dir)?;
+        }
+
+        let flow = match self {
+            ToolchainOverride::File(p) => {
+                let pinned = file_pins_version(p)?;
+                if !pinned {
+                    write_atomically(&toolchain_dir.join(UPDATED_FILE), b"")?;
+                }
+
+                let contents = fs::read(p).with_path(p)?;
+                write_atomically(&toolchain_dir.join(TOOLCHAIN_FILE_COPY), &contents)?;
+
+                // Same as for the overrides below
+                if pinned {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            }
+            ToolchainOverride::Version {
+                version: Some(_), ..
+            } => ControlFlow::Continue(()),
+
+            // Remember when the toolchain was updated (see `cache_is_valid`).
+            // N.B.: the cache is trusted only for a while, so there is no reason to re-check it
+            //       after `nix-build`.
+            ToolchainOverride::None | ToolchainOverride::Version { version: None, .. } => {
+                write_atomically(&toolchain_dir.join(UPDATED_FILE), b"")?;
+                ControlFlow::Break(())
+            }
+
+            // Never cached
+            ToolchainOverride::LocalName(_) => ControlFlow::Break(()),
+        };
+
+        Ok(flow)
+    }
+}
+
+impl fmt::Display for ToolchainOverride {
+    /// Formats the override the same way it would be specified after `+`
+    /// (or as a path for toolchain files), so [`parse_toolchain_spec`] parses it back.
+    ///
+    /// N.B.: targets and the profile can't be specified this way, so they are left out
+    ///       (see [`Extras`]'s `Display`).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolchainOverride::File(p) => write!(f, "{}", p.display()),
+            ToolchainOverride::Version {
+                channel,
+                version: Some(version),
+                extras,
+            } => write!(f, "{channel}-{version}{extras}"),
+            ToolchainOverride::Version {
+                channel,
+                version: None,
+                extras,
+            } => write!(f, "{channel}{extras}"),
+            ToolchainOverride::None => f.write_str("default"),
+            ToolchainOverride::LocalName(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Additional components and targets requested on top of a toolchain (its profile and, optionally,
+/// the store path it must have).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct Extras {
+    /// Components, like `rust-src` or `clippy` (rust-overlay calls these "extensions").
+    ///
+    /// These are sets, so that the order in which things are specified doesn't matter.
+    pub components: BTreeSet<String>,
+    /// Additional targets to include the standard library for, like `wasm32-unknown-unknown`.
+    pub targets: BTreeSet<String>,
+    /// The set of components to start with.
+    pub profile: Profile,
+    /// The hash the store path of the toolchain must have (`/nix/store/<hash>-...`), given as
+    /// `+<toolchain>@<hash>`.
+    ///
+    /// nix can't build a store path by its hash, so this doesn't change what is built, instead the
+    /// result is checked to be exactly this toolchain (and then never updated).
+    pub hash: Option<String>,
+}
+
+impl Extras {
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+            && self.targets.is_empty()
+            && self.profile == Profile::Default
+            && self.hash.is_none()
+    }
+
+    /// Returns the part of the cache key describing the extras (empty if there are none).
+    ///
+    /// N.B.: must agree with [`Extras::from_key_suffix`].
+    fn key_suffix(&self) -> String {
+        let mut suffix = String::new();
+        for c in &self.components {
+            suffix.push_str(",component=");
+            suffix.push_str(c);
+        }
+        for t in &self.targets {
+            suffix.push_str(",target=");
+            suffix.push_str(t);
+        }
+        // N.B.: the default profile is not a part of the key, so that keys of toolchains which
+        //       were cached before profiles were supported stay the same
+        if self.profile != Profile::Default {
+            suffix.push_str(",profile=");
+            suffix.push_str(self.profile.as_str());
+        }
+        if let Some(hash) = &self.hash {
+            suffix.push_str(",hash=");
+            suffix.push_str(hash);
+        }
+
+        suffix
+    }
+
+    /// Parses the output of [`Extras::key_suffix`] (without the leading `,`).
+    fn from_key_suffix(suffix: &str) -> Option<Self> {
+        let mut extras = Extras::default();
+
+        for part in suffix.split(',') {
+            match part.split_once('=')? {
+                ("component", c) if is_valid_extra_name(c) => {
+                    extras.components.insert(c.to_owned())
+                }
+                ("target", t) if is_valid_extra_name(t) => extras.targets.insert(t.to_owned()),
+                ("profile", p) => {
+                    extras.profile = p.parse().ok()?;
+                    true
+                }
+                ("hash", h) if is_store_hash(h) => extras.hash.replace(h.to_owned()).is_none(),
+                _ => return None,
+            };
+        }
+
+        Some(extras)
+    }
+
+    /// Returns a nix expression suffix which adds the extras to a rust-overlay toolchain
+    /// (empty if there are none).
+    ///
+    /// N.B.: the profile is not a part of this, see [`ToolchainOverride::nix_expr`].
+    fn nix_override(&self) -> String {
+        if self.components.is_empty() && self.targets.is_empty() {
+            return String::new();
+        }
+
+        fn list(set: &BTreeSet<String>) -> String {
+            let mut list = String::new();
+            for x in set {
+                list.push(' ');
+                list.push_str(&nix_string(x));
+            }
+
+            list
+        }
+
+        format!(
+            ".override {{ extensions = [{} ]; targets = [{} ]; }}",
+            list(&self.components),
+            list(&self.targets)
+        )
+    }
+}
+
+impl fmt::Display for Extras {
+    /// Formats the extras as a suffix for a toolchain spec, i.e. `+<component>...@<hash>`
+    /// (empty if there are none).
+    ///
+    /// Targets and the profile are only ever set with `--target`/`--profile`, there is no spec
+    /// syntax for them, so they are not included.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for component in &self.components {
+            write!(f, "+{component}")?;
+        }
+
+        // The hash goes last, `parse_toolchain_spec` splits it off first
+        if let Some(hash) = &self.hash {
+            write!(f, "@{hash}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A rust-overlay profile, i.e. the set of components a toolchain starts with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Just `rustc`, `cargo` and `rust-std`.
+    Minimal,
+    /// `minimal` with `rust-docs`, `rustfmt` and `clippy`.
+    #[default]
+    Default,
+    /// All the components (only really available on nightly).
+    Complete,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 3] = [Profile::Minimal, Profile::Default, Profile::Complete];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Profile::Minimal => "minimal",
+            Profile::Default => "default",
+            Profile::Complete => "complete",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Profile::ALL.into_iter().find(|p| p.as_str() == s).ok_or(())
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A rust release channel.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Stable, Channel::Beta, Channel::Nightly];
+
+    /// Returns an iterator over all the channels, i.e. [`Channel::ALL`].
+    pub fn iter() -> impl Iterator<Item = Channel> {
+        Self::ALL.into_iter()
+    }
+
+    /// Returns the name of the channel, as it's used in toolchain specs (`stable`, ...).
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Nightly => "nightly",
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "beta" => Ok(Channel::Beta),
+            "nightly" => Ok(Channel::Nightly),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses a toolchain given to a `rustdn` subcommand (or stored in `rustdn` configuration).
+///
+/// Unlike with proxies, the `+` is optional and paths to toolchain files are allowed.
+pub fn parse_toolchain_arg(arg: &str) -> Result<ToolchainOverride, Error> {
+    if arg == "default" {
+        return Ok(ToolchainOverride::None);
+    }
+
+    if let Ok(toolchain) = parse_toolchain_spec(arg.strip_prefix('+').unwrap_or(arg)) {
+        return Ok(toolchain);
+    }
+
+    let path = Path::new(arg);
+    if path.is_file() {
+        return toolchain_from_file(path);
+    }
+
+    Err(Error::InvalidToolchain {
+        spec: arg.to_owned(),
+    })
+}
+
+pub fn parse_toolchain_override(s: Option<&str>) -> Result<Option<ToolchainOverride>, ()> {
+    let Some(s) = s else { return Ok(None) };
+
+    let Some(s) = s.strip_prefix('+') else {
+        return Ok(None);
+    };
+
+    parse_toolchain_spec(s).map(Some)
+}
+
+/// Parses a toolchain specification, i.e. the `<...>` part of `+<...>`.
+///
+/// Channel based toolchains can be followed by components to add to the toolchain, separated by
+/// `+`, e.g. `nightly+rust-src` or `1.80+clippy+rust-analyzer`.
+pub fn parse_toolchain_spec(s: &str) -> Result<ToolchainOverride, ()> {
+    // Channels never start with `.` or `/`, so these are unambiguously paths
+    if s.starts_with(['.', '/']) {
+        return parse_toolchain_path(Path::new(s));
+    }
+
+    // `<toolchain>@<hash>` pins the store path of a channel based toolchain (see `Extras::hash`)
+    if let Some((spec, hash)) = s.split_once('@') {
+        if let Ok(toolchain @ ToolchainOverride::Version { .. }) = parse_toolchain_spec(spec) {
+            if !is_store_hash(hash) {
+                return Err(());
+            }
+
+            let extras = Extras {
+                hash: Some(hash.to_owned()),
+                ..Extras::default()
+            };
+
+            // Can't fail, `toolchain` is `Version`
+            return toolchain.with_extras(extras).map_err(drop);
+        }
+    }
+
+    if let Some((channel, components)) = s.split_once('+') {
+        if let Ok(toolchain) = parse_builtin_toolchain_spec(channel) {
+            let components = components.split('+').map(str::to_owned);
+            let extras = Extras {
+                components: components.collect(),
+                ..Extras::default()
+            };
+
+            if !extras.components.iter().all(|c| is_valid_extra_name(c)) {
+                return Err(());
+            }
+
+            // Can't fail, `toolchain` is `Version`
+            return toolchain.with_extras(extras).map_err(drop);
+        }
+    }
+
+    if let Ok(toolchain) = parse_builtin_toolchain_spec(s) {
+        return Ok(toolchain);
+    }
+
+    // Any other name might be a linked toolchain.
+    // N.B.: `symlink_metadata`, so that the error about broken links is reported later,
+    //       instead of "invalid toolchain override".
+    if is_valid_link_name(s)
+        && links_dir().is_ok_and(|links| links.join(s).symlink_metadata().is_ok())
+    {
+        return Ok(ToolchainOverride::LocalName(s.to_owned()));
+    }
+
+    // Invalid toolchain override specification
+    Err(())
+}
+
+/// Parses channel based toolchain specifications (`stable`, `nightly-2024-05-01`, `1.78`, ...).
+fn parse_builtin_toolchain_spec(s: &str) -> Result<ToolchainOverride, ()> {
+    if let Some(s) = s.strip_prefix("stable") {
+        let version = parse_toolchain_version(Channel::Stable, s)?;
+        return Ok(ToolchainOverride::Version {
+            channel: Channel::Stable,
+            version,
+            extras: Extras::default(),
+        });
+    }
+
+    if let Some(s) = s.strip_prefix("beta") {
+        // Betas are published daily, for the next release, and rust-overlay only has them by date
+        let version = parse_toolchain_version(Channel::Beta, s)?;
+
+        return Ok(ToolchainOverride::Version {
+            channel: Channel::Beta,
+            version,
+            extras: Extras::default(),
+        });
+    }
+
+    if let Some(s) = s.strip_prefix("nightly") {
+        let version = parse_toolchain_version(Channel::Nightly, s)?;
+        return Ok(ToolchainOverride::Version {
+            channel: Channel::Nightly,
+            version,
+            extras: Extras::default(),
+        });
+    }
+
+    // `+x.y.z` is a shorthand for `+stable-x.y.z`
+    if is_version_number(s) {
+        return Ok(ToolchainOverride::Version {
+            channel: Channel::Stable,
+            version: Some(s.to_owned()),
+            extras: Extras::default(),
+        });
+    }
+
+    // `+yyyy-mm-dd` is a shorthand for `+nightly-yyyy-mm-dd`
+    if looks_like_date(s) {
+        if !is_valid_date(s) {
+            return Err(());
+        }
+
+        return Ok(ToolchainOverride::Version {
+            channel: Channel::Nightly,
+            version: Some(s.to_owned()),
+            extras: Extras::default(),
+        });
+    }
+
+    // Invalid toolchain override specification
+    Err(())
+}
+
+/// Parses a path to a toolchain file, given as a toolchain specification.
+///
+/// Fails if the path doesn't exist or is not a `rust-toolchain[.toml]` file.
+fn parse_toolchain_path(path: &Path) -> Result<ToolchainOverride, ()> {
+    let is_toolchain_file = path.file_name() == Some(OsStr::new("rust-toolchain"))
+        || path.extension() == Some(OsStr::new("toml"));
+
+    if !is_toolchain_file || !path.is_file() {
+        return Err(());
+    }
+
+    // N.B.: the path is canonicalized, so the cache key is independent of the current directory
+    toolchain_from_file(path).map_err(drop)
+}
+
+/// Returns `true` if `s` is a hash of a store path, i.e. 32 characters of nix's base32.
+fn is_store_hash(s: &str) -> bool {
+    // N.B.: nix's base32 omits `e`, `o`, `t` and `u`
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+    s.len() == 32 && s.bytes().all(|b| ALPHABET.contains(&b))
+}
+
+/// Returns the hash part of a store path (`<hash>` in `/nix/store/<hash>-<name>`).
+fn store_hash(path: &Path) -> Option<&str> {
+    let (hash, _name) = path.file_name()?.to_str()?.split_once('-')?;
+    is_store_hash(hash).then_some(hash)
+}
+
+/// Returns `true` if `s` has the shape of `yyyy-mm-dd` (without checking that the date is valid).
+fn looks_like_date(s: &str) -> bool {
+    let s = s.as_bytes();
+
+    s.len() == 10
+        && s.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Returns `true` if `s` is a valid `yyyy-mm-dd` date.
+fn is_valid_date(s: &str) -> bool {
+    if !looks_like_date(s) {
+        return false;
+    }
+
+    // Can't fail, we checked that these are digits above
+    let year: u32 = s[0..4].parse().unwrap();
+    let month: u32 = s[5..7].parse().unwrap();
+    let day: u32 = s[8..10].parse().unwrap();
+
+    let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+
+    (1..=days_in_month).contains(&day)
+}
+
+/// Returns `true` if `s` is `x.y` or `x.y.z`, where `x`, `y` and `z` are decimal numbers.
+fn is_version_number(s: &str) -> bool {
+    let mut components = s.split('.');
+
+    (2..=3).contains(&components.clone().count())
+        && components.all(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Returns `true` if `s` can be the version of a `channel` based toolchain, i.e. one rust-overlay
+/// has: a release (`x.y[.z]`) of `stable`, or a valid `yyyy-mm-dd` date of `beta` and `nightly`.
+///
+/// N.B.: versions end up in cache keys (i.e. in paths) and in nix expressions, so nothing else
+///       (like `/`, `,` or `"`) may ever be accepted here.
+fn is_valid_version(channel: Channel, s: &str) -> bool {
+    match channel {
+        Channel::Stable => is_version_number(s),
+        Channel::Beta | Channel::Nightly => is_valid_date(s),
+    }
+}
+
+/// Returns `true` if `s` can be the name of a component or a target (`rust-src`,
+/// `wasm32-unknown-unknown`, ...), i.e. it's made of ASCII letters, digits, `_`, `.` and `-`.
+///
+/// N.B.: like versions (see [`is_valid_version`]), these end up in cache keys and nix expressions.
+pub fn is_valid_extra_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+}
+
+/// Parses the `-<version>` after the name of `channel` (`None` if there is no version).
+fn parse_toolchain_version(channel: Channel, s: &str) -> Result<Option<String>, ()> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+
+    match s.strip_prefix('-') {
+        Some(version) if is_valid_version(channel, version) => Ok(Some(version.to_owned())),
+        _ => Err(()),
+    }
+}
+
+/// Looks for a toolchain file in `dir` and its parents.
+///
+/// In each directory `rust-toolchain.toml` is preferred over the legacy `rust-toolchain`.
+/// The search stops after `max_ancestor_depth` parents, and (if enabled in `config`) at the root
+/// of the git repository `dir` is in, or before crossing into another filesystem.
+fn find_toolchain_file(dir: &Path, config: &Config) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let device = |d: &Path| fs::metadata(d).map(|m| m.dev()).ok();
+    let max_depth = config.max_ancestor_depth.unwrap_or(usize::MAX);
+    let mut previous_device = device(dir);
+
+    for (depth, d) in dir.ancestors().enumerate() {
+        if depth > max_depth {
+            trace!(max_depth, "reached `max_ancestor_depth`");
+            break;
+        }
+
+        if config.stop_at_filesystem_boundary {
+            let current_device = device(d);
+            if current_device != previous_device {
+                trace!(dir = %d.display(), "reached a filesystem boundary");
+                break;
+            }
+            previous_device = current_device;
+        }
+
+        let files = [d.join("rust-toolchain.toml"), d.join("rust-toolchain")];
+        if let Some(path) = files.into_iter().find(|f| f.exists()) {
+            debug!(path = %path.display(), depth, "found a toolchain file");
+            return Some(path);
+        }
+
+        if config.stop_at_vcs_root && d.join(".git").exists() {
+            trace!(dir = %d.display(), "reached the root of the git repository");
+            break;
+        }
+    }
+
+    trace!("no toolchain file found");
+    None
+}
+
+/// Returns the toolchain specified by a toolchain file at `path`.
+///
+/// Files which only specify a channel (like legacy `rust-toolchain` files with just
+/// `nightly-2023-01-01`, or TOML ones without components, targets and profile) are parsed into
+/// [`ToolchainOverride::Version`], so that they share the cache with `+<...>` overrides.
+/// Everything else is passed to rust-overlay as is.
+pub fn toolchain_from_file(path: &Path) -> Result<ToolchainOverride, Error> {
+    if path.file_name() == Some(OsStr::new("rust-toolchain")) {
+        let contents = fs::read_to_string(path).with_path(path)?;
+
+        // N.B.: the contents come from whatever repository we are in, `parse_toolchain_spec`
+        //       rejects anything which is not a valid toolchain (e.g. invalid versions, which
+        //       would end up in paths, see `is_valid_version`)
+        if let Some(channel) = legacy_channel(&contents) {
+            return parse_toolchain_spec(channel).map_err(|()| Error::InvalidToolchainFile {
+                path: path.to_owned(),
+                reason: format!("invalid toolchain `{channel}`"),
+            });
+        }
+    }
+
+    // N.B.: invalid files are reported by `ToolchainOverride::validate`
+    if let Ok(file) = toolchain_file::parse(path) {
+        if file.components.is_empty() && file.targets.is_empty() && file.profile.is_none() {
+            if let Some(Ok(toolchain)) = file.channel.as_deref().map(parse_builtin_toolchain_spec) {
+                return Ok(toolchain);
+            }
+        }
+    }
+
+    // Same file, same cache: `./rust-toolchain.toml`, `/src/x/../rust-toolchain.toml` and
+    // symlinks to it are all the same toolchain
+    let path = fs::canonicalize(path).with_path(path)?;
+
+    Ok(ToolchainOverride::File(path.into()))
+}
+
+/// Returns the channel specified by a legacy (non-TOML) toolchain file,
+/// or `None` if `contents` are not in the legacy format.
+fn legacy_channel(contents: &str) -> Option<&str> {
+    let mut lines = contents.lines().map(str::trim).filter(|l| !l.is_empty());
+
+    match (lines.next(), lines.next()) {
+        (Some(line), None) if !line.contains(['[', '=']) => Some(line),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+    use crate::unstd::TempDir;
 
     #[test]
     fn smoke() {
         assert_eq!(parse_toolchain_override(None), Ok(None));
         assert_eq!(parse_toolchain_override(Some("not-plus")), Ok(None));
         assert_eq!(
-            parse_toolchain_override(Some("+stable")),
-            Ok(Some(ToolchainOverride::Version {
+            parse_toolchain_override(Some("+stable")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Stable,
+                version: None,
+                extras: Extras::default(),
+            }))
+        );
+        assert_eq!(parse_toolchain_override(Some("+stable-")), Err(()));
+        assert_eq!(
+            parse_toolchain_override(Some("+stable-1.78")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Stable,
+                version: Some("1.78".to_owned()),
+                extras: Extras::default(),
+            }))
+        );
+    }
+
+    #[test]
+    fn stable_version_shorthand() {
+        assert_eq!(
+            parse_toolchain_override(Some("+1.78")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Stable,
+                version: Some("1.78".to_owned()),
+                extras: Extras::default(),
+            }))
+        );
+        assert_eq!(
+            parse_toolchain_override(Some("+1.78.0")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Stable,
+                version: Some("1.78.0".to_owned()),
+                extras: Extras::default(),
+            }))
+        );
+
+        assert_eq!(parse_toolchain_override(Some("+1")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+1.")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+.78")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+1..0")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+1.2.3.4")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+1.78.x")), Err(()));
+    }
+
+    #[test]
+    fn malicious_versions() {
+        // Versions end up in cache keys (and paths), and in nix expressions
+        for spec in [
+            "stable-../../../../victim",
+            "stable-1.80/..",
+            r#"stable-1.80"; builtins.abort "pwned"#,
+            "beta-1.80 ",
+            "stable-latest",
+        ] {
+            assert_eq!(parse_toolchain_spec(spec), Err(()), "{spec}");
+            let key = format!("pinned-{spec}");
+            assert_eq!(
+                ToolchainOverride::from_key(key.clone().into()),
+                None,
+                "{key}"
+            );
+        }
+
+        let dir = TempDir::new("malicious-versions");
+
+        // Toolchain files with such channels are left to rust-overlay
+        let file = dir.join("rust-toolchain.toml");
+        for channel in [
+            "stable-../../../../victim",
+            r#"nightly-2024-05-01\"; builtins.abort \"pwned"#,
+        ] {
+            fs::write(&file, format!("[toolchain]\nchannel = \"{channel}\"\n")).unwrap();
+            let toolchain = toolchain_from_file(&file).unwrap();
+            assert!(
+                matches!(toolchain, ToolchainOverride::File(_)),
+                "{toolchain:?}"
+            );
+        }
+
+        // Even if one is constructed by hand, it can't escape the cache or the nix string
+        let toolchain = ToolchainOverride::Version {
+            channel: Channel::Stable,
+            version: Some(r#"../../x"; builtins.abort "pwned"#.to_owned()),
+            extras: Extras::default(),
+        };
+        assert!(matches!(
+            toolchain_dir(&toolchain),
+            Err(Error::InvalidToolchain { .. })
+        ));
+        assert!(toolchain
+            .nix_expr(&Config::default())
+            .ends_with(r#".rust-bin.stable."../../x\"; builtins.abort \"pwned".default"#));
+    }
+
+    #[test]
+    fn toolchain_file_depth() {
+        let dir = TempDir::new("file-depth");
+        let deep = dir.join("a/b");
+        fs::create_dir_all(&deep).unwrap();
+
+        let file = dir.join("rust-toolchain.toml");
+        fs::write(&file, "[toolchain]\nchannel = \"stable\"\n").unwrap();
+
+        let find = |dir: &Path, max_ancestor_depth| {
+            let config = Config {
+                max_ancestor_depth,
+                ..Config::default()
+            };
+            find_toolchain_file(dir, &config)
+        };
+
+        assert_eq!(find(&deep, Some(2)), Some(file.clone()));
+        assert_eq!(find(&deep, None), Some(file.clone()));
+        assert_eq!(find(&deep, Some(1)), None);
+        assert_eq!(find(&dir, Some(0)), Some(file));
+    }
+
+    #[test]
+    fn toolchain_file_vcs_root() {
+        let dir = TempDir::new("file-vcs-root");
+        let repo = dir.join("repo");
+        let deep = repo.join("crates/foo");
+        fs::create_dir_all(&deep).unwrap();
+
+        // Outside of the repository
+        let file = dir.join("rust-toolchain.toml");
+        fs::write(&file, "[toolchain]\nchannel = \"stable\"\n").unwrap();
+
+        let stop = Config {
+            stop_at_vcs_root: true,
+            ..Config::default()
+        };
+
+        // Not a repository (yet), so nothing stops the search
+        assert_eq!(find_toolchain_file(&deep, &stop), Some(file.clone()));
+
+        fs::create_dir(repo.join(".git")).unwrap();
+        assert_eq!(find_toolchain_file(&deep, &stop), None);
+        assert_eq!(
+            find_toolchain_file(&deep, &Config::default()),
+            Some(file.clone())
+        );
+
+        // The root of the repository is still searched
+        let in_repo = repo.join("rust-toolchain");
+        fs::write(&in_repo, "nightly\n").unwrap();
+        assert_eq!(find_toolchain_file(&deep, &stop), Some(in_repo));
+
+        // Same filesystem
+        let boundary = Config {
+            stop_at_filesystem_boundary: true,
+            ..Config::default()
+        };
+        assert_eq!(find_toolchain_file(&dir, &boundary), Some(file));
+    }
+
+    #[test]
+    fn beta_versions() {
+        let beta = |version: Option<&str>| {
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Beta,
+                version: version.map(str::to_owned),
+                extras: Extras::default(),
+            }))
+        };
+
+        assert_eq!(parse_toolchain_override(Some("+beta")), beta(None));
+        assert_eq!(
+            parse_toolchain_override(Some("+beta-2024-05-01")),
+            beta(Some("2024-05-01"))
+        );
+
+        // rust-overlay only has betas by date
+        assert_eq!(parse_toolchain_override(Some("+beta-1.79.0")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta-1.79")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta-")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta-latest")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta-1.79.0.1")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta-2024-13-01")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+beta1.79")), Err(()));
+
+        // Same for the other channels, nightlies are dates, stable versions are releases
+        assert_eq!(parse_toolchain_override(Some("+nightly-1.79.0")), Err(()));
+        assert_eq!(
+            parse_toolchain_override(Some("+stable-2024-05-01")),
+            Err(())
+        );
+        assert_eq!(
+            ToolchainOverride::from_key("pinned-nightly-1.79.0".into()),
+            None
+        );
+
+        for (spec, hint) in [
+            ("+beta-1.79.0", "`beta-2024-05-01`"),
+            ("+nightly-1.79.0", "`nightly-2024-05-01`"),
+            ("+stable-2024-05-01", "`stable-1.79.0`"),
+        ] {
+            let error = resolve_toolchain_in(Some(spec), Path::new("/"))
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains(hint), "{error}");
+        }
+    }
+
+    #[test]
+    fn nightly_date_shorthand() {
+        assert_eq!(
+            parse_toolchain_override(Some("+2024-05-01")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Nightly,
+                version: Some("2024-05-01".to_owned()),
+                extras: Extras::default(),
+            }))
+        );
+        assert_eq!(
+            parse_toolchain_override(Some("+2024-02-29")),
+            Ok(Some(ToolchainOverride::Version {
+                channel: Channel::Nightly,
+                version: Some("2024-02-29".to_owned()),
+                extras: Extras::default(),
+            }))
+        );
+
+        assert_eq!(parse_toolchain_override(Some("+2024-13-40")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+2024-00-01")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+2023-02-29")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+2024-5-1")), Err(()));
+        assert_eq!(parse_toolchain_override(Some("+2024-05-01x")), Err(()));
+    }
+
+    #[test]
+    fn extras() {
+        let toolchain = ToolchainOverride::Version {
+            channel: Channel::Nightly,
+            version: None,
+            extras: Extras::default(),
+        }
+        .with_extras(Extras {
+            components: ["rust-src".to_owned(), "clippy".to_owned()].into(),
+            targets: ["wasm32-unknown-unknown".to_owned()].into(),
+            profile: Profile::Default,
+            hash: None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            toolchain.key(),
+            "external-nightly,component=clippy,component=rust-src,target=wasm32-unknown-unknown"
+        );
+        assert_eq!(
+            ToolchainOverride::from_key(toolchain.key()),
+            Some(toolchain.clone())
+        );
+
+        // Displays as a spec, without the targets (there is no spec syntax for them)
+        assert_eq!(toolchain.to_string(), "nightly+clippy+rust-src");
+
+        let pinned = parse_toolchain_spec("nightly-2024-05-01")
+            .unwrap()
+            .with_extras(Extras {
+                components: ["rust-src".to_owned()].into(),
+                targets: BTreeSet::new(),
+                profile: Profile::Default,
+                hash: None,
+            })
+            .unwrap();
+        assert_eq!(pinned.key(), "pinned-nightly-2024-05-01,component=rust-src");
+
+        // `+<toolchain>+<component>...`
+        assert_eq!(
+            parse_toolchain_spec("nightly-2024-05-01+rust-src").unwrap(),
+            pinned
+        );
+        assert_eq!(
+            parse_toolchain_spec("nightly+rust-src+clippy")
+                .unwrap()
+                .key(),
+            "external-nightly,component=clippy,component=rust-src"
+        );
+        assert!(parse_toolchain_spec("nightly+").is_err());
+        for spec in [
+            "nightly-2024-05-01+rust-src",
+            "stable+clippy+rust-src",
+            "beta-2024-05-01+rust-src@0c4m8hwc4rq8vvcxrl8gygmp5wbrp4z5",
+        ] {
+            let toolchain = parse_toolchain_spec(spec).unwrap();
+            assert_eq!(toolchain.to_string(), spec);
+            assert_eq!(parse_toolchain_spec(&toolchain.to_string()), Ok(toolchain));
+        }
+        assert!(parse_toolchain_spec("nightly++rust-src").is_err());
+        assert_eq!(ToolchainOverride::from_key(pinned.key()), Some(pinned));
+
+        let extras = Extras {
+            components: ["rust-src".to_owned()].into(),
+            targets: BTreeSet::new(),
+            profile: Profile::Default,
+            hash: None,
+        };
+        assert_eq!(
+            extras.nix_override(),
+            r#".override { extensions = [ "rust-src" ]; targets = [ ]; }"#
+        );
+        assert_eq!(
+            ToolchainOverride::None.with_extras(extras.clone()).unwrap(),
+            ToolchainOverride::Version {
                 channel: Channel::Stable,
-                version: None
-            }))
+                version: None,
+                extras,
+            }
+        );
+    }
+
+    #[test]
+    fn fetch_progress() {
+        let mut fetch = FetchProgress::default();
+        let mut update = |line| (fetch.update(line), fetch.fetched, fetch.paths);
+
+        assert_eq!(update("these 2 derivations will be built:"), (false, 0, 0));
+        assert_eq!(update("  /nix/store/aaa-rust-1.80.0.drv"), (false, 0, 0));
+        assert_eq!(
+            update("these 2 paths will be fetched (45.67 MiB download, 312.34 MiB unpacked):"),
+            (false, 0, 0)
+        );
+        assert_eq!(update("  /nix/store/bbb-rust-std-1.80.0"), (true, 0, 1));
+        assert_eq!(update("  /nix/store/ccc-rustc-1.80.0"), (true, 0, 2));
+        assert_eq!(
+            update(
+                "copying path '/nix/store/bbb-rust-std-1.80.0' from 'https://cache.nixos.org'..."
+            ),
+            (true, 1, 2)
+        );
+        assert_eq!(update("  /nix/store/ddd-not-listed"), (false, 1, 2));
+        assert_eq!(
+            update("copying path '/nix/store/ccc-rustc-1.80.0' from 'https://cache.nixos.org'..."),
+            (true, 2, 2)
+        );
+        // More copying than announced doesn't go over the total
+        assert_eq!(
+            update("copying path '/nix/store/eee' from 'https://cache.nixos.org'..."),
+            (false, 2, 2)
+        );
+        assert_eq!(fetch.unit(), "paths (45.67 MiB download)");
+
+        // Older versions of nix, and a single path
+        let mut fetch = FetchProgress::default();
+        fetch.update("these paths will be fetched (1.00 MiB download, 2.00 MiB unpacked):");
+        fetch.update("  /nix/store/aaa");
+        fetch.update("this path will be fetched (0.50 MiB download, 1.00 MiB unpacked):");
+        fetch.update("  /nix/store/bbb");
+        assert_eq!((fetch.fetched, fetch.paths), (0, 2));
+        assert_eq!(fetch.unit(), "paths (1.00 MiB download)");
+
+        // Unknown output is just ignored
+        let mut fetch = FetchProgress::default();
+        assert!(!fetch.update("building '/nix/store/aaa.drv'..."));
+        assert!(!fetch.update("copying path '/nix/store/aaa' from 'https://cache.nixos.org'..."));
+        assert_eq!(fetch, FetchProgress::default());
+        assert_eq!(fetch.unit(), "paths");
+    }
+
+    #[test]
+    fn store_hashes() {
+        const HASH: &str = "0c4m8hwc4rq8vvcxrl8gygmp5wbrp4z5";
+
+        let toolchain = parse_toolchain_spec(&format!("stable@{HASH}")).unwrap();
+        assert_eq!(toolchain.expected_hash(), Some(HASH));
+        assert_eq!(toolchain.to_string(), format!("stable@{HASH}"));
+        assert_eq!(toolchain.key(), *format!("external-stable,hash={HASH}"));
+        assert_eq!(
+            ToolchainOverride::from_key(toolchain.key()),
+            Some(toolchain.clone())
+        );
+        assert!(!toolchain.updates_in_place());
+
+        // With shorthands, versions and components
+        let toolchain = parse_toolchain_spec(&format!("1.80+rust-src@{HASH}")).unwrap();
+        assert_eq!(
+            toolchain.key(),
+            *format!("pinned-stable-1.80,component=rust-src,hash={HASH}")
+        );
+        assert_eq!(
+            toolchain,
+            parse_toolchain_override(Some(&format!("+stable-1.80+rust-src@{HASH}")))
+                .unwrap()
+                .unwrap()
+        );
+
+        // Not hashes
+        assert!(parse_toolchain_spec("stable@").is_err());
+        assert!(parse_toolchain_spec(&format!("stable@{}", &HASH[1..])).is_err());
+        assert!(parse_toolchain_spec(&format!("stable@{HASH}0")).is_err());
+        assert!(parse_toolchain_spec("stable@0c4m8hwc4rq8vvcxrl8gygmp5wbrp4ze").is_err());
+        assert!(parse_toolchain_spec(&format!("stable@{HASH}@{HASH}")).is_err());
+        assert!(parse_toolchain_spec(&format!("not-a-toolchain@{HASH}")).is_err());
+        assert!(ToolchainOverride::from_key("external-stable,hash=abc".into()).is_none());
+
+        assert_eq!(
+            store_hash(Path::new(&format!("/nix/store/{HASH}-rust-default-1.80.0"))),
+            Some(HASH)
+        );
+        assert_eq!(
+            store_hash(Path::new("/nix/store/rust-default-1.80.0")),
+            None
+        );
+        assert_eq!(store_hash(Path::new(HASH)), None);
+    }
+
+    #[test]
+    fn channels() {
+        let names: Vec<_> = Channel::iter().map(Channel::as_str).collect();
+        assert_eq!(names, ["stable", "beta", "nightly"]);
+
+        for channel in Channel::ALL {
+            assert_eq!(channel.as_str().parse(), Ok(channel));
+            assert_eq!(channel.to_string(), channel.as_str());
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let minimal = ToolchainOverride::None
+            .with_extras(Extras {
+                profile: Profile::Minimal,
+                ..Extras::default()
+            })
+            .unwrap();
+
+        assert_eq!(minimal.key(), "external-stable,profile=minimal");
+        // There is no spec syntax for profiles
+        assert_eq!(minimal.to_string(), "stable");
+        assert!(minimal
+            .nix_expr(&Config::default())
+            .ends_with(r#".rust-bin.stable."latest".minimal"#));
+        assert_eq!(ToolchainOverride::from_key(minimal.key()), Some(minimal));
+
+        // The default profile doesn't change the key
+        let default = parse_toolchain_spec("nightly")
+            .unwrap()
+            .with_extras(Extras {
+                profile: Profile::Default,
+                ..Extras::default()
+            })
+            .unwrap();
+        assert_eq!(default.key(), "external-nightly");
+
+        let nightly_minimal = default
+            .with_extras(Extras {
+                profile: Profile::Minimal,
+                ..Extras::default()
+            })
+            .unwrap();
+        assert_eq!(nightly_minimal.key(), "external-nightly,profile=minimal");
+
+        assert_eq!(
+            ToolchainOverride::from_key("external-beta,profile=max".into()),
+            None
+        );
+    }
+
+    #[test]
+    fn channel_keys() {
+        let key = |spec| parse_toolchain_spec(spec).unwrap().key();
+        let from_key = |key: &str| ToolchainOverride::from_key(key.into());
+
+        assert_eq!(key("nightly"), "external-nightly");
+        assert_eq!(key("nightly-2024-05-01"), "pinned-nightly-2024-05-01");
+        assert_eq!(key("1.78"), "pinned-stable-1.78");
+
+        assert_eq!(
+            from_key("external-nightly"),
+            Some(parse_toolchain_spec("nightly").unwrap())
+        );
+        assert_eq!(
+            from_key("pinned-nightly-2024-05-01"),
+            Some(parse_toolchain_spec("nightly-2024-05-01").unwrap())
+        );
+        // Legacy key of versioned toolchains
+        assert_eq!(
+            from_key("external-stable-1.78,target=wasm32-unknown-unknown"),
+            from_key("pinned-stable-1.78,target=wasm32-unknown-unknown"),
+        );
+
+        // Pinned toolchains must have a version
+        assert_eq!(from_key("pinned-nightly"), None);
+        assert_eq!(from_key("pinned-"), None);
+        assert_eq!(from_key("external-"), None);
+    }
+
+    #[test]
+    fn legacy_toolchain_file() {
+        assert_eq!(
+            legacy_channel("nightly-2023-01-01\n"),
+            Some("nightly-2023-01-01")
+        );
+        assert_eq!(legacy_channel("  1.78.0  "), Some("1.78.0"));
+        assert_eq!(legacy_channel("\nstable\n\n"), Some("stable"));
+
+        assert_eq!(legacy_channel(""), None);
+        assert_eq!(legacy_channel("[toolchain]\nchannel = \"nightly\"\n"), None);
+        assert_eq!(legacy_channel("[toolchain]"), None);
+    }
+
+    #[test]
+    fn path_overrides() {
+        let dir = TempDir::new("path-overrides");
+
+        let toml = dir.join("foo.toml");
+        fs::write(
+            &toml,
+            "[toolchain]\nchannel = \"nightly\"\ncomponents = [\"rust-src\"]\n",
+        )
+        .unwrap();
+
+        // `./../../<...>`, relative to the current directory
+        let cwd = env::current_dir().unwrap();
+        let mut relative = PathBuf::from(".");
+        relative.extend(cwd.components().skip(1).map(|_| ".."));
+        relative.push(toml.strip_prefix("/").unwrap());
+
+        // All of these are the same file, so they share the cache
+        let canonical = Ok(Some(ToolchainOverride::File(
+            fs::canonicalize(&toml).unwrap().into(),
+        )));
+        let symlink = dir.join("link.toml");
+        std::os::unix::fs::symlink(&toml, &symlink).unwrap();
+
+        let dotdot = dir
+            .join("..")
+            .join(dir.file_name().unwrap())
+            .join("foo.toml");
+
+        for path in [&relative, &toml, &dotdot, &symlink] {
+            let parsed = parse_toolchain_override(Some(&format!("+{}", path.display())));
+            assert_eq!(parsed, canonical, "{}", path.display());
+        }
+        assert_eq!(
+            parse_toolchain_arg(relative.to_str().unwrap())
+                .unwrap()
+                .key(),
+            parse_toolchain_arg(symlink.to_str().unwrap())
+                .unwrap()
+                .key()
         );
+
+        // Same as `+nightly`
+        let channel_only = dir.join("channel.toml");
+        fs::write(&channel_only, "[toolchain]\nchannel = \"nightly\"\n").unwrap();
         assert_eq!(
-            parse_toolchain_override(Some("+stable-")),
+            parse_toolchain_override(Some(&format!("+{}", channel_only.display()))),
             Ok(Some(ToolchainOverride::Version {
-                channel: Channel::Stable,
-                version: Some("".to_owned())
+                channel: Channel::Nightly,
+                version: None,
+                extras: Extras::default(),
             }))
         );
+
+        let legacy = dir.join("rust-toolchain");
+        fs::write(&legacy, "nightly-2024-05-01\n").unwrap();
         assert_eq!(
-            parse_toolchain_override(Some("+stable-1.78")),
+            parse_toolchain_override(Some(&format!("+{}", legacy.display()))),
             Ok(Some(ToolchainOverride::Version {
-                channel: Channel::Stable,
-                version: Some("1.78".to_owned())
+                channel: Channel::Nightly,
+                version: Some("2024-05-01".to_owned()),
+                extras: Extras::default(),
             }))
         );
+
+        let not_toolchain_file = dir.join("foo.txt");
+        fs::write(&not_toolchain_file, "").unwrap();
+        assert_eq!(
+            parse_toolchain_override(Some(&format!("+{}", not_toolchain_file.display()))),
+            Err(())
+        );
+
+        assert_eq!(
+            parse_toolchain_override(Some(&format!("+{}", dir.join("nope.toml").display()))),
+            Err(())
+        );
+        assert_eq!(parse_toolchain_override(Some("+./nope.toml")), Err(()));
+    }
+
+    #[test]
+    fn link_names() {
+        assert!(is_valid_link_name("stage1"));
+        assert!(is_valid_link_name("my-toolchain"));
+
+        assert!(!is_valid_link_name(""));
+        assert!(!is_valid_link_name("default"));
+        assert!(!is_valid_link_name("nightly"));
+        assert!(!is_valid_link_name("stable-1.78"));
+        assert!(!is_valid_link_name("1.78"));
+        assert!(!is_valid_link_name("2024-05-01"));
+        assert!(!is_valid_link_name("./stage1"));
+        assert!(!is_valid_link_name("a/b"));
+    }
+
+    #[test]
+    fn nix_commands() {
+        let args = |c: &Command| {
+            iter::once(c.get_program())
+                .chain(c.get_args())
+                .map(|a| a.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let link = Path::new("/cache/toolchain");
+
+        assert_eq!(
+            args(&nix_build_command(NixCommand::Legacy, &[], link, "{}: x")),
+            "nix-build --out-link /cache/toolchain --expr {}: x"
+        );
+        assert_eq!(
+            args(&nix_build_command(NixCommand::New, &[], link, "{}: x")),
+            "nix --extra-experimental-features nix-command build --impure \
+             --out-link /cache/toolchain --expr ({}: x) {}"
+        );
+        assert_eq!(
+            args(&nix_eval_command(NixCommand::Legacy, &[], "x")),
+            "nix-instantiate --eval --expr x"
+        );
+        assert_eq!(
+            args(&nix_eval_command(NixCommand::New, &[], "x")),
+            "nix --extra-experimental-features nix-command eval --impure --raw --expr x"
+        );
+
+        let extra = ["--max-jobs".to_owned(), "4".to_owned()];
+        assert_eq!(
+            args(&nix_build_command(
+                NixCommand::Legacy,
+                &extra,
+                link,
+                "{}: x"
+            )),
+            "nix-build --out-link /cache/toolchain --max-jobs 4 --expr {}: x"
+        );
+        assert_eq!(
+            args(&nix_eval_command(NixCommand::Legacy, &extra, "x")),
+            "nix-instantiate --eval --max-jobs 4 --expr x"
+        );
+
+        let config = Config {
+            extra_nix_args: vec!["--out-link=/tmp/x".to_owned()],
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.extra_nix_args(),
+            Err(Error::InvalidNixArgs { .. })
+        ));
+
+        let config: Config = toml::from_str(r#"nix_command = "nix build""#).unwrap();
+        assert_eq!(config.nix_command(), NixCommand::New);
+        assert!(toml::from_str::<Config>(r#"nix_command = "nix""#).is_err());
+    }
+
+    #[test]
+    fn nix_expr() {
+        let toolchain = parse_toolchain_spec("nightly-2024-05-01").unwrap();
+
+        assert_eq!(
+            toolchain.nix_expr(&Config::default()),
+            r#"{}: (import <nixpkgs> {overlays = [(import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz"))];}).rust-bin.nightly."2024-05-01".default"#
+        );
+
+        let pinned = Config {
+            rust_overlay_rev: Some("abc".to_owned()),
+            rust_overlay_sha256: Some("sha256-AAAA".to_owned()),
+            nixpkgs_rev: Some("def".to_owned()),
+            ..Config::default()
+        };
+        assert_eq!(
+            toolchain.nix_expr(&pinned),
+            r#"{}: (import (builtins.fetchTarball "https://github.com/NixOS/nixpkgs/archive/def.tar.gz") {overlays = [(import (builtins.fetchTarball { url = "https://github.com/oxalica/rust-overlay/archive/abc.tar.gz"; sha256 = "sha256-AAAA"; }))];}).rust-bin.nightly."2024-05-01".default"#
+        );
+
+        // Everything from the config is quoted
+        let injected = Config {
+            nixpkgs_url: Some(r#"https://x"; y = builtins.abort "pwned"#.to_owned()),
+            nixpkgs_sha256: Some(r#"a"; y = "b"#.to_owned()),
+            ..Config::default()
+        };
+        assert!(toolchain.nix_expr(&injected).starts_with(
+            r#"{}: (import (builtins.fetchTarball { url = "https://x\"; y = builtins.abort \"pwned"; sha256 = "a\"; y = \"b"; })"#
+        ));
+    }
+
+    #[test]
+    fn malicious_extras() {
+        for spec in [
+            "nightly+../../x",
+            r#"nightly+rust-src"]; builtins.abort "pwned"#,
+            "nightly+a,component=b",
+            "nightly+a b",
+        ] {
+            assert_eq!(parse_toolchain_spec(spec), Err(()), "{spec}");
+        }
+        for key in [
+            "external-nightly,component=../../x",
+            "external-nightly,target=a\"b",
+            "external-nightly,target=",
+        ] {
+            assert_eq!(ToolchainOverride::from_key(key.into()), None, "{key}");
+        }
+
+        assert!(is_valid_extra_name("wasm32-unknown-unknown"));
+        assert!(is_valid_extra_name("llvm-tools-preview"));
+        assert!(!is_valid_extra_name("x86_64 linux"));
+
+        // Names are quoted anyway
+        let toolchain = ToolchainOverride::Version {
+            channel: Channel::Nightly,
+            version: None,
+            extras: Extras {
+                components: [r#"a"; builtins.abort "pwned"#.to_owned()].into(),
+                ..Extras::default()
+            },
+        };
+        assert!(toolchain.nix_expr(&Config::default()).ends_with(
+            r#".override { extensions = [ "a\"; builtins.abort \"pwned" ]; targets = [ ]; }"#
+        ));
+    }
+
+    #[test]
+    fn nix_expr_exotic_path() {
+        let file =
+            ToolchainOverride::File(Path::new(r#"/tmp/a "b" \c/${d}/rust-toolchain"#).into());
+        let expr = file.nix_expr(&Config::default());
+
+        let (_, arg) = expr.split_once("fromRustupToolchainFile ").unwrap();
+        assert_eq!(arg, r#""/tmp/a \"b\" \\c/\${d}/rust-toolchain""#);
+
+        // The string ends exactly at the end of the expression, and doesn't interpolate anything
+        let mut chars = arg.chars().skip(1);
+        let mut len = 1;
+        while let Some(c) = chars.next() {
+            len += 1;
+            match c {
+                '\\' => _ = chars.next().map(|_| len += 1),
+                '"' => break,
+                '$' => assert_ne!(chars.clone().next(), Some('{')),
+                _ => {}
+            }
+        }
+        assert_eq!(len, arg.len());
+    }
+
+    #[test]
+    fn upgrade_backoff() {
+        let mut backoff = UpgradeBackoff::new();
+        let delays: Vec<_> = iter::from_fn(|| backoff.next_delay()).take(20).collect();
+
+        assert!(delays[0] >= UPGRADE_BACKOFF_INITIAL / 2 && delays[0] <= UPGRADE_BACKOFF_INITIAL);
+        assert!(delays[3] >= UPGRADE_BACKOFF_INITIAL * 4);
+        assert!(delays.iter().all(|&d| d <= UPGRADE_BACKOFF_MAX));
+
+        // Gives up after the deadline
+        backoff.deadline = Instant::now() - Duration::from_secs(1);
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.wait(), Err(rustix::io::Errno::TIMEDOUT));
+    }
+
+    #[test]
+    fn build_retry_delays() {
+        let delays: Vec<_> = (1..=7).map(|r| build_retry_delay(r).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32, 60, 60]);
+
+        // Doesn't overflow
+        assert_eq!(build_retry_delay(u32::MAX), BUILD_RETRY_DELAY_MAX);
+    }
+
+    #[test]
+    fn interrupted_update() {
+        let ttl = Config::default().cache_ttl();
+        let dir = TempDir::new("interrupted-update");
+        let cache = dir.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+
+        let file = dir.join("rust-toolchain.toml");
+        fs::write(&file, "[toolchain]\nchannel = \"1.78\"\n").unwrap();
+        let toolchain = ToolchainOverride::File(file.clone().into_boxed_path());
+
+        // What `nix-build` would create
+        fs::create_dir_all(dir.join("store")).unwrap();
+        std::os::unix::fs::symlink(dir.join("store"), cache.join("toolchain")).unwrap();
+
+        let lock_file = fs::File::create(dir.join("lock")).unwrap();
+        let mut lock = crate::lock::lock_shared(&lock_file)
+            .unwrap()
+            .upgrade()
+            .unwrap();
+
+        toolchain.commit_cache(&cache, &mut lock).unwrap();
+        assert!(toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+
+        let meta = read_meta(&cache.join("toolchain")).unwrap();
+        assert_eq!(meta.store_path, dir.join("store"));
+        assert_eq!(meta.rustc_version, None);
+
+        // The toolchain file changes, and an update is interrupted after `nix-build`
+        // replaced the toolchain, but before it was committed...
+        fs::write(&file, "[toolchain]\nchannel = \"1.80\"\n").unwrap();
+        assert!(!toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+        toolchain.invalidate_cache(&cache, &mut lock).unwrap();
+
+        // ...then the file is changed back. The cached toolchain is the new one,
+        // so the old metadata must not be trusted.
+        fs::write(&file, "[toolchain]\nchannel = \"1.78\"\n").unwrap();
+        assert!(!toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+
+        // The next update recovers
+        toolchain.invalidate_cache(&cache, &mut lock).unwrap();
+        toolchain.commit_cache(&cache, &mut lock).unwrap();
+        assert!(toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+        assert!(!cache.join("rust-toolchain.toml.tmp").exists());
+    }
+
+    #[test]
+    fn memoization() {
+        let key = OsString::from(format!("memoization-{}", std::process::id()));
+
+        let first = memoized(key.clone(), || Ok(PathBuf::from("/nix/store/a"))).unwrap();
+        let second = memoized(key.clone(), || panic!("resolved twice")).unwrap();
+        assert_eq!(first, second);
+
+        // Errors are not remembered
+        let other = OsString::from(format!("memoization-error-{}", std::process::id()));
+        assert!(memoized(other.clone(), || Err(Error::NoHome)).is_err());
+        assert_eq!(
+            memoized(other, || Ok(PathBuf::from("/nix/store/b"))).unwrap(),
+            Path::new("/nix/store/b")
+        );
+    }
+
+    #[test]
+    fn configured_default() {
+        let stable = Config::default();
+        assert_eq!(stable.default_toolchain().to_string(), "stable");
+        assert!(ToolchainOverride::None
+            .nix_expr(&stable)
+            .ends_with(r#".rust-bin.stable."latest".default"#));
+
+        let nightly = Config {
+            default_channel: Some("nightly-2024-05-01".to_owned()),
+            ..Config::default()
+        };
+        let toolchain = nightly.default_toolchain();
+        assert_eq!(
+            toolchain,
+            ToolchainOverride::Version {
+                channel: Channel::Nightly,
+                version: Some("2024-05-01".to_owned()),
+                extras: Extras::default(),
+            }
+        );
+        assert_eq!(
+            ToolchainOverride::None.nix_expr(&nightly),
+            toolchain.nix_expr(&nightly)
+        );
+
+        // Only channels are allowed
+        let invalid = Config {
+            default_channel: Some("./rust-toolchain.toml".to_owned()),
+            ..Config::default()
+        };
+        assert_eq!(invalid.default_toolchain().to_string(), "stable");
+    }
+
+    #[test]
+    fn pinned_toolchain_file() {
+        // Not the user's config, which might e.g. never trust caches
+        let ttl = Config::default().cache_ttl();
+        let dir = TempDir::new("pinned-file");
+        let cache = dir.join("cache");
+        fs::create_dir_all(dir.join("store")).unwrap();
+        fs::create_dir_all(&cache).unwrap();
+        std::os::unix::fs::symlink(dir.join("store"), cache.join("toolchain")).unwrap();
+
+        let lock_file = fs::File::create(dir.join("lock")).unwrap();
+        let mut lock = crate::lock::lock_shared(&lock_file)
+            .unwrap()
+            .upgrade()
+            .unwrap();
+
+        let file = dir.join("rust-toolchain.toml");
+        let toolchain = ToolchainOverride::File(file.clone().into_boxed_path());
+
+        // A pinned version can't change, so it's trusted without `updated`
+        fs::write(&file, "[toolchain]\nchannel = \"1.78.0\"\n").unwrap();
+        assert!(file_pins_version(&file).unwrap());
+        assert_eq!(
+            toolchain.commit_cache(&cache, &mut lock).unwrap(),
+            ControlFlow::Continue(())
+        );
+        assert!(!cache.join(UPDATED_FILE).exists());
+        assert!(toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+
+        // `stable` updates in place, so it's only trusted for a while after an update
+        fs::write(&file, "[toolchain]\nchannel = \"stable\"\n").unwrap();
+        assert!(!file_pins_version(&file).unwrap());
+        assert!(!toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+        toolchain.invalidate_cache(&cache, &mut lock).unwrap();
+        assert_eq!(
+            toolchain.commit_cache(&cache, &mut lock).unwrap(),
+            ControlFlow::Break(())
+        );
+        assert!(toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+
+        fs::remove_file(cache.join(UPDATED_FILE)).unwrap();
+        assert!(!toolchain.cache_is_valid(&cache, &lock, ttl).unwrap());
+    }
+
+    #[test]
+    fn broken_toolchain_is_rebuilt() {
+        use std::os::unix::fs::{symlink, PermissionsExt as _};
+
+        let dir = TempDir::new("broken-toolchain");
+        let store_path = dir.join("store/rust");
+        let cache = dir.join("cache");
+        fs::create_dir_all(store_path.join("bin")).unwrap();
+        fs::create_dir_all(&cache).unwrap();
+
+        let rustc = store_path.join("bin/rustc");
+        fs::write(&rustc, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&rustc, fs::Permissions::from_mode(0o755)).unwrap();
+        symlink(&store_path, cache.join("toolchain")).unwrap();
+
+        assert!(toolchain_link_exists(&cache));
+
+        fs::set_permissions(&rustc, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(!toolchain_link_exists(&cache));
+
+        // Garbage collected
+        fs::remove_dir_all(&store_path).unwrap();
+        assert!(!toolchain_link_exists(&cache));
+    }
+
+    /// A tiny deterministic PRNG (xorshift), to generate arbitrary values without a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn string(&mut self, alphabet: &[u8], max_len: usize) -> String {
+            let len = 1 + self.below(max_len);
+            (0..len)
+                .map(|_| alphabet[self.below(alphabet.len())] as char)
+                .collect()
+        }
+
+        fn set(&mut self, alphabet: &[u8]) -> BTreeSet<String> {
+            (0..self.below(3))
+                .map(|_| self.string(alphabet, 12))
+                .collect()
+        }
+
+        fn path(&mut self) -> Box<Path> {
+            // Slashes, escapes, non-UTF-8 and non-ASCII bytes are the interesting parts
+            const SPECIAL: &[u8] = &[b'/', 0x10, 0x1, 0x7f, 0x80, 0xff, b',', b'-', b'.'];
+
+            let len = self.below(32);
+            let bytes = (0..len)
+                .map(|_| match self.below(3) {
+                    0 => SPECIAL[self.below(SPECIAL.len())],
+                    // Any byte, except NUL (which can't be in paths)
+                    _ => 1 + self.below(255) as u8,
+                })
+                .collect();
+
+            PathBuf::from(OsString::from_vec(bytes)).into_boxed_path()
+        }
+
+        /// Half of the time a valid version, otherwise (mostly) garbage.
+        fn version(&mut self) -> String {
+            // Separators and quotes are what would escape the key or the nix expression
+            const VERSION: &[u8] = b"0123456789.-abcz,/\"";
+
+            match self.below(4) {
+                0 => format!("1.{}", self.below(100)),
+                1 => format!(
+                    "20{:02}-{:02}-{:02}",
+                    self.below(100),
+                    1 + self.below(12),
+                    1 + self.below(28)
+                ),
+                _ => self.string(VERSION, 12),
+            }
+        }
+
+        fn toolchain(&mut self) -> ToolchainOverride {
+            const EXTRA: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789-_";
+            const HASH: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+            match self.below(4) {
+                0 => ToolchainOverride::File(self.path()),
+                1 => ToolchainOverride::Version {
+                    channel: Channel::ALL[self.below(3)],
+                    version: (self.below(2) == 0).then(|| self.version()),
+                    extras: Extras {
+                        components: self.set(EXTRA),
+                        targets: self.set(EXTRA),
+                        profile: Profile::ALL[self.below(3)],
+                        hash: (self.below(2) == 0).then(|| {
+                            (0..32)
+                                .map(|_| HASH[self.below(HASH.len())] as char)
+                                .collect()
+                        }),
+                    },
+                },
+                2 => ToolchainOverride::None,
+                _ => ToolchainOverride::LocalName(self.string(EXTRA, 12)),
+            }
+        }
+    }
+
+    #[test]
+    fn key_roundtrip() {
+        let mut valid = 0;
+        let mut check = |toolchain: ToolchainOverride| {
+            let key = toolchain.key();
+            // Toolchains with invalid versions can't be parsed, so they must not have keys either
+            let expected = match &toolchain {
+                ToolchainOverride::Version {
+                    channel,
+                    version: Some(version),
+                    ..
+                } if !is_valid_version(*channel, version) => None,
+                _ => Some(toolchain.clone()),
+            };
+            assert_eq!(
+                ToolchainOverride::from_key(key.clone()),
+                expected,
+                "key: {key:?}"
+            );
+
+            // Specs round-trip too, except for targets and the profile (see `Display`)
+            if let Some(ToolchainOverride::Version {
+                channel,
+                version,
+                extras,
+            }) = expected
+            {
+                valid += 1;
+
+                let spec = ToolchainOverride::Version {
+                    channel,
+                    version,
+                    extras: Extras {
+                        targets: BTreeSet::new(),
+                        profile: Profile::Default,
+                        ..extras
+                    },
+                };
+                assert_eq!(
+                    parse_toolchain_spec(&spec.to_string()),
+                    Ok(spec.clone()),
+                    "spec: {spec}"
+                );
+            }
+        };
+
+        // Known troublemakers
+        for path in [
+            &b"/home/user/project/rust-toolchain.toml"[..],
+            b"relative/rust-toolchain",
+            b"/",
+            b"/trailing/",
+            b"\x10",
+            b"/a\x10b/\x10/c",
+            b"/\xff\xfe/non-utf8",
+            b"/\xc3\xa9t\xc3\xa9/rust-toolchain.toml",
+        ] {
+            let path = Path::new(OsStr::from_bytes(path));
+            check(ToolchainOverride::File(path.into()));
+        }
+
+        let mut rng = Rng(0x5eed_1234_abcd_ef01);
+        for _ in 0..10_000 {
+            check(rng.toolchain());
+        }
+
+        // Make sure the interesting cases are actually generated
+        assert!(valid > 1_000, "only {valid} valid toolchains");
     }
 }
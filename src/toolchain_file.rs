@@ -0,0 +1,135 @@
+//! Parsing of `rust-toolchain.toml` files.
+//!
+//! Toolchain files are built by rust-overlay (`fromRustupToolchainFile`), this only checks that
+//! they make sense beforehand, so that mistakes are reported clearly, instead of as an opaque
+//! nix evaluation error.
+
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    error::{Error, IoResultExt as _},
+    toolchain::{is_valid_extra_name, Profile},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolchainFile {
+    toolchain: Toolchain,
+}
+
+/// The `[toolchain]` table of a toolchain file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Toolchain {
+    /// `stable`, `nightly-2024-05-01`, `1.78.0`, ...
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub components: Vec<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// `minimal`, `default` or `complete`.
+    pub profile: Option<String>,
+    /// Path to a custom toolchain (not supported, only exists to give a better error).
+    path: Option<String>,
+}
+
+/// Reads and checks the toolchain file at `path`.
+pub fn parse(path: &Path) -> Result<Toolchain, Error> {
+    let contents = fs::read_to_string(path).with_path(path)?;
+
+    parse_str(&contents).map_err(|reason| Error::InvalidToolchainFile {
+        path: path.to_owned(),
+        reason,
+    })
+}
+
+fn parse_str(contents: &str) -> Result<Toolchain, String> {
+    let file: ToolchainFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let toolchain = file.toolchain;
+
+    if toolchain.path.is_some() {
+        return Err("`toolchain.path` is not supported, \
+                    use `rustdn toolchain link <name> <path>` for custom toolchains"
+            .to_owned());
+    }
+
+    if toolchain.channel.is_none() {
+        return Err("`toolchain.channel` must be specified".to_owned());
+    }
+
+    for (field, names) in [
+        ("components", &toolchain.components),
+        ("targets", &toolchain.targets),
+    ] {
+        if let Some(name) = names.iter().find(|n| !is_valid_extra_name(n)) {
+            return Err(format!("invalid name `{name}` in `toolchain.{field}`"));
+        }
+    }
+
+    if let Some(profile) = &toolchain.profile {
+        if profile.parse::<Profile>().is_err() {
+            let profiles = Profile::ALL.map(Profile::as_str);
+            return Err(format!(
+                "unknown profile `{profile}` in `toolchain.profile`, expected one of: {}",
+                profiles.join(", ")
+            ));
+        }
+    }
+
+    Ok(toolchain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        let toolchain = parse_str(
+            r#"
+            [toolchain]
+            channel = "nightly-2024-05-01"
+            components = ["rust-src", "clippy"]
+            targets = ["wasm32-unknown-unknown"]
+            profile = "minimal"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            toolchain,
+            Toolchain {
+                channel: Some("nightly-2024-05-01".to_owned()),
+                components: vec!["rust-src".to_owned(), "clippy".to_owned()],
+                targets: vec!["wasm32-unknown-unknown".to_owned()],
+                profile: Some("minimal".to_owned()),
+                path: None,
+            }
+        );
+
+        assert!(parse_str("[toolchain]\nchannel = \"stable\"\n").is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        let err = |contents| parse_str(contents).unwrap_err();
+
+        assert!(err("[toolchain]\nchanel = \"stable\"\n").contains("unknown field `chanel`"));
+        assert!(err("[toolchain]\nchannel = 1\n").contains("invalid type"));
+        assert!(err("channel = \"stable\"\n").contains("unknown field `channel`"));
+        assert!(err("[toolchain]\ncomponents = [\"rust-src\"]\n").contains("channel"));
+        assert!(err("[toolchain]\nchannel = \"stable\"\nprofile = \"max\"\n").contains("`max`"));
+        assert!(err("[toolchain]\npath = \"/opt/rust\"\n").contains("not supported"));
+        assert!(
+            err("[toolchain]\nchannel = \"stable\"\ncomponents = [\"a\\\"b\"]\n")
+                .contains("`toolchain.components`")
+        );
+        assert!(
+            err("[toolchain]\nchannel = \"stable\"\ntargets = [\"../x\"]\n")
+                .contains("`toolchain.targets`")
+        );
+    }
+}
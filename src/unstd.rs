@@ -1,10 +1,6 @@
 //! Module with std-like things, in other words "utilities".
 
 pub trait AnyExt: Sized {
-    fn apply<R>(self, f: impl FnOnce(Self) -> R) -> R {
-        f(self)
-    }
-
     fn also(mut self, f: impl FnOnce(&mut Self)) -> Self {
         f(&mut self);
         self
@@ -13,20 +9,162 @@ pub trait AnyExt: Sized {
 
 impl<T> AnyExt for T {}
 
+/// Exits the current process the same way a child process with `status` exited.
+///
+/// i.e. exits with the same exit code, or, if the child was killed by a signal, kills the current
+/// process with the same signal (so that e.g. the shell can see that `cargo test` was
+/// interrupted with `^C`).
+pub fn exit_like(status: std::process::ExitStatus) -> ! {
+    use std::os::unix::process::ExitStatusExt as _;
+
+    if let Some(code) = status.code() {
+        std::process::exit(code);
+    }
+
+    if let Some(signal) = status.signal() {
+        if let Some(sig) = rustix::process::Signal::from_raw(signal) {
+            _ = rustix::process::kill_process(rustix::process::getpid(), sig);
+        }
+
+        // The signal is ignored or handled (or we couldn't send it),
+        // so do what shells do for processes killed by a signal.
+        std::process::exit(128 + signal);
+    }
+
+    // Neither an exit code, nor a signal? Must be stopped or something, this shouldn't happen
+    // with `wait`.
+    std::process::exit(1)
+}
+
+/// Quotes `s` for POSIX shells (`sh`, `bash`, `zsh`, ...), e.g. `it's` -> `'it'\''s'`.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Splits `s` into words, like a POSIX shell would (without any expansions).
+///
+/// Supports `'single'` and `"double"` quotes, and `\` escapes (outside of single quotes).
+/// Returns `None` if a quote is not terminated.
+pub fn shell_split(s: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    // `None` between words, so that `''` is still an (empty) word
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            c => word.extend(['\\', c]),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+/// Quotes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    use std::fmt::Write as _;
+
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str(r#"\""#),
+            '\\' => out.push_str(r"\\"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            c if c.is_control() => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+/// Quotes `s` as a nix string (`"..."`), so that it can be embedded in a nix expression.
+///
+/// `$` is always escaped, so `${...}` in `s` is not interpolated.
+pub fn nix_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str(r#"\""#),
+            '\\' => out.push_str(r"\\"),
+            '$' => out.push_str(r"\$"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
 /// Destructures `$e` using a provided pattern.
 ///
 /// Importantly, this works with types which implement `Drop` (ofc, this doesn't run the destructor).
+///
+/// The pattern is either a struct pattern (`Type { a, b: _ }`) or a tuple struct pattern
+/// (`Type(a, _)`, up to 12 fields). The type can be a path and can have generic arguments
+/// (`lock::Lock<F, M> { .. }`, but not lifetimes), but they can also be left to inference.
 // FIXME: move this to its own crate
 #[macro_export]
 macro_rules! destructure {
-    ($Type:ident { $($f:tt $(: $rename:pat)? ),+ $(,)? } = $e:expr) => (
+    (@_internal_pat_helper $f:tt) => ($f);
+    (@_internal_pat_helper $f:tt $rename:pat) => ($rename);
+
+    // Gives the patterns of the tuple struct form their field indices, one by one
+    (@_internal_tuple [$($Type:tt)*] [$p:pat $(, $rest:pat)*] [$i:tt $($is:tt)*] [$($fields:tt)*] = $e:expr) => (
+        $crate::destructure!(@_internal_tuple [$($Type)*] [$($rest),*] [$($is)*] [$($fields)* $i: $p,] = $e)
+    );
+    (@_internal_tuple [$($Type:tt)*] [] [$($is:tt)*] [$($fields:tt)*] = $e:expr) => (
+        $crate::destructure!($($Type)* { $($fields)* } = $e)
+    );
+
+    ($($Type:ident)::+ $(<$($gen:ty),+ $(,)?>)? ( $($p:pat),+ $(,)? ) = $e:expr) => (
+        $crate::destructure!(
+            @_internal_tuple [$($Type)::+ $(<$($gen),+>)?] [$($p),+] [0 1 2 3 4 5 6 7 8 9 10 11] [] = $e
+        )
+    );
+
+    ($($Type:ident)::+ $(<$($gen:ty),+ $(,)?>)? { $($f:tt $(: $rename:pat)? ),+ $(,)? } = $e:expr) => (
         // FIXME: use $crate:: paths
         let tmp = $crate::unstd::_macro_reexport::core::mem::ManuallyDrop::new($e);
 
         // assert that `$e` is an owned expression, rather than `&Type`
+        #[allow(clippy::diverging_sub_expression)]
         if false {
             #[allow(unreachable_code)]
-            let _assert_owned_expr = [&tmp, &$crate::unstd::_macro_reexport::core::mem::ManuallyDrop::new($Type { $($f: todo!()),* })];
+            let _assert_owned_expr = [&tmp, &$crate::unstd::_macro_reexport::core::mem::ManuallyDrop::new($($Type)::+ $(::<$($gen),+>)? { $($f: todo!()),* })];
         };
 
         $(
@@ -44,8 +182,41 @@ macro_rules! destructure {
         // doesn't actually drop, since `ManuallyDrop`.
         _ = {tmp};
     );
-    (@_internal_pat_helper $f:tt) => ($f);
-    (@_internal_pat_helper $f:tt $rename:pat) => ($rename);
+}
+
+/// A directory for a test (`rustdn-<name>-<pid>` in the temporary directory), which is removed
+/// when it's dropped, i.e. also when the test fails.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates the (empty) directory, `name` must be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rustdn-{name}-{}", std::process::id()));
+
+        // Left over by a killed run, with the same pid
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
 }
 
 #[doc(hidden)]
@@ -53,3 +224,97 @@ macro_rules! destructure {
 pub mod _macro_reexport {
     pub use core;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("/nix/store/a b"), "'/nix/store/a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+
+        assert_eq!(
+            shell_split(r#"  -I nixpkgs=/a\ b --option substituters "x \"y\"" '' "#).unwrap(),
+            [
+                "-I",
+                "nixpkgs=/a b",
+                "--option",
+                "substituters",
+                r#"x "y""#,
+                ""
+            ]
+        );
+        assert_eq!(shell_split(""), Some(vec![]));
+        assert_eq!(shell_split("'unterminated"), None);
+        assert_eq!(
+            shell_split(&shell_quote("it's")),
+            Some(vec!["it's".to_owned()])
+        );
+
+        assert_eq!(json_string("/nix/store/abc"), r#""/nix/store/abc""#);
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+
+        assert_eq!(nix_string("/a/rust-toolchain"), r#""/a/rust-toolchain""#);
+        assert_eq!(nix_string("a\"b\\c${d}$e\nf"), r#""a\"b\\c\${d}\$e\nf""#);
+    }
+
+    #[test]
+    fn destructure() {
+        use std::{cell::Cell, rc::Rc};
+
+        mod types {
+            use std::{cell::Cell, rc::Rc};
+
+            pub struct Generic<T> {
+                pub value: T,
+                pub drops: Rc<Cell<u32>>,
+            }
+
+            pub struct Tuple<T>(pub T, pub Rc<Cell<u32>>);
+
+            impl<T> Drop for Generic<T> {
+                fn drop(&mut self) {
+                    self.drops.set(self.drops.get() + 1);
+                }
+            }
+
+            impl<T> Drop for Tuple<T> {
+                fn drop(&mut self) {
+                    self.1.set(self.1.get() + 1);
+                }
+            }
+        }
+        use types::{Generic, Tuple};
+
+        let drops = Rc::new(Cell::new(0));
+
+        let generic = Generic {
+            value: String::from("a"),
+            drops: drops.clone(),
+        };
+        crate::destructure!(Generic<String> { value, drops: d } = generic);
+        assert_eq!((value.as_str(), d.get()), ("a", 0));
+        drop(d);
+
+        crate::destructure!(
+            types::Generic { value: v, drops: _ } = Generic {
+                value: 1,
+                drops: drops.clone(),
+            }
+        );
+        assert_eq!(v, 1);
+
+        crate::destructure!(Tuple<Vec<u8>>(bytes, d) = Tuple(vec![1, 2], drops.clone()));
+        assert_eq!(bytes, [1, 2]);
+        drop(d);
+
+        crate::destructure!(Tuple(x, _,) = Tuple(3, drops.clone()));
+        crate::destructure!(Tuple { 0: y, 1: _ } = Tuple(x + 1, drops.clone()));
+        assert_eq!(y, 4);
+
+        // None of the destructors ran, and all the clones of `drops` were dropped
+        assert_eq!(drops.get(), 0);
+        assert_eq!(Rc::strong_count(&drops), 1);
+    }
+}
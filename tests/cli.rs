@@ -0,0 +1,1030 @@
+//! End-to-end tests, which run the `rustdn` binary (and its proxies) with a fake `nix-build`.
+//!
+//! The fake `nix-build` "builds" a toolchain with a `bin/rustc`, which prints its version and the
+//! nix expression the toolchain was built from, and records each build in a log.
+
+use std::{
+    env, fs,
+    os::unix::fs::{symlink, PermissionsExt as _},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// An isolated environment: a `RUSTDN_HOME`, a fake nix store and a `PATH` with the fake
+/// `nix-build` and the proxies.
+struct TestEnv {
+    root: PathBuf,
+}
+
+impl TestEnv {
+    fn new(name: &str) -> Self {
+        let root = env::temp_dir().join(format!("rustdn-cli-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let bin = root.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::create_dir_all(root.join("project")).unwrap();
+
+        let nix_build = bin.join("nix-build");
+        fs::write(
+            &nix_build,
+            format!(
+                r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --out-link) link="$2"; shift ;;
+        --expr) expr="$2"; shift ;;
+    esac
+    shift
+done
+
+out="{store}/$(printf '%s' "$expr" | cksum | cut -d ' ' -f 1)"
+mkdir -p "$out/bin"
+printf '%s\n' "$expr" > "$out/expr"
+printf '#!/bin/sh\necho "rustc 1.99.0 (fake)"\ncat "%s/expr"\n' "$out" > "$out/bin/rustc"
+chmod +x "$out/bin/rustc"
+printf '%s\n' "$expr" >> "{builds}"
+ln -sfn "$out" "$link"
+"#,
+                store = root.join("store").display(),
+                builds = root.join("builds").display(),
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&nix_build, fs::Permissions::from_mode(0o755)).unwrap();
+
+        symlink(env!("CARGO_BIN_EXE_rustdn"), bin.join("rustdn")).unwrap();
+        symlink(env!("CARGO_BIN_EXE_rustdn"), bin.join("rustc")).unwrap();
+
+        TestEnv { root }
+    }
+
+    /// Directory the commands are run in.
+    fn project(&self) -> PathBuf {
+        self.root.join("project")
+    }
+
+    /// Runs `program` (`rustdn` or a proxy, or an absolute path) in [`TestEnv::project`],
+    /// expecting it to succeed.
+    fn run(&self, program: &str, args: &[&str]) -> String {
+        let output = self.command(program, args);
+        assert!(
+            output.status.success(),
+            "`{program} {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn command(&self, program: impl AsRef<Path>, args: &[&str]) -> Output {
+        Command::new(self.root.join("bin").join(program))
+            .args(args)
+            .current_dir(self.project())
+            .env_clear()
+            .env(
+                "PATH",
+                format!("{}:/usr/bin:/bin", self.root.join("bin").display()),
+            )
+            .env("HOME", self.root.join("home"))
+            .env("RUSTDN_HOME", self.root.join("rustdn"))
+            .output()
+            .unwrap()
+    }
+
+    /// Makes all the following builds fail.
+    fn fail_builds(&self) {
+        let nix_build = self.root.join("bin/nix-build");
+        write(
+            &nix_build,
+            "#!/bin/sh\necho 'error: build failed' >&2\nexit 1\n",
+        );
+        fs::set_permissions(&nix_build, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Returns the nix expressions of all the builds so far.
+    fn builds(&self) -> Vec<String> {
+        match fs::read_to_string(self.root.join("builds")) {
+            Ok(log) => log.lines().map(str::to_owned).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn plus_override() {
+    let env = TestEnv::new("plus-override");
+
+    let out = env.run("rustc", &["+nightly-2024-05-01", "-V"]);
+    assert!(out.starts_with("rustc 1.99.0 (fake)\n"));
+    assert!(out.contains(r#".rust-bin.nightly."2024-05-01".default"#));
+
+    let out = env.run("rustc", &["+1.80", "-V"]);
+    assert!(out.contains(r#".rust-bin.stable."1.80".default"#));
+
+    assert_eq!(env.builds().len(), 2);
+}
+
+#[test]
+fn cached_on_second_run() {
+    let env = TestEnv::new("cached");
+
+    let first = env.run("rustc", &["+1.80", "-V"]);
+    let second = env.run("rustc", &["+1.80", "-V"]);
+
+    assert_eq!(first, second);
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn toolchain_file_discovery() {
+    let env = TestEnv::new("toolchain-file");
+
+    let file = env.project().join("rust-toolchain.toml");
+    write(
+        &file,
+        "[toolchain]\nchannel = \"1.78\"\ncomponents = [\"rust-src\"]\n",
+    );
+    let out = env.run("rustdn", &["show", "active-toolchain", "--plain"]);
+    assert_eq!(out.trim(), file.display().to_string());
+
+    let out = env.run("rustc", &["-V"]);
+    assert!(out.contains("fromRustupToolchainFile"));
+    assert!(out.contains(&file.display().to_string()));
+
+    // `+<toolchain>` still wins
+    let out = env.run("rustc", &["+beta", "-V"]);
+    assert!(out.contains(".rust-bin.beta."));
+}
+
+#[test]
+fn no_toolchain_file() {
+    let env = TestEnv::new("no-toolchain-file");
+
+    write(
+        &env.project().join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.78\"\n",
+    );
+
+    let out = env.run(
+        "rustdn",
+        &["--no-toolchain-file", "show", "active-toolchain"],
+    );
+    assert_eq!(out, "default (default)\n");
+
+    let out = env.run(
+        "/usr/bin/env",
+        &["RUSTDN_NO_TOOLCHAIN_FILE=1", "rustc", "-V"],
+    );
+    assert!(out.contains(r#".rust-bin.stable."latest".default"#));
+
+    // `+<toolchain>` still works
+    let out = env.run(
+        "/usr/bin/env",
+        &["RUSTDN_NO_TOOLCHAIN_FILE=1", "rustc", "+beta", "-V"],
+    );
+    assert!(out.contains(".rust-bin.beta."));
+}
+
+#[test]
+fn toolchain_file_var() {
+    let env = TestEnv::new("toolchain-file-var");
+
+    let shared = env.root.join("shared.toml");
+    write(&shared, "[toolchain]\nchannel = \"1.79\"\n");
+    let var = format!("RUSTDN_TOOLCHAIN_FILE={}", shared.display());
+
+    // Used instead of the toolchain file in the project
+    write(
+        &env.project().join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.78\"\n",
+    );
+
+    let out = env.run("/usr/bin/env", &[&var, "rustc", "-V"]);
+    assert!(out.contains(r#".rust-bin.stable."1.79".default"#));
+
+    let out = env.run(
+        "/usr/bin/env",
+        &[&var, "rustdn", "show", "active-toolchain"],
+    );
+    assert!(out.contains("`RUSTDN_TOOLCHAIN_FILE` environment variable"));
+
+    // `+<toolchain>` still wins
+    let out = env.run("/usr/bin/env", &[&var, "rustc", "+beta", "-V"]);
+    assert!(out.contains(".rust-bin.beta."));
+
+    // Unlike toolchain files in directories, it must exist
+    let missing = format!(
+        "RUSTDN_TOOLCHAIN_FILE={}",
+        env.root.join("nope.toml").display()
+    );
+    let out = env.command("/usr/bin/env", &[&missing, "rustc", "-V"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("the file doesn't exist"));
+}
+
+#[test]
+fn pinned_skips_resolution() {
+    let env = TestEnv::new("pinned");
+
+    let rustc = env.run("rustdn", &["which", "+1.80", "rustc"]);
+    let toolchain = Path::new(rustc.trim_end())
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap();
+
+    // Would be an error, if the proxy looked for it
+    write(&env.project().join("rust-toolchain.toml"), "[toolchain]\n");
+
+    let pin = format!("RUSTDN_TOOLCHAIN={}", toolchain.display());
+    let out = env.run("/usr/bin/env", &[&pin, "rustc", "-V"]);
+    assert!(out.contains(r#".rust-bin.stable."1.80".default"#));
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn channel_only_toolchain_file() {
+    let env = TestEnv::new("channel-only");
+
+    write(
+        &env.project().join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.78\"\n",
+    );
+
+    // The file is the same as `+1.78`, so they share the build
+    let out = env.run("rustc", &["-V"]);
+    assert!(out.contains(r#".rust-bin.stable."1.78".default"#));
+    assert_eq!(env.run("rustc", &["+1.78", "-V"]), out);
+
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn configured_default_channel() {
+    let env = TestEnv::new("default-channel");
+
+    fs::create_dir_all(env.root.join("rustdn")).unwrap();
+    write(
+        &env.root.join("rustdn/config.toml"),
+        "default_channel = \"nightly\"\n",
+    );
+
+    let out = env.run("rustc", &["-V"]);
+    assert!(out.contains(r#".rust-bin.nightly."latest".default"#));
+    assert_eq!(env.run("rustc", &["+nightly", "-V"]), out);
+    assert_eq!(env.run("rustdn", &["default"]), "default (`nightly`)\n");
+
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn self_install_and_uninstall() {
+    let env = TestEnv::new("self-install");
+    let bin_dir = env.root.join("proxies");
+    let bin_dir_arg = bin_dir.to_str().unwrap();
+
+    let out = env.run("rustdn", &["self", "install", "--bin-dir", bin_dir_arg]);
+    assert!(out.contains(&format!("created `{}`", bin_dir.join("cargo").display())));
+    assert!(out.contains("is not in `PATH`"));
+
+    let rustc = bin_dir.join("rustc");
+    let out = env.run(rustc.to_str().unwrap(), &["+1.80", "-V"]);
+    assert!(out.starts_with("rustc 1.99.0 (fake)\n"));
+
+    // Real files are not replaced without `--force`
+    fs::remove_file(bin_dir.join("rustfmt")).unwrap();
+    write(&bin_dir.join("rustfmt"), "");
+    let out = env.command("rustdn", &["self", "install", "--bin-dir", bin_dir_arg]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("use `--force`"));
+
+    // Without `--yes`, stdin (which is not a terminal here) is asked for a confirmation
+    let out = env.command("rustdn", &["self", "uninstall", bin_dir_arg]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("use `--yes`"));
+    assert!(bin_dir.join("rustc").exists());
+
+    let out = env.run("rustdn", &["self", "uninstall", "--yes", bin_dir_arg]);
+    assert!(out.contains(&format!("removed `{}`", bin_dir.join("rustc").display())));
+    assert!(!bin_dir.join("rustc").exists());
+    assert!(bin_dir.join("rustfmt").exists());
+}
+
+#[test]
+fn non_utf8_arg0() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+    let env = TestEnv::new("non-utf8");
+
+    let dir = env.root.join(OsStr::from_bytes(b"bin-\xff"));
+    fs::create_dir_all(&dir).unwrap();
+    symlink(env!("CARGO_BIN_EXE_rustdn"), dir.join("rustc")).unwrap();
+    symlink(
+        env!("CARGO_BIN_EXE_rustdn"),
+        dir.join(OsStr::from_bytes(b"rust\xffc")),
+    )
+    .unwrap();
+
+    let out = env.command(dir.join("rustc"), &["+1.80", "-V"]);
+    assert!(out.status.success());
+    assert!(out.stdout.starts_with(b"rustc 1.99.0 (fake)\n"));
+
+    // Not a tool, but still not a panic
+    let out = env.command(dir.join(OsStr::from_bytes(b"rust\xffc")), &["-V"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("is not the name of `rustdn` or a tool"));
+}
+
+#[test]
+fn which_all() {
+    let env = TestEnv::new("which-all");
+
+    let out = env.run("rustdn", &["which", "+1.80", "--all"]);
+    let rustc = env.run("rustdn", &["which", "+1.80", "rustc"]);
+
+    // The fake toolchain only has `rustc`
+    assert_eq!(
+        out,
+        format!("cargo -> missing\nrustc -> {rustc}rustdoc -> missing\n")
+    );
+}
+
+#[test]
+fn nixpkgs_fallback() {
+    let env = TestEnv::new("nixpkgs-fallback");
+
+    // `nix-instantiate` is used to check if there is a `<nixpkgs>`, this one says there isn't
+    let nix_instantiate = env.root.join("bin/nix-instantiate");
+    write(&nix_instantiate, "#!/bin/sh\nexit 1\n");
+    fs::set_permissions(&nix_instantiate, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let out = env.run("rustc", &["+1.80", "-V"]);
+    assert!(out.contains(
+        r#"(import (builtins.fetchTarball "https://github.com/NixOS/nixpkgs/archive/24.05.tar.gz")"#
+    ));
+
+    write(&nix_instantiate, "#!/bin/sh\nexit 0\n");
+    let out = env.run("rustc", &["+1.81", "-V"]);
+    assert!(out.contains("(import <nixpkgs>"));
+}
+
+#[test]
+fn quiet() {
+    let env = TestEnv::new("quiet");
+
+    // Warns about the missing `<nixpkgs>` normally
+    let nix_instantiate = env.root.join("bin/nix-instantiate");
+    write(&nix_instantiate, "#!/bin/sh\nexit 1\n");
+    fs::set_permissions(&nix_instantiate, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let out = env.command("rustdn", &["-q", "which", "+1.80", "rustc"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+    // What was asked for is still printed
+    assert!(String::from_utf8_lossy(&out.stdout).ends_with("/bin/rustc\n"));
+
+    assert_eq!(env.run("rustdn", &["--quiet", "default", "+1.80"]), "");
+    assert_eq!(
+        env.run("rustdn", &["default", "+1.80"]),
+        "default toolchain set to `stable-1.80`\n"
+    );
+}
+
+#[test]
+fn deleted_current_dir() {
+    let env = TestEnv::new("deleted-cwd");
+
+    let out = env.command(
+        "/bin/sh",
+        &[
+            "-c",
+            "mkdir gone && cd gone && rmdir ../gone && exec rustc -V",
+        ],
+    );
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("`cd` to another directory"));
+}
+
+#[test]
+fn build_without_rustc() {
+    let env = TestEnv::new("no-rustc");
+
+    // Builds an empty directory
+    write(
+        &env.root.join("bin/nix-build"),
+        &format!(
+            "#!/bin/sh\nmkdir -p {store}/empty\nln -sfn {store}/empty \"$2\"\n",
+            store = env.root.join("store").display()
+        ),
+    );
+
+    let out = env.command("rustc", &["+1.80", "-V"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("doesn't contain `bin/rustc`"));
+    assert!(stderr.contains(r#".rust-bin.stable."1.80".default"#));
+
+    // Nothing was cached
+    assert_eq!(env.run("rustdn", &["toolchain", "list"]), "");
+}
+
+#[test]
+fn toolchain_file_traversal() {
+    let env = TestEnv::new("traversal");
+    env.fail_builds();
+
+    // `<root>/rustdn/toolchains/pinned-stable-../../../../victim` is `<root>/victim`, which would
+    // be removed when the build fails
+    let victim = env.root.join("victim");
+    fs::create_dir_all(&victim).unwrap();
+    write(&victim.join("important"), "");
+
+    let legacy = env.project().join("rust-toolchain");
+    write(&legacy, "stable-../../../../victim\n");
+    let out = env.command("rustc", &["-V"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("invalid toolchain `stable-../../../../victim`"),
+        "{stderr}"
+    );
+    fs::remove_file(&legacy).unwrap();
+
+    // Left to rust-overlay, as a file
+    write(
+        &env.project().join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"stable-../../../../victim\"\n",
+    );
+    let out = env.command("rustc", &["-V"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(env
+        .root
+        .join("rustdn/toolchains")
+        .read_dir()
+        .unwrap()
+        .next()
+        .is_none());
+
+    // Same for the environment
+    let toolchain_file = env.project().join("rust-toolchain.toml");
+    fs::remove_file(&toolchain_file).unwrap();
+    for var in [
+        "RUSTUP_TOOLCHAIN=stable-../../../../victim",
+        "RUSTUP_TOOLCHAIN=nightly-../../../../victim+rust-src",
+    ] {
+        let out = env.command("/usr/bin/env", &[var, "rustc", "-V"]);
+        assert_eq!(out.status.code(), Some(1));
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("invalid toolchain"), "{stderr}");
+    }
+
+    assert!(victim.join("important").exists());
+}
+
+#[test]
+fn config_hashes() {
+    let env = TestEnv::new("config-hashes");
+    let config = env.root.join("rustdn/config.toml");
+    fs::create_dir_all(config.parent().unwrap()).unwrap();
+
+    for sha256 in [
+        "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        "0sw2qqa0iyd2kz8afrhwqb8fmh78rwrm5hpzxbbh9ni8f1d9qjgd",
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    ] {
+        write(&config, &format!("rust_overlay_sha256 = \"{sha256}\"\n"));
+        env.run("rustdn", &["which", "+1.80", "rustc"]);
+    }
+
+    for sha256 in ["sha256-AAAA", r#"x\"; y = \"z"#, "latest"] {
+        write(&config, &format!("nixpkgs_sha256 = \"{sha256}\"\n"));
+        let out = env.command("rustdn", &["which", "+1.81", "rustc"]);
+        assert_eq!(out.status.code(), Some(1), "{sha256}");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("invalid `nixpkgs_sha256`"), "{stderr}");
+    }
+}
+
+#[test]
+fn config_build_retries() {
+    let env = TestEnv::new("config-build-retries");
+    let config = env.root.join("rustdn/config.toml");
+    fs::create_dir_all(config.parent().unwrap()).unwrap();
+
+    write(&config, "build_retries = 10\n");
+    env.run("rustdn", &["which", "+1.80", "rustc"]);
+
+    for retries in ["11", "4294967295"] {
+        write(&config, &format!("build_retries = {retries}\n"));
+        let out = env.command("rustdn", &["which", "+1.81", "rustc"]);
+        assert_eq!(out.status.code(), Some(1), "{retries}");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("invalid `build_retries`"), "{stderr}");
+    }
+}
+
+#[test]
+fn toolchain_add_same_key() {
+    let env = TestEnv::new("toolchain-add-same-key");
+
+    // `default` is `stable`, they must not be built at the same time
+    let out = env.run(
+        "rustdn",
+        &["toolchain", "add", "default", "stable", "+stable"],
+    );
+    assert_eq!(out, "installed `default`\n");
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn gc_older_than() {
+    let env = TestEnv::new("gc-older-than");
+    env.run("rustdn", &["toolchain", "add", "stable"]);
+
+    let out = env.run("rustdn", &["gc", "--dry-run", "--older-than", "0"]);
+    assert!(out.contains("would remove `stable`"), "{out}");
+
+    // Doesn't overflow (or wrap around to a short age)
+    let out = env.run("rustdn", &["gc", "--older-than", "300000000000000"]);
+    assert_eq!(out, "");
+    assert!(env.run("rustdn", &["toolchain", "list"]).contains("stable"));
+}
+
+#[test]
+fn invalid_extras() {
+    let env = TestEnv::new("invalid-extras");
+
+    for flag in ["--component", "--target"] {
+        let args = ["run", flag, r#"rust-src,a"b"#, "nightly", "true"];
+        let out = env.command("rustdn", &args);
+        assert_eq!(out.status.code(), Some(2), "{flag}");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains(r#"invalid name `a"b`"#), "{stderr}");
+    }
+
+    assert!(env.builds().is_empty());
+}
+
+#[test]
+fn transient_build_failures() {
+    let env = TestEnv::new("transient");
+
+    // The first build fails with a network error, the rest work as usual.
+    // Toolchains with `nope` in their expression fail to evaluate.
+    let bin = env.root.join("bin");
+    fs::rename(bin.join("nix-build"), bin.join("nix-build-real")).unwrap();
+    write(
+        &bin.join("nix-build"),
+        &format!(
+            "#!/bin/sh\n\
+             echo attempt >> {attempts}\n\
+             case \"$*\" in *nope*) echo \"error: attribute 'nope' missing\" >&2; exit 1 ;; esac\n\
+             if [ ! -e {failed} ]; then\n\
+                 touch {failed}\n\
+                 echo \"error: unable to download 'https://x': HTTP error 503\" >&2\n\
+                 exit 1\n\
+             fi\n\
+             exec {real} \"$@\"\n",
+            attempts = env.root.join("attempts").display(),
+            failed = env.root.join("failed").display(),
+            real = bin.join("nix-build-real").display(),
+        ),
+    );
+    fs::set_permissions(bin.join("nix-build"), fs::Permissions::from_mode(0o755)).unwrap();
+    let attempts = || {
+        fs::read_to_string(env.root.join("attempts"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    let out = env.command("rustc", &["+1.80", "-V"]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("retrying in 2s (retry 1 of 2)"));
+    assert_eq!(attempts(), 2);
+
+    // Other errors are not retried
+    let out = env.command("rustc", &["+stable+nope", "-V"]);
+    assert!(!out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("retrying"));
+    assert_eq!(attempts(), 3);
+}
+
+#[test]
+fn store_hash() {
+    const HASH: &str = "0c4m8hwc4rq8vvcxrl8gygmp5wbrp4z5";
+
+    let env = TestEnv::new("store-hash");
+
+    // The fake store paths don't have hashes
+    let out = env.command("rustc", &[&format!("+nightly@{HASH}"), "-V"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("doesn't have the expected hash"),
+        "{stderr}"
+    );
+    assert_eq!(env.run("rustdn", &["toolchain", "list"]), "");
+
+    // Builds a toolchain with the expected hash
+    let store_path = env.root.join(format!("store/{HASH}-rust-nightly"));
+    write(
+        &env.root.join("bin/nix-build"),
+        &format!(
+            "#!/bin/sh\nmkdir -p {path}/bin\nprintf '#!/bin/sh\\necho hashed\\n' > {path}/bin/rustc\n\
+             chmod +x {path}/bin/rustc\nln -sfn {path} \"$2\"\n",
+            path = store_path.display()
+        ),
+    );
+
+    assert_eq!(
+        env.run("rustc", &[&format!("+nightly@{HASH}"), "-V"]),
+        "hashed\n"
+    );
+    assert_eq!(
+        env.run("rustdn", &["toolchain", "list"]),
+        format!("  nightly@{HASH}  hashed  cached\n")
+    );
+}
+
+#[test]
+fn doctor() {
+    let env = TestEnv::new("doctor");
+
+    let nix_instantiate = env.root.join("bin/nix-instantiate");
+    write(
+        &nix_instantiate,
+        "#!/bin/sh\ncase \"$*\" in\n  *'1 + 1'*) echo 2 ;;\n  *) echo '\"/nixpkgs\"' ;;\nesac\n",
+    );
+    fs::set_permissions(&nix_instantiate, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // There is no `cargo` proxy
+    let out = env.command("rustdn", &["doctor"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("ok: nix can evaluate expressions\n"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("ok: `<nixpkgs>` is `/nixpkgs`\n"));
+    assert!(stdout.contains("ok: proxies in `PATH` run `rustdn`"));
+    assert!(stdout.contains("): rustc\n"));
+    assert!(
+        stdout.contains("error: there is no `cargo` in `PATH`\n  hint: run `rustdn self install`")
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains("1 check failed"));
+
+    symlink(env!("CARGO_BIN_EXE_rustdn"), env.root.join("bin/cargo")).unwrap();
+    let out = env.run("rustdn", &["doctor"]);
+    assert!(!out.contains("error:"), "{out}");
+    assert!(out.contains(&format!(
+        "ok: `{}` is writable",
+        env.root.join("rustdn").display()
+    )));
+}
+
+#[test]
+fn list_and_show() {
+    let env = TestEnv::new("list-show");
+
+    assert_eq!(env.run("rustdn", &["toolchain", "list"]), "");
+    let out = env.run("rustdn", &["show", "+1.80"]);
+    assert!(out.contains("path: not installed"));
+
+    env.run("rustc", &["+1.80", "-V"]);
+
+    let out = env.run("rustdn", &["toolchain", "list"]);
+    assert!(out.contains("stable-1.80"), "{out}");
+
+    let out = env.run("rustdn", &["show", "+1.80"]);
+    assert!(out.contains("source: command line override"));
+    assert!(out.contains(&format!("path: {}", env.root.join("store").display())));
+    assert!(out.contains("version: rustc 1.99.0 (fake)"));
+
+    // Nothing is built by `show`/`list`
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn shared_cache_export_import() {
+    let env = TestEnv::new("export-import");
+
+    env.run("rustc", &["+1.80", "-V"]);
+    env.run("rustc", &["+nightly+rust-src", "-V"]);
+
+    let exported = env.run("rustdn", &["toolchain", "export"]);
+    assert_eq!(
+        exported,
+        "external-nightly,component=rust-src\npinned-stable-1.80\n"
+    );
+    let file = env.root.join("toolchains.txt");
+    write(&file, &format!("# exported\n{exported}\n"));
+
+    // A separate cache, e.g. one restored by CI
+    let shared = |dir: &str| format!("RUSTDN_SHARED_CACHE={}", env.root.join(dir).display());
+    let in_cache = |dir: &str, args: &[&str]| {
+        let var = shared(dir);
+        env.run("/usr/bin/env", &[&[var.as_str(), "rustdn"], args].concat())
+    };
+
+    assert_eq!(in_cache("shared", &["toolchain", "list"]), "");
+    in_cache("shared", &["toolchain", "import", file.to_str().unwrap()]);
+    assert_eq!(env.builds().len(), 4);
+    assert_eq!(in_cache("shared", &["toolchain", "export"]), exported);
+    assert!(env
+        .root
+        .join("shared/toolchains/pinned-stable-1.80")
+        .is_dir());
+
+    // The cache still works after it's moved
+    fs::rename(env.root.join("shared"), env.root.join("moved")).unwrap();
+    let out = in_cache("moved", &["show", "toolchains-dir"]);
+    assert_eq!(
+        out.trim_end(),
+        env.root.join("moved/toolchains").to_str().unwrap()
+    );
+    let var = shared("moved");
+    env.run("/usr/bin/env", &[&var, "rustc", "+nightly+rust-src", "-V"]);
+    assert_eq!(env.builds().len(), 4);
+
+    let out = env.command("rustdn", &["toolchain", "import", "/dev/null"]);
+    assert!(out.status.success());
+    // Lines with `\r\n` work, things which are not keys don't
+    write(&file, "pinned-stable-1.80\r\n");
+    env.run("rustdn", &["toolchain", "import", file.to_str().unwrap()]);
+    for key in [
+        "not-a-key",
+        "pinned-stable-../../../x",
+        "pinned-stable-1.80,component=../../x",
+        // Parses, but isn't what `key` would produce
+        "pinned-stable-1.80,profile=default",
+    ] {
+        write(&file, &format!("{key}\n"));
+        let out = env.command("rustdn", &["toolchain", "import", file.to_str().unwrap()]);
+        assert_eq!(out.status.code(), Some(2), "{key}");
+    }
+    assert!(!env.root.join("x").exists());
+
+    // `default` is `stable`, it's built once
+    write(&file, "default\nexternal-stable\n");
+    let builds = env.builds().len();
+    let out = in_cache("dedup", &["toolchain", "import", file.to_str().unwrap()]);
+    assert_eq!(out, "installed `default`\n");
+    assert_eq!(env.builds().len(), builds + 1);
+}
+
+#[test]
+fn show_paths() {
+    let env = TestEnv::new("show-paths");
+
+    let home = env.root.join("rustdn");
+    assert_eq!(
+        env.run("rustdn", &["show", "home"]),
+        format!("{}\n", home.display())
+    );
+    assert_eq!(
+        env.run("rustdn", &["show", "toolchains-dir"]),
+        format!("{}\n", home.join("toolchains").display())
+    );
+    assert!(!env
+        .command("rustdn", &["show", "home", "+1.80"])
+        .status
+        .success());
+
+    // Without nix (i.e. offline) only installed toolchains have a known store path
+    let offline = |args: &[&str]| {
+        let args = [&["RUSTDN_OFFLINE=1", "rustdn", "show", "store-path"], args].concat();
+        env.command("/usr/bin/env", &args)
+    };
+    assert!(!offline(&["+1.80"]).status.success());
+
+    let rustc = env.run("rustdn", &["which", "+1.80", "rustc"]);
+    let store_path = Path::new(rustc.trim_end()).ancestors().nth(2).unwrap();
+
+    let out = offline(&["+1.80"]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}\n", store_path.display())
+    );
+    assert_eq!(env.builds().len(), 1);
+}
+
+#[test]
+fn list_filters() {
+    let env = TestEnv::new("list-filters");
+
+    let rustc = env.run("rustdn", &["which", "+1.80", "rustc"]);
+    env.run("rustdn", &["which", "+1.81", "rustc"]);
+
+    // As if 1.80 was garbage collected
+    let store_path = Path::new(rustc.trim_end())
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap();
+    fs::remove_dir_all(store_path).unwrap();
+
+    let out = env.run("rustdn", &["toolchain", "list"]);
+    assert!(out.contains("  stable-1.80  missing, will be rebuilt when used  cached\n"));
+    assert!(out.contains("  stable-1.81  rustc 1.99.0 (fake)"));
+
+    let out = env.run("rustdn", &["toolchain", "list", "--porcelain"]);
+    assert!(out.contains("stable-1.80\tmissing\t\tcached\t\t\n"));
+    assert!(out.contains("stable-1.81\tinstalled\trustc 1.99.0 (fake)\tcached\t/"));
+
+    let out = env.run("rustdn", &["toolchain", "list", "--installed-only"]);
+    assert!(!out.contains("stable-1.80"));
+    assert!(out.contains("stable-1.81"));
+
+    let out = env.run("rustdn", &["toolchain", "list", "--json"]);
+    assert!(out.starts_with('[') && out.ends_with("]\n"));
+    assert!(out.contains(
+        r#"{"spec": "stable-1.80", "linked": false, "installed": false, "path": null, "rustc_version": null}"#
+    ));
+    assert!(
+        out.contains(r#"{"spec": "stable-1.81", "linked": false, "installed": true, "path": ""#)
+    );
+}
+
+#[test]
+fn update() {
+    let env = TestEnv::new("update");
+
+    env.run("rustc", &["+nightly", "-V"]);
+    env.run("rustc", &["+1.80", "-V"]);
+    assert_eq!(env.builds().len(), 2);
+
+    // Version-less toolchains are rebuilt, even though their cache is still fresh
+    let out = env.run("rustdn", &["update"]);
+    assert!(
+        out.contains("nightly: up to date (rustc 1.99.0 (fake)"),
+        "{out}"
+    );
+    assert!(out.contains("stable-1.80: pinned\n"), "{out}");
+    assert_eq!(env.builds().len(), 3);
+    assert!(env.builds()[2].contains(r#".rust-bin.nightly."latest".default"#));
+
+    // A different rust-overlay means a different nightly
+    write(
+        &env.root.join("rustdn/config.toml"),
+        "rust_overlay_rev = \"0123456789abcdef\"\n",
+    );
+    let out = env.run("rustdn", &["update", "+nightly"]);
+    assert!(
+        out.starts_with("nightly: updated (rustc 1.99.0 (fake)"),
+        "{out}"
+    );
+    assert!(out.contains("0123456789abcdef"), "{out}");
+
+    assert_eq!(
+        env.run("rustdn", &["update", "1.80"]),
+        "stable-1.80: pinned\n"
+    );
+    assert_eq!(env.builds().len(), 4);
+
+    let out = env.command("rustdn", &["--offline", "update"]);
+    assert_eq!(out.status.code(), Some(2));
+
+    // Failures are reported, and fail the whole update (e.g. in CI)
+    env.fail_builds();
+    let out = env.command("rustdn", &["update"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("couldn't update `nightly`"), "{stderr}");
+    assert!(
+        stderr.contains("couldn't update 1 of 2 toolchains"),
+        "{stderr}"
+    );
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .contains("stable-1.80: pinned"));
+}
+
+#[test]
+fn invalid_home() {
+    let env = TestEnv::new("invalid-home");
+
+    let run = |vars: &[&str]| {
+        let args = [vars, &["rustdn", "toolchain", "list"]].concat();
+        let out = env.command("/usr/bin/env", &args);
+        assert_eq!(out.status.code(), Some(1));
+        String::from_utf8(out.stderr).unwrap()
+    };
+
+    let err = run(&["RUSTDN_HOME=state"]);
+    assert!(
+        err.contains("`state`: it's not an absolute path (set by `RUSTDN_HOME`)"),
+        "{err}"
+    );
+
+    let err = run(&["-u", "RUSTDN_HOME", "HOME=home"]);
+    assert!(
+        err.contains("`home/.rustdn`: it's not an absolute path"),
+        "{err}"
+    );
+    assert!(
+        err.contains("set `RUSTDN_HOME` to use another directory"),
+        "{err}"
+    );
+
+    let file = env.root.join("file");
+    write(&file, "");
+    let err = run(&[&format!("RUSTDN_HOME={}", file.display())]);
+    assert!(err.contains("it's not a directory"), "{err}");
+    let err = run(&[&format!("RUSTDN_HOME={}/rustdn", file.display())]);
+    assert!(
+        err.contains("is not a directory (set by `RUSTDN_HOME`)"),
+        "{err}"
+    );
+
+    let err = run(&["RUSTDN_SHARED_CACHE=cache"]);
+    assert!(err.contains("(set by `RUSTDN_SHARED_CACHE`)"), "{err}");
+
+    // Nothing was created in the current directory
+    assert_eq!(fs::read_dir(env.project()).unwrap().count(), 0);
+
+    // A home which doesn't exist yet is fine
+    let home = format!("RUSTDN_HOME={}", env.root.join("new/home").display());
+    assert_eq!(
+        env.run("/usr/bin/env", &[&home, "rustdn", "toolchain", "list"]),
+        ""
+    );
+}
+
+#[test]
+fn list_markers() {
+    let env = TestEnv::new("list-markers");
+
+    env.run("rustc", &["+1.80", "-V"]);
+    env.run("rustc", &["+nightly-2024-05-01", "-V"]);
+    env.run("rustdn", &["default", "1.80"]);
+    env.run("rustdn", &["override", "set", "nightly-2024-05-01"]);
+
+    let out = env.run("rustdn", &["toolchain", "list"]);
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{out}");
+    assert!(lines.contains(&"* nightly-2024-05-01  rustc 1.99.0 (fake)  cached"));
+    assert!(lines.contains(&"  stable-1.80         rustc 1.99.0 (fake)  cached (default)"));
+
+    // Colors are only used if asked for, and never for `--porcelain`
+    let out = env.run(
+        "/usr/bin/env",
+        &["RUSTDN_COLOR=always", "rustdn", "toolchain", "list"],
+    );
+    assert!(out.contains("\x1b[1;32m*\x1b[0m"));
+    let args = [
+        "RUSTDN_COLOR=always",
+        "rustdn",
+        "toolchain",
+        "list",
+        "--porcelain",
+    ];
+    let out = env.run("/usr/bin/env", &args);
+    assert!(!out.contains('\x1b'));
+    assert!(out.contains("\tcached\t"));
+    assert!(out.contains("\tactive\n"));
+    assert!(out.contains("\tdefault\n"));
+
+    let out = env.command("rustdn", &["toolchain", "list", "--json", "--porcelain"]);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn show_json() {
+    let env = TestEnv::new("show-json");
+
+    let out = env.run("rustdn", &["show", "+nightly-2024-05-01", "--json"]);
+    assert_eq!(
+        out.trim_end(),
+        r#"{"source": "cli", "spec": "nightly-2024-05-01", "store_path": null, "installed": false, "rustc_version": null}"#
+    );
+    assert!(env.builds().is_empty());
+
+    let file = env.project().join("rust-toolchain.toml");
+    write(
+        &file,
+        "[toolchain]\nchannel = \"1.78\"\ntargets = [\"wasm32-wasi\"]\n",
+    );
+    env.run("rustc", &["-V"]);
+
+    let out = env.run("rustdn", &["show", "--json"]);
+    assert!(out.starts_with(&format!(
+        r#"{{"source": "toolchain-file", "spec": "{}", "store_path": "{}/"#,
+        file.display(),
+        env.root.join("store").display()
+    )));
+    // (the fake `rustc` also prints the expression, hence the `\n`)
+    assert!(out.contains(r#"", "installed": true, "rustc_version": "rustc 1.99.0 (fake)\n"#));
+}